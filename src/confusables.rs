@@ -0,0 +1,152 @@
+//! A tiny take on the Unicode confusable "skeleton" from UTS #39.
+//!
+//! The real thing has a table with thousands of entries. We only care about
+//! usernames, so after decomposing and lowercasing this maps the usual
+//! suspects (Cyrillic, Greek, fullwidth, digits that look like letters) onto
+//! plain ASCII and throws away accents and anything invisible.
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Single characters and the prototype they're confusable with. Everything
+/// is lowercased by the time it gets here.
+const CONFUSABLES: &[(char, char)] = &[
+    // Cyrillic lowercase.
+    ('а', 'a'),
+    ('в', 'b'),
+    ('е', 'e'),
+    ('һ', 'h'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('к', 'k'),
+    ('ӏ', 'l'),
+    ('м', 'm'),
+    ('н', 'h'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('ԛ', 'q'),
+    ('ѕ', 's'),
+    ('с', 'c'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('ү', 'y'),
+    ('ԝ', 'w'),
+    ('х', 'x'),
+    ('ԁ', 'd'),
+    ('ь', 'b'),
+    // Greek lowercase.
+    ('α', 'a'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+    // Latin lookalikes.
+    ('ı', 'i'),
+    ('ℓ', 'l'),
+    ('ɑ', 'a'),
+    ('ɡ', 'g'),
+    ('ǀ', 'l'),
+    ('1', 'l'),
+    ('|', 'l'),
+    ('0', 'o'),
+];
+
+/// Multi-character sequences that render like a single letter.
+const SEQUENCES: &[(&str, &str)] = &[("rn", "m"), ("vv", "w"), ("cl", "d")];
+
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{00ad}'
+        | '\u{034f}'
+        | '\u{180e}'
+        | '\u{200b}'..='\u{200f}'
+        | '\u{202a}'..='\u{202e}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{feff}'
+    ) || c.is_whitespace()
+}
+
+fn prototype(c: char) -> char {
+    // Fullwidth ASCII variants are laid out in the same order as ASCII.
+    if ('\u{ff01}'..='\u{ff5e}').contains(&c) {
+        return char::from_u32(c as u32 - 0xfee0).unwrap_or(c);
+    }
+    CONFUSABLES
+        .iter()
+        .find(|(from, _)| *from == c)
+        .map(|(_, to)| *to)
+        .unwrap_or(c)
+}
+
+/// Reduce a string to a form where confusable strings compare equal. Case
+/// and accents go first, so "José" and "jose" are the same name.
+pub fn skeleton(s: &str) -> String {
+    let mut skeleton = s
+        .chars()
+        .flat_map(char::to_lowercase)
+        .nfd()
+        .filter(|c| !is_invisible(*c) && !is_combining_mark(*c))
+        .map(prototype)
+        .collect::<String>();
+    for (from, to) in SEQUENCES {
+        skeleton = skeleton.replace(from, to);
+    }
+    skeleton
+}
+
+pub fn is_confusable(a: &str, b: &str) -> bool {
+    skeleton(a) == skeleton(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookalike_letters_collide() {
+        assert!(is_confusable("alice", "аlice")); // Cyrillic а
+        assert!(is_confusable("paypal", "раураl")); // Cyrillic р, а, у
+        assert!(is_confusable("bob", "ｂｏｂ")); // fullwidth
+        assert!(is_confusable("kappa", "κappα")); // Greek κ, α
+        assert!(is_confusable("bill", "bi11"));
+        assert!(is_confusable("ЅОНО", "soho")); // Cyrillic Ѕ, О, Н
+        assert!(is_confusable("bob", "b0b"));
+    }
+
+    #[test]
+    fn sequences_collide() {
+        assert!(is_confusable("modern", "rnodern"));
+        assert!(is_confusable("wendy", "vvendy"));
+        assert!(is_confusable("dave", "clave"));
+    }
+
+    #[test]
+    fn invisible_characters_are_ignored() {
+        assert!(is_confusable("alice", "al\u{200b}ice"));
+        assert!(is_confusable("alice", "\u{feff}alice\u{200d}"));
+        assert!(is_confusable("alice", "al ice"));
+    }
+
+    #[test]
+    fn case_and_accents_are_ignored() {
+        assert!(is_confusable("alice", "ALICE"));
+        assert!(is_confusable("bob", "BOB"));
+        assert!(is_confusable("jose", "josé"));
+        assert!(is_confusable("jose", "jose\u{0301}"));
+        assert!(is_confusable("alice", "a\u{0301}lice"));
+        assert!(is_confusable("zoe", "Zoë"));
+    }
+
+    #[test]
+    fn different_names_dont_collide() {
+        assert!(!is_confusable("alice", "bob"));
+        assert!(!is_confusable("anna", "ana"));
+        assert!(!is_confusable("mark", "marc"));
+        assert!(!is_confusable("alice", "alice2"));
+        assert!(!is_confusable("", "a"));
+    }
+}
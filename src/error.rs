@@ -29,6 +29,18 @@ pub enum ArpchatError {
     #[error("message too long to send")]
     MsgTooLong,
 
-    #[error("tried to set interface, but interface is already initialized")]
-    InterfaceAlreadySet,
+    #[error("wrong passphrase")]
+    WrongPassphrase,
+
+    #[error("the passphrase can't be empty")]
+    EmptyPassphrase,
+
+    #[error("{0}")]
+    HistoryError(String),
+
+    #[error("couldn't drop privileges: {0}")]
+    PrivilegeDropFailed(String),
+
+    #[error("couldn't save settings: {0}")]
+    ConfigSaveFailed(String),
 }
@@ -1,6 +1,9 @@
 #![feature(try_blocks)]
 
+pub mod confusables;
 pub mod error;
 pub mod net;
+pub mod privileges;
+pub mod ratelimit;
 pub mod ringbuffer;
 pub mod ui;
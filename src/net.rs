@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::io::ErrorKind;
 use std::slice::Iter;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use pnet::datalink::{
     Channel as DataLinkChannel, DataLinkReceiver, DataLinkSender, NetworkInterface,
@@ -14,6 +14,7 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use crate::error::ArpchatError;
+use crate::ratelimit::{RateLimiter, Verdict};
 use crate::ringbuffer::Ringbuffer;
 
 const ARP_HTYPE: &[u8] = &[0x00, 0x01]; // Hardware Type (Ethernet)
@@ -24,9 +25,26 @@ const PACKET_PREFIX: &[u8] = b"uwu";
 pub const ID_SIZE: usize = 8;
 pub type Id = [u8; ID_SIZE];
 
+pub const FILE_HASH_SIZE: usize = 32;
+pub type FileHash = [u8; FILE_HASH_SIZE];
+
+/// The room everyone starts out in.
+pub const DEFAULT_ROOM: &str = "#general";
+const MAX_ROOM_LEN: usize = 32;
+
 // Tag, seq, and total, are each one byte, thus the `+ 3`.
 const PACKET_PART_SIZE: usize = u8::MAX as usize - (PACKET_PREFIX.len() + 3 + ID_SIZE);
 
+/// Most packets we'll wait on the rest of at once, and how long we'll wait.
+/// Anyone can start a packet and never finish it.
+const MAX_PARTIAL: usize = 128;
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many new packets with more than one part each MAC can start, per
+/// second and in a burst. File transfers stay well under this.
+const PARTIAL_RATE: f64 = 100.0;
+const PARTIAL_BURST: f64 = 200.0;
+
 #[derive(Default, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum EtherType {
     #[default]
@@ -69,52 +87,753 @@ impl Display for EtherType {
     }
 }
 
+/// What someone's up to, as far as they've told everyone.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Availability {
+    #[default]
+    Available,
+    Away,
+    Busy,
+    DoNotDisturb,
+}
+
+impl Availability {
+    fn byte(&self) -> u8 {
+        match self {
+            Availability::Available => 0,
+            Availability::Away => 1,
+            Availability::Busy => 2,
+            Availability::DoNotDisturb => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Availability::Available),
+            1 => Some(Availability::Away),
+            2 => Some(Availability::Busy),
+            3 => Some(Availability::DoNotDisturb),
+            _ => None,
+        }
+    }
+
+    /// Whatever someone typed after `/status`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "available" | "online" => Some(Availability::Available),
+            "away" => Some(Availability::Away),
+            "busy" => Some(Availability::Busy),
+            "dnd" | "do-not-disturb" => Some(Availability::DoNotDisturb),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Availability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Availability::Available => write!(f, "available"),
+            Availability::Away => write!(f, "away"),
+            Availability::Busy => write!(f, "busy"),
+            Availability::DoNotDisturb => write!(f, "do not disturb"),
+        }
+    }
+}
+
+/// Turn whatever someone typed after `/join` into a room name, or `None` if
+/// it can't be one.
+pub fn normalize_room(name: &str) -> Option<String> {
+    let name = name.trim();
+    let name = name.strip_prefix('#').unwrap_or(name).to_lowercase();
+    if name.is_empty()
+        || name.chars().count() > MAX_ROOM_LEN
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '#')
+    {
+        return None;
+    }
+    Some(format!("#{name}"))
+}
+
+/// A string prefixed with its length, so more fields can come after it.
+/// Anything past 255 bytes is cut off.
+fn short_str(str: &str) -> Vec<u8> {
+    let mut len = str.len().min(u8::MAX as usize);
+    while !str.is_char_boundary(len) {
+        len -= 1;
+    }
+    [&[len as u8], &str.as_bytes()[..len]].concat()
+}
+
+/// Read a string written by `short_str`, returning it and whatever's left.
+fn read_short_str(data: &[u8]) -> Option<(String, &[u8])> {
+    let len = *data.first()? as usize;
+    let str = String::from_utf8(data.get(1..1 + len)?.to_vec()).ok()?;
+    Some((str, &data[1 + len..]))
+}
+
+/// An id that might not be there, prefixed with whether it is.
+fn optional_id(id: &Option<Id>) -> Vec<u8> {
+    match id {
+        Some(id) => [&[1], id as &[u8]].concat(),
+        None => vec![0],
+    }
+}
+
+/// Read an id written by `optional_id`, returning it and whatever's left.
+fn read_optional_id(data: &[u8]) -> Option<(Option<Id>, &[u8])> {
+    match data.first()? {
+        0 => Some((None, &data[1..])),
+        _ => {
+            let id: Id = data.get(1..1 + ID_SIZE)?.try_into().ok()?;
+            Some((Some(id), &data[1 + ID_SIZE..]))
+        }
+    }
+}
+
+/// Roughly how much of a backlog fits in one response. Packets can't be
+/// much more than 60KiB, and nobody needs that much catching up.
+const MAX_BACKLOG_SIZE: usize = 16 * 1024;
+
+/// A message someone missed, sent to them when they show up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BacklogEntry {
+    pub msg_id: Id,
+    pub author: Id,
+    /// Unix timestamp, in seconds.
+    pub time: i64,
+    pub reply_to: Option<Id>,
+    pub username: String,
+    /// Empty if it was a direct message.
+    pub room: String,
+    pub msg: String,
+}
+
+impl BacklogEntry {
+    fn serialize(&self) -> Vec<u8> {
+        let msg = smaz::compress(self.msg.as_bytes());
+        [
+            &self.msg_id as &[u8],
+            &self.author,
+            &self.time.to_be_bytes(),
+            &optional_id(&self.reply_to),
+            &short_str(&self.username),
+            &short_str(&self.room),
+            &(msg.len() as u16).to_be_bytes(),
+            &msg,
+        ]
+        .concat()
+    }
+
+    fn deserialize(data: &[u8]) -> Option<(Self, &[u8])> {
+        let msg_id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+        let author: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+        let time = i64::from_be_bytes(data.get(ID_SIZE * 2..ID_SIZE * 2 + 8)?.try_into().ok()?);
+        let (reply_to, rest) = read_optional_id(&data[ID_SIZE * 2 + 8..])?;
+        let (username, rest) = read_short_str(rest)?;
+        let (room, rest) = read_short_str(rest)?;
+        let len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let raw_str = smaz::decompress(rest.get(2..2 + len)?).ok()?;
+        let msg = String::from_utf8(raw_str).ok()?;
+        let entry = BacklogEntry {
+            msg_id,
+            author,
+            time,
+            reply_to,
+            username,
+            room,
+            msg,
+        };
+        Some((entry, &rest[2 + len..]))
+    }
+}
+
+/// How many entries, from the first, fit in a packet together.
+pub fn backlog_fits<'a>(entries: impl IntoIterator<Item = &'a BacklogEntry>) -> usize {
+    let mut size = 0;
+    entries
+        .into_iter()
+        .take_while(|entry| {
+            size += entry.serialize().len();
+            size <= MAX_BACKLOG_SIZE
+        })
+        .count()
+}
+
+/// As many of the newest entries as fit in a packet, oldest first.
+fn backlog_entries(entries: &[BacklogEntry]) -> Vec<u8> {
+    let mut kept = vec![];
+    let mut size = 0;
+    for entry in entries.iter().rev() {
+        let data = entry.serialize();
+        size += data.len();
+        if size > MAX_BACKLOG_SIZE {
+            break;
+        }
+        kept.push(data);
+    }
+    kept.reverse();
+    kept.concat()
+}
+
+fn read_backlog_entries(mut data: &[u8]) -> Option<Vec<BacklogEntry>> {
+    let mut entries = vec![];
+    while !data.is_empty() {
+        let (entry, rest) = BacklogEntry::deserialize(data)?;
+        entries.push(entry);
+        data = rest;
+    }
+    Some(entries)
+}
+
+/// A room's topic, and who set it when. It's cleared by setting it to
+/// nothing, so there's still someone to blame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    pub author: Id,
+    pub username: String,
+    /// Unix timestamp, in seconds.
+    pub time: i64,
+}
+
+impl Topic {
+    fn serialize(&self) -> Vec<u8> {
+        let mut len = self.text.len().min(u16::MAX as usize);
+        while !self.text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let text = &self.text.as_bytes()[..len];
+        [
+            &self.author as &[u8],
+            &self.time.to_be_bytes(),
+            &short_str(&self.username),
+            &(text.len() as u16).to_be_bytes(),
+            text,
+        ]
+        .concat()
+    }
+
+    fn deserialize(data: &[u8]) -> Option<(Self, &[u8])> {
+        let author: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+        let time = i64::from_be_bytes(data.get(ID_SIZE..ID_SIZE + 8)?.try_into().ok()?);
+        let (username, rest) = read_short_str(&data[ID_SIZE + 8..])?;
+        let len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let text = String::from_utf8(rest.get(2..2 + len)?.to_vec()).ok()?;
+        let topic = Topic {
+            text,
+            author,
+            username,
+            time,
+        };
+        Some((topic, &rest[2 + len..]))
+    }
+}
+
+/// One thing in a shared folder, or one of someone's shares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShareEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Always 0 for folders.
+    pub size: u64,
+}
+
+impl ShareEntry {
+    pub fn serialize(&self) -> Vec<u8> {
+        [
+            &short_str(&self.name) as &[u8],
+            &[self.is_dir as u8],
+            &self.size.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn deserialize(data: &[u8]) -> Option<(Self, &[u8])> {
+        let (name, rest) = read_short_str(data)?;
+        let is_dir = *rest.first()? > 0;
+        let size = u64::from_be_bytes(rest.get(1..9)?.try_into().ok()?);
+        Some((ShareEntry { name, is_dir, size }, &rest[9..]))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Packet {
-    Message(Id, String),
+    /// Sender, message id, the message it's replying to, room, and message.
+    Message(Id, Id, Option<Id>, String, String),
     PresenceReq,
-    Presence(Id, bool, String),
+    /// Sender, whether they just joined, username, the rooms they're in,
+    /// their availability and status line, and the folders they're sharing.
+    Presence(
+        Id,
+        bool,
+        String,
+        Vec<String>,
+        Availability,
+        String,
+        Vec<String>,
+    ),
     Disconnect(Id),
+    /// Sender, recipient, message id, the message it's replying to, and
+    /// message.
+    DirectMessage(Id, Id, Id, Option<Id>, String),
+    /// Sender, the author of the messages, and the ids of the ones that
+    /// were delivered and the ones that were read. No more than 255 can be
+    /// marked delivered in one go.
+    Receipt(Id, Id, Vec<Id>, Vec<Id>),
+    /// Author, message id, and the new message.
+    Edit(Id, Id, String),
+    /// Author and message id.
+    Delete(Id, Id),
+    /// Sender, message id, the reaction, and whether it's being added or
+    /// taken back.
+    Reaction(Id, Id, String, bool),
+    /// Sender, who they're typing to if it's a direct message, and the room
+    /// they're typing in if it isn't.
+    Typing(Id, Option<Id>, String),
+    /// Sender, and the rooms they want to catch up on.
+    HistoryReq(Id, Vec<String>),
+    /// Sender, recipient, and the backlog, oldest first. If it doesn't all
+    /// fit, the oldest entries are left out.
+    HistoryResp(Id, Id, Vec<BacklogEntry>),
+    /// Whoever held them, recipient, and messages that came in while the
+    /// recipient was offline. Like a backlog, the oldest might be left out.
+    Mail(Id, Id, Vec<BacklogEntry>),
+    /// Recipient, and the held messages they've now got.
+    MailAck(Id, Vec<Id>),
+    /// Sender, who it's for if it's direct, file id, the room it's offered
+    /// in if it isn't, file name, size, and hash.
+    FileOffer(Id, Option<Id>, Id, String, String, u64, FileHash),
+    /// Sender, whoever offered the file, file id, and how much of it the
+    /// sender has. Both accepts an offer and acknowledges chunks.
+    FileAccept(Id, Id, Id, u64),
+    /// Sender, recipient, file id, offset, and data.
+    FileChunk(Id, Id, Id, u64, Vec<u8>),
+    /// Sender, recipient, and file id. Declines an offer or gives up on a
+    /// transfer, from either end.
+    FileCancel(Id, Id, Id),
+    /// Sender, recipient, and the path in the recipient's shares to list.
+    ListReq(Id, Id, String),
+    /// Sender, recipient, the path that was listed, whether some of it was
+    /// left out, and what's there.
+    Listing(Id, Id, String, bool, Vec<ShareEntry>),
+    /// Sender, recipient, file id to offer it with, and the path in the
+    /// recipient's shares to fetch.
+    GetReq(Id, Id, Id, String),
+    /// Sender, recipient, the path asked about, and what's wrong with it.
+    ShareError(Id, Id, String, String),
+    /// Sender, message id, who it's for if it's direct, the room it's in if
+    /// it isn't, file name, and the image, already shrunk down.
+    Image(Id, Id, Option<Id>, String, String, Vec<u8>),
+    /// Sender, room, when it last changed in unix milliseconds, its topic
+    /// if it's ever had one, and its pinned messages, oldest first. It's
+    /// always all of it, so whichever changed last wins.
+    RoomInfo(Id, String, i64, Option<Topic>, Vec<BacklogEntry>),
 }
 
 impl Packet {
     fn tag(&self) -> u8 {
         match self {
-            Packet::Message(_, _) => 0,
+            Packet::Message(_, _, _, _, _) => 0,
             Packet::PresenceReq => 1,
-            Packet::Presence(_, _, _) => 2,
+            Packet::Presence(_, _, _, _, _, _, _) => 2,
             Packet::Disconnect(_) => 3,
+            Packet::DirectMessage(_, _, _, _, _) => 4,
+            Packet::Receipt(_, _, _, _) => 5,
+            Packet::Edit(_, _, _) => 6,
+            Packet::Delete(_, _) => 7,
+            Packet::Reaction(_, _, _, _) => 8,
+            Packet::Typing(_, _, _) => 9,
+            Packet::HistoryReq(_, _) => 10,
+            Packet::HistoryResp(_, _, _) => 11,
+            Packet::Mail(_, _, _) => 12,
+            Packet::MailAck(_, _) => 13,
+            Packet::FileOffer(_, _, _, _, _, _, _) => 14,
+            Packet::FileAccept(_, _, _, _) => 15,
+            Packet::FileChunk(_, _, _, _, _) => 16,
+            Packet::FileCancel(_, _, _) => 17,
+            Packet::ListReq(_, _, _) => 18,
+            Packet::Listing(_, _, _, _, _) => 19,
+            Packet::GetReq(_, _, _, _) => 20,
+            Packet::ShareError(_, _, _, _) => 21,
+            Packet::Image(_, _, _, _, _, _) => 22,
+            Packet::RoomInfo(_, _, _, _, _) => 23,
+        }
+    }
+
+    /// The id of the peer that sent this packet, if it says.
+    pub fn sender(&self) -> Option<Id> {
+        match self {
+            Packet::Message(id, _, _, _, _)
+            | Packet::Presence(id, _, _, _, _, _, _)
+            | Packet::Disconnect(id)
+            | Packet::DirectMessage(id, _, _, _, _)
+            | Packet::Receipt(id, _, _, _)
+            | Packet::Edit(id, _, _)
+            | Packet::Delete(id, _)
+            | Packet::Reaction(id, _, _, _)
+            | Packet::Typing(id, _, _)
+            | Packet::HistoryReq(id, _)
+            | Packet::HistoryResp(id, _, _)
+            | Packet::Mail(id, _, _)
+            | Packet::MailAck(id, _)
+            | Packet::FileOffer(id, _, _, _, _, _, _)
+            | Packet::FileAccept(id, _, _, _)
+            | Packet::FileChunk(id, _, _, _, _)
+            | Packet::FileCancel(id, _, _)
+            | Packet::ListReq(id, _, _)
+            | Packet::Listing(id, _, _, _, _)
+            | Packet::GetReq(id, _, _, _)
+            | Packet::ShareError(id, _, _, _)
+            | Packet::Image(id, _, _, _, _, _)
+            | Packet::RoomInfo(id, _, _, _, _) => Some(*id),
+            Packet::PresenceReq => None,
         }
     }
 
     fn deserialize(tag: u8, data: &[u8]) -> Option<Self> {
         match tag {
             0 => {
-                let id: Id = data[..ID_SIZE].try_into().ok()?;
-                let raw_str = smaz::decompress(&data[ID_SIZE..]).ok()?;
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let (reply_to, rest) = read_optional_id(&data[ID_SIZE * 2..])?;
+                let (room, rest) = read_short_str(rest)?;
+                let raw_str = smaz::decompress(rest).ok()?;
                 let str = String::from_utf8(raw_str).ok()?;
-                Some(Packet::Message(id, str))
+                Some(Packet::Message(id, msg_id, reply_to, room, str))
             }
             1 => Some(Packet::PresenceReq),
             2 => {
-                let id: Id = data[..ID_SIZE].try_into().ok()?;
-                let is_join = data[ID_SIZE] > 0;
-                let str = String::from_utf8(data[ID_SIZE + 1..].to_vec()).ok()?;
-                Some(Packet::Presence(id, is_join, str))
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let is_join = *data.get(ID_SIZE)? > 0;
+                let (str, rest) = read_short_str(&data[ID_SIZE + 1..])?;
+                let availability = Availability::from_byte(*rest.first()?)?;
+                let (status, rest) = read_short_str(&rest[1..])?;
+                let share_count = *rest.first()? as usize;
+                let mut rest = &rest[1..];
+                let mut shares = vec![];
+                for _ in 0..share_count {
+                    let (share, next) = read_short_str(rest)?;
+                    shares.push(share);
+                    rest = next;
+                }
+                let mut rooms = vec![];
+                while !rest.is_empty() {
+                    let (room, next) = read_short_str(rest)?;
+                    rooms.push(room);
+                    rest = next;
+                }
+                Some(Packet::Presence(
+                    id,
+                    is_join,
+                    str,
+                    rooms,
+                    availability,
+                    status,
+                    shares,
+                ))
             }
             3 => Some(Packet::Disconnect(data.try_into().ok()?)),
+            4 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE * 2..ID_SIZE * 3)?.try_into().ok()?;
+                let (reply_to, rest) = read_optional_id(&data[ID_SIZE * 3..])?;
+                let raw_str = smaz::decompress(rest).ok()?;
+                let str = String::from_utf8(raw_str).ok()?;
+                Some(Packet::DirectMessage(from, to, msg_id, reply_to, str))
+            }
+            5 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let delivered_len = *data.get(ID_SIZE * 2)? as usize;
+                let ids = data[ID_SIZE * 2 + 1..]
+                    .chunks(ID_SIZE)
+                    .map(|id| id.try_into().ok())
+                    .collect::<Option<Vec<Id>>>()?;
+                if delivered_len > ids.len() {
+                    return None;
+                }
+                let (delivered, read) = ids.split_at(delivered_len);
+                Some(Packet::Receipt(from, to, delivered.to_vec(), read.to_vec()))
+            }
+            6 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let raw_str = smaz::decompress(&data[ID_SIZE * 2..]).ok()?;
+                let str = String::from_utf8(raw_str).ok()?;
+                Some(Packet::Edit(id, msg_id, str))
+            }
+            7 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE..)?.try_into().ok()?;
+                Some(Packet::Delete(id, msg_id))
+            }
+            8 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let is_added = *data.get(ID_SIZE * 2)? > 0;
+                let (reaction, _) = read_short_str(&data[ID_SIZE * 2 + 1..])?;
+                Some(Packet::Reaction(id, msg_id, reaction, is_added))
+            }
+            9 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let (to, rest) = read_optional_id(&data[ID_SIZE..])?;
+                let (room, _) = read_short_str(rest)?;
+                Some(Packet::Typing(id, to, room))
+            }
+            10 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let mut rest = &data[ID_SIZE..];
+                let mut rooms = vec![];
+                while !rest.is_empty() {
+                    let (room, next) = read_short_str(rest)?;
+                    rooms.push(room);
+                    rest = next;
+                }
+                Some(Packet::HistoryReq(id, rooms))
+            }
+            11 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let entries = read_backlog_entries(&data[ID_SIZE * 2..])?;
+                Some(Packet::HistoryResp(from, to, entries))
+            }
+            12 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let entries = read_backlog_entries(&data[ID_SIZE * 2..])?;
+                Some(Packet::Mail(from, to, entries))
+            }
+            13 => {
+                let id: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_ids = data[ID_SIZE..]
+                    .chunks(ID_SIZE)
+                    .map(|id| id.try_into().ok())
+                    .collect::<Option<Vec<Id>>>()?;
+                Some(Packet::MailAck(id, msg_ids))
+            }
+            14 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let (to, rest) = read_optional_id(&data[ID_SIZE..])?;
+                let file_id: Id = rest.get(..ID_SIZE)?.try_into().ok()?;
+                let size = u64::from_be_bytes(rest.get(ID_SIZE..ID_SIZE + 8)?.try_into().ok()?);
+                let rest = &rest[ID_SIZE + 8..];
+                let hash: FileHash = rest.get(..FILE_HASH_SIZE)?.try_into().ok()?;
+                let (room, rest) = read_short_str(&rest[FILE_HASH_SIZE..])?;
+                let (name, _) = read_short_str(rest)?;
+                Some(Packet::FileOffer(from, to, file_id, room, name, size, hash))
+            }
+            15 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let file_id: Id = data.get(ID_SIZE * 2..ID_SIZE * 3)?.try_into().ok()?;
+                let offset =
+                    u64::from_be_bytes(data.get(ID_SIZE * 3..ID_SIZE * 3 + 8)?.try_into().ok()?);
+                Some(Packet::FileAccept(from, to, file_id, offset))
+            }
+            16 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let file_id: Id = data.get(ID_SIZE * 2..ID_SIZE * 3)?.try_into().ok()?;
+                let offset =
+                    u64::from_be_bytes(data.get(ID_SIZE * 3..ID_SIZE * 3 + 8)?.try_into().ok()?);
+                let chunk = data[ID_SIZE * 3 + 8..].to_vec();
+                Some(Packet::FileChunk(from, to, file_id, offset, chunk))
+            }
+            17 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let file_id: Id = data.get(ID_SIZE * 2..ID_SIZE * 3)?.try_into().ok()?;
+                Some(Packet::FileCancel(from, to, file_id))
+            }
+            18 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let (path, _) = read_short_str(&data[ID_SIZE * 2..])?;
+                Some(Packet::ListReq(from, to, path))
+            }
+            19 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let (path, rest) = read_short_str(&data[ID_SIZE * 2..])?;
+                let truncated = *rest.first()? > 0;
+                let mut rest = &rest[1..];
+                let mut entries = vec![];
+                while !rest.is_empty() {
+                    let (entry, next) = ShareEntry::deserialize(rest)?;
+                    entries.push(entry);
+                    rest = next;
+                }
+                Some(Packet::Listing(from, to, path, truncated, entries))
+            }
+            20 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let file_id: Id = data.get(ID_SIZE * 2..ID_SIZE * 3)?.try_into().ok()?;
+                let (path, _) = read_short_str(&data[ID_SIZE * 3..])?;
+                Some(Packet::GetReq(from, to, file_id, path))
+            }
+            21 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let to: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let (path, rest) = read_short_str(&data[ID_SIZE * 2..])?;
+                let (reason, _) = read_short_str(rest)?;
+                Some(Packet::ShareError(from, to, path, reason))
+            }
+            22 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let msg_id: Id = data.get(ID_SIZE..ID_SIZE * 2)?.try_into().ok()?;
+                let (to, rest) = read_optional_id(&data[ID_SIZE * 2..])?;
+                let (room, rest) = read_short_str(rest)?;
+                let (name, rest) = read_short_str(rest)?;
+                Some(Packet::Image(from, msg_id, to, room, name, rest.to_vec()))
+            }
+            23 => {
+                let from: Id = data.get(..ID_SIZE)?.try_into().ok()?;
+                let (room, rest) = read_short_str(&data[ID_SIZE..])?;
+                let updated = i64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+                let (topic, rest) = match *rest.get(8)? {
+                    0 => (None, &rest[9..]),
+                    _ => {
+                        let (topic, rest) = Topic::deserialize(&rest[9..])?;
+                        (Some(topic), rest)
+                    }
+                };
+                let pins = read_backlog_entries(rest)?;
+                Some(Packet::RoomInfo(from, room, updated, topic, pins))
+            }
             _ => None,
         }
     }
 
     fn serialize(&self) -> Vec<u8> {
         match self {
-            Packet::Message(id, msg) => [id as &[u8], &smaz::compress(msg.as_bytes())].concat(),
+            Packet::Message(id, msg_id, reply_to, room, msg) => [
+                id as &[u8],
+                msg_id,
+                &optional_id(reply_to),
+                &short_str(room),
+                &smaz::compress(msg.as_bytes()),
+            ]
+            .concat(),
             Packet::PresenceReq => vec![],
-            Packet::Presence(id, is_join, str) => {
-                [id as &[u8], &[*is_join as u8], str.as_bytes()].concat()
+            Packet::Presence(id, is_join, str, rooms, availability, status, shares) => {
+                let shares = &shares[..shares.len().min(u8::MAX as usize)];
+                let mut data = [
+                    id as &[u8],
+                    &[*is_join as u8],
+                    &short_str(str),
+                    &[availability.byte()],
+                    &short_str(status),
+                    &[shares.len() as u8],
+                ]
+                .concat();
+                for share in shares {
+                    data.extend(short_str(share));
+                }
+                for room in rooms {
+                    data.extend(short_str(room));
+                }
+                data
             }
             Packet::Disconnect(id) => id.to_vec(),
+            Packet::DirectMessage(from, to, msg_id, reply_to, msg) => [
+                from as &[u8],
+                to,
+                msg_id,
+                &optional_id(reply_to),
+                &smaz::compress(msg.as_bytes()),
+            ]
+            .concat(),
+            Packet::Receipt(from, to, delivered, read) => [
+                from as &[u8],
+                to,
+                &[delivered.len() as u8],
+                &delivered.concat(),
+                &read.concat(),
+            ]
+            .concat(),
+            Packet::Edit(id, msg_id, msg) => {
+                [id as &[u8], msg_id, &smaz::compress(msg.as_bytes())].concat()
+            }
+            Packet::Delete(id, msg_id) => [id as &[u8], msg_id].concat(),
+            Packet::Reaction(id, msg_id, reaction, is_added) => [
+                id as &[u8],
+                msg_id,
+                &[*is_added as u8],
+                &short_str(reaction),
+            ]
+            .concat(),
+            Packet::Typing(id, to, room) => {
+                [id as &[u8], &optional_id(to), &short_str(room)].concat()
+            }
+            Packet::HistoryReq(id, rooms) => {
+                let mut data = id.to_vec();
+                for room in rooms {
+                    data.extend(short_str(room));
+                }
+                data
+            }
+            Packet::HistoryResp(from, to, entries) | Packet::Mail(from, to, entries) => {
+                [from as &[u8], to, &backlog_entries(entries)].concat()
+            }
+            Packet::MailAck(id, msg_ids) => [id as &[u8], &msg_ids.concat()].concat(),
+            Packet::FileOffer(from, to, file_id, room, name, size, hash) => [
+                from as &[u8],
+                &optional_id(to),
+                file_id,
+                &size.to_be_bytes(),
+                hash,
+                &short_str(room),
+                &short_str(name),
+            ]
+            .concat(),
+            Packet::FileAccept(from, to, file_id, offset) => {
+                [from as &[u8], to, file_id, &offset.to_be_bytes()].concat()
+            }
+            Packet::FileChunk(from, to, file_id, offset, chunk) => {
+                [from as &[u8], to, file_id, &offset.to_be_bytes(), chunk].concat()
+            }
+            Packet::FileCancel(from, to, file_id) => [from as &[u8], to, file_id].concat(),
+            Packet::ListReq(from, to, path) => [from as &[u8], to, &short_str(path)].concat(),
+            Packet::Listing(from, to, path, truncated, entries) => {
+                let mut data = [from as &[u8], to, &short_str(path), &[*truncated as u8]].concat();
+                for entry in entries {
+                    data.extend(entry.serialize());
+                }
+                data
+            }
+            Packet::GetReq(from, to, file_id, path) => {
+                [from as &[u8], to, file_id, &short_str(path)].concat()
+            }
+            Packet::ShareError(from, to, path, reason) => {
+                [from as &[u8], to, &short_str(path), &short_str(reason)].concat()
+            }
+            Packet::Image(from, msg_id, to, room, name, image) => [
+                from as &[u8],
+                msg_id,
+                &optional_id(to),
+                &short_str(room),
+                &short_str(name),
+                image,
+            ]
+            .concat(),
+            Packet::RoomInfo(from, room, updated, topic, pins) => {
+                let topic = match topic {
+                    Some(topic) => [vec![1], topic.serialize()].concat(),
+                    None => vec![0],
+                };
+                [
+                    from as &[u8],
+                    &short_str(room),
+                    &updated.to_be_bytes(),
+                    &topic,
+                    &backlog_entries(pins),
+                ]
+                .concat()
+            }
         }
     }
 }
@@ -140,11 +859,13 @@ pub struct Channel {
 
     /// Buffer of received packet parts, keyed by the packet id.
     ///
-    /// Each value is the Vec of its parts, and counts as a packet when
-    /// every part is non-empty. There are probably several optimization
-    /// opportunities here, but c'mon, a naive approach is perfectly fine
-    /// for a program this cursed.
-    buffer: HashMap<Id, Vec<Vec<u8>>>,
+    /// Each value is when the first part came in and the Vec of its parts,
+    /// and counts as a packet when every part is non-empty. There are
+    /// probably several optimization opportunities here, but c'mon, a naive
+    /// approach is perfectly fine for a program this cursed.
+    buffer: HashMap<Id, (Instant, Vec<Vec<u8>>)>,
+    /// New packets with more than one part, by source MAC.
+    partial_limiter: RateLimiter<MacAddr>,
 
     /// Recent packet buffer for deduplication.
     recent: Ringbuffer<Id>,
@@ -170,15 +891,30 @@ impl Channel {
             tx,
             rx,
             buffer: HashMap::new(),
+            partial_limiter: RateLimiter::new(PARTIAL_RATE, PARTIAL_BURST),
             recent: Ringbuffer::with_capacity(16),
         })
     }
 
+    pub fn src_mac(&self) -> MacAddr {
+        self.src_mac
+    }
+
+    pub fn ether_type(&self) -> EtherType {
+        self.ether_type
+    }
+
     pub fn set_ether_type(&mut self, ether_type: EtherType) {
         self.ether_type = ether_type;
     }
 
     pub fn send(&mut self, packet: Packet) -> Result<(), ArpchatError> {
+        self.send_to(packet, MacAddr::broadcast())
+    }
+
+    /// Send a packet in frames addressed to a single MAC address. Everyone
+    /// else on a switched network won't even see it.
+    pub fn send_to(&mut self, packet: Packet, dest: MacAddr) -> Result<(), ArpchatError> {
         let data = packet.serialize();
         let mut parts: Vec<&[u8]> = data.chunks(PACKET_PART_SIZE).collect();
 
@@ -195,7 +931,7 @@ impl Channel {
         let total = (parts.len() - 1) as u8;
         let id: Id = rand::thread_rng().gen();
         for (seq, part) in parts.into_iter().enumerate() {
-            self.send_part(packet.tag(), seq as u8, total, id, part)?;
+            self.send_part(dest, packet.tag(), seq as u8, total, id, part)?;
         }
 
         Ok(())
@@ -203,6 +939,7 @@ impl Channel {
 
     fn send_part(
         &mut self,
+        dest: MacAddr,
         tag: u8,
         seq: u8,
         total: u8,
@@ -235,7 +972,7 @@ impl Channel {
         let mut eth_buffer = vec![0; 14 + arp_buffer.len()];
         let mut eth_packet =
             MutableEthernetPacket::new(&mut eth_buffer).ok_or(ArpchatError::ARPSerializeFailed)?;
-        eth_packet.set_destination(MacAddr::broadcast());
+        eth_packet.set_destination(dest);
         eth_packet.set_source(self.src_mac);
         eth_packet.set_ethertype(EtherTypes::Arp);
         eth_packet.set_payload(&arp_buffer);
@@ -246,7 +983,18 @@ impl Channel {
         }
     }
 
-    pub fn try_recv(&mut self) -> Result<Option<Packet>, ArpchatError> {
+    /// Throw away everything that's queued up, so a channel that sat unread
+    /// doesn't come back with a pile of stale frames. Gives up after a
+    /// second on a network that never goes quiet.
+    pub fn drain(&mut self) {
+        let started = Instant::now();
+        while self.rx.next().is_ok() && started.elapsed() < Duration::from_secs(1) {}
+        self.buffer.clear();
+    }
+
+    /// Receive the next complete packet, along with the source MAC address of
+    /// the Ethernet frame its final part arrived in.
+    pub fn try_recv(&mut self) -> Result<Option<(MacAddr, Packet)>, ArpchatError> {
         let packet = match self.rx.next() {
             Ok(packet) => packet,
             Err(e) => {
@@ -263,56 +1011,238 @@ impl Channel {
         };
 
         // Early filter for packets that aren't relevant.
+        let payload = packet.payload();
         if packet.get_ethertype() != EtherTypes::Arp
-            || &packet.payload()[6..8] != ARP_OPER
-            || &packet.payload()[..2] != ARP_HTYPE
-            || packet.payload()[4] != ARP_HLEN
+            || payload.len() < 14
+            || &payload[6..8] != ARP_OPER
+            || &payload[..2] != ARP_HTYPE
+            || payload[4] != ARP_HLEN
         {
             return Ok(None);
         }
 
-        let data_len = packet.payload()[5] as usize;
-        let data = &packet.payload()[14..14 + data_len];
+        let src_mac = packet.get_source();
+        let data_len = payload[5] as usize;
+        let Some(data) = payload.get(14..14 + data_len) else {
+            return Ok(None);
+        };
         if !data.starts_with(PACKET_PREFIX) {
             return Ok(None);
         }
 
         if let &[tag, seq, total, ref inner @ ..] = &data[PACKET_PREFIX.len()..] {
             Ok(try {
-                let id: Id = inner[..ID_SIZE].try_into().ok()?;
-                let inner = &inner[ID_SIZE..];
+                let id: Id = inner.get(..ID_SIZE)?.try_into().ok()?;
+                let part = inner[ID_SIZE..].to_vec();
 
-                // Skip if we already have this packet.
-                if self.recent.contains(&id) {
+                // Skip if we already have this packet, or it makes no sense.
+                if self.recent.contains(&id) || seq > total {
                     None?;
                 }
 
-                if let Some(parts) = self.buffer.get_mut(&id) {
-                    parts[seq as usize] = inner.to_vec();
+                if let Some((_, parts)) = self.buffer.get_mut(&id) {
+                    *parts.get_mut(seq as usize)? = part;
                 } else {
+                    if total > 0 {
+                        self.make_room(src_mac)?;
+                    }
                     let mut parts = vec![vec![]; total as usize + 1];
-                    parts[seq as usize] = inner.to_vec();
-                    self.buffer.insert(id, parts);
+                    parts[seq as usize] = part;
+                    self.buffer.insert(id, (Instant::now(), parts));
                 }
 
                 // SAFETY: Guaranteed to exist because it's populated directly above.
-                let parts = unsafe { self.buffer.get(&id).unwrap_unchecked() };
+                let (_, parts) = unsafe { self.buffer.get(&id).unwrap_unchecked() };
 
                 // Short-circuit if we don't have all the parts yet.
                 if !parts.iter().all(|p| !p.is_empty()) {
                     None?;
                 }
 
-                // Put the packet together.
+                // Put the packet together. If it doesn't make sense, more
+                // parts won't help.
                 let packet = Packet::deserialize(tag, &parts.concat());
+                self.buffer.remove(&id);
                 if packet.is_some() {
-                    self.buffer.remove(&id);
                     self.recent.push(id);
                 }
-                packet?
+                (src_mac, packet?)
             })
         } else {
             Ok(None)
         }
     }
+
+    /// Make space for a new packet that'll come in parts, if this MAC isn't
+    /// starting them too fast. Old and unfinished ones go first.
+    fn make_room(&mut self, src_mac: MacAddr) -> Option<()> {
+        if self.partial_limiter.check(src_mac) != Verdict::Allowed {
+            return None;
+        }
+        self.buffer
+            .retain(|_, (started, _)| started.elapsed() < PARTIAL_TIMEOUT);
+        if self.buffer.len() >= MAX_PARTIAL {
+            let oldest = self
+                .buffer
+                .iter()
+                .min_by_key(|(_, (started, _))| *started)
+                .map(|(id, _)| *id)?;
+            self.buffer.remove(&oldest);
+        }
+        self.partial_limiter.prune();
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u8, room: &str) -> BacklogEntry {
+        BacklogEntry {
+            msg_id: [n; ID_SIZE],
+            author: [n + 1; ID_SIZE],
+            time: 1_700_000_000 + n as i64,
+            reply_to: n.is_multiple_of(2).then_some([n + 2; ID_SIZE]),
+            username: format!("user {n}"),
+            room: room.to_string(),
+            msg: "hello there, how's it going?".to_string(),
+        }
+    }
+
+    /// One of everything, filled in enough to catch fields getting mixed up.
+    fn packets() -> Vec<Packet> {
+        let (a, b, c) = ([1; ID_SIZE], [2; ID_SIZE], [3; ID_SIZE]);
+        vec![
+            Packet::Message(a, b, Some(c), "#general".to_string(), "hi all".to_string()),
+            Packet::Message(a, b, None, "#general".to_string(), String::new()),
+            Packet::PresenceReq,
+            Packet::Presence(
+                a,
+                true,
+                "alice".to_string(),
+                vec!["#general".to_string(), "#rust".to_string()],
+                Availability::Busy,
+                "in a meeting".to_string(),
+                vec!["music".to_string()],
+            ),
+            Packet::Disconnect(a),
+            Packet::DirectMessage(a, b, c, None, "psst".to_string()),
+            Packet::Receipt(a, b, vec![c], vec![[4; ID_SIZE], [5; ID_SIZE]]),
+            Packet::Edit(a, b, "fixed it".to_string()),
+            Packet::Delete(a, b),
+            Packet::Reaction(a, b, "👍".to_string(), false),
+            Packet::Typing(a, Some(b), String::new()),
+            Packet::Typing(a, None, "#general".to_string()),
+            Packet::HistoryReq(a, vec!["#general".to_string()]),
+            Packet::HistoryResp(a, b, vec![entry(1, "#general"), entry(2, "#general")]),
+            Packet::Mail(a, b, vec![entry(3, "")]),
+            Packet::MailAck(a, vec![b, c]),
+            Packet::FileOffer(
+                a,
+                Some(b),
+                c,
+                String::new(),
+                "notes.txt".to_string(),
+                12345,
+                [7; FILE_HASH_SIZE],
+            ),
+            Packet::FileAccept(a, b, c, 4096),
+            Packet::FileChunk(a, b, c, 8192, vec![1, 2, 3, 4]),
+            Packet::FileCancel(a, b, c),
+            Packet::ListReq(a, b, "music/old".to_string()),
+            Packet::Listing(
+                a,
+                b,
+                "music".to_string(),
+                true,
+                vec![
+                    ShareEntry {
+                        name: "old".to_string(),
+                        is_dir: true,
+                        size: 0,
+                    },
+                    ShareEntry {
+                        name: "song.ogg".to_string(),
+                        is_dir: false,
+                        size: 3_000_000,
+                    },
+                ],
+            ),
+            Packet::GetReq(a, b, c, "music/song.ogg".to_string()),
+            Packet::ShareError(a, b, "nope".to_string(), "not found".to_string()),
+            Packet::Image(
+                a,
+                b,
+                None,
+                "#general".to_string(),
+                "cat.png".to_string(),
+                vec![9; 64],
+            ),
+            Packet::RoomInfo(
+                a,
+                "#general".to_string(),
+                1_700_000_000_000,
+                Some(Topic {
+                    text: "be nice".to_string(),
+                    author: b,
+                    username: "bob".to_string(),
+                    time: 1_700_000_000,
+                }),
+                vec![entry(4, "#general")],
+            ),
+            Packet::RoomInfo(a, "#rust".to_string(), 0, None, vec![]),
+        ]
+    }
+
+    #[test]
+    fn packets_survive_the_trip() {
+        let packets = packets();
+        let mut tags: Vec<u8> = packets.iter().map(Packet::tag).collect();
+        tags.dedup();
+        assert_eq!(tags, (0..=23).collect::<Vec<u8>>());
+
+        for packet in packets {
+            let data = packet.serialize();
+            assert_eq!(Packet::deserialize(packet.tag(), &data), Some(packet));
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        for packet in packets() {
+            let data = packet.serialize();
+            // Anything goes as long as nothing panics.
+            for len in 0..data.len() {
+                Packet::deserialize(packet.tag(), &data[..len]);
+            }
+
+            if packet == Packet::PresenceReq {
+                continue;
+            }
+            assert_eq!(
+                Packet::deserialize(packet.tag(), &data[..ID_SIZE - 1]),
+                None,
+                "{packet:?}"
+            );
+            // These end in something that knows how long it should be. The
+            // rest end in compressed text or raw bytes, where less is still
+            // something.
+            let open_ended = matches!(
+                packet,
+                Packet::Message(..)
+                    | Packet::DirectMessage(..)
+                    | Packet::Edit(..)
+                    | Packet::FileChunk(..)
+                    | Packet::Image(..)
+            );
+            if !open_ended {
+                assert_eq!(
+                    Packet::deserialize(packet.tag(), &data[..data.len() - 1]),
+                    None,
+                    "{packet:?}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,165 @@
+//! Raw sockets need CAP_NET_RAW (or root) to open, but not to use. Once the
+//! sockets we want are open, there's no reason to keep privileges around
+//! while we're parsing whatever random frames show up on the network.
+
+use std::path::PathBuf;
+
+use crate::error::ArpchatError;
+
+/// The uid and gid of whoever ran us through sudo, if that's how we got root.
+#[cfg(target_os = "linux")]
+fn sudo_ids() -> Option<(libc::uid_t, libc::gid_t)> {
+    // SAFETY: No arguments, can't fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    let env_id = |name: &str| std::env::var(name).ok()?.parse().ok();
+    Some((env_id("SUDO_UID")?, env_id("SUDO_GID")?))
+}
+
+/// If we were started through sudo, keep our files in the home of whoever
+/// ran it, since that's who we'll be once privileges are dropped. Otherwise
+/// sudo's HOME points at root's, which we can't get into anymore.
+///
+/// This has to happen before anything looks up a config or data path, and
+/// before any other threads exist.
+#[cfg(target_os = "linux")]
+pub fn use_sudo_home() {
+    let Some((uid, _)) = sudo_ids() else {
+        return;
+    };
+    // SAFETY: Nothing else is running yet, so nobody can call getpwuid
+    // under us and clobber the entry before it's copied out.
+    let home = unsafe {
+        let entry = libc::getpwuid(uid);
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            return;
+        }
+        std::ffi::CStr::from_ptr((*entry).pw_dir)
+            .to_string_lossy()
+            .into_owned()
+    };
+    std::env::set_var("HOME", home);
+    // These would still be root's if sudo was told to keep them.
+    std::env::remove_var("XDG_CONFIG_HOME");
+    std::env::remove_var("XDG_DATA_HOME");
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn use_sudo_home() {}
+
+/// Give back any of our files an earlier run as root left owned by root,
+/// along with the folders they're in, so we can still write them after
+/// dropping to `uid`.
+#[cfg(target_os = "linux")]
+fn hand_over(files: &[Option<PathBuf>], uid: libc::uid_t, gid: libc::gid_t) {
+    use std::os::unix::fs::MetadataExt;
+
+    let paths = files
+        .iter()
+        .flatten()
+        .flat_map(|file| {
+            [
+                file.parent().map(|dir| dir.to_path_buf()),
+                Some(file.clone()),
+            ]
+        })
+        .flatten();
+    for path in paths {
+        let owned_by_root = std::fs::metadata(&path).is_ok_and(|meta| meta.uid() == 0);
+        if owned_by_root {
+            // If it doesn't work, saving will say so later.
+            let _ = std::os::unix::fs::chown(&path, Some(uid), Some(gid));
+        }
+    }
+}
+
+/// Give up root and every capability, process-wide where the OS allows it.
+/// Returns a description of what was dropped, if anything.
+///
+/// `files` are the config and data files we'll still want to write, which
+/// get handed over to whoever we become if we got here through sudo.
+///
+/// Capabilities are per-thread on Linux, so any other thread that's already
+/// running needs to call [`drop_thread_capabilities`] itself.
+#[cfg(target_os = "linux")]
+pub fn drop_privileges(files: &[Option<PathBuf>]) -> Result<Option<String>, ArpchatError> {
+    let fail = |what: &str| {
+        ArpchatError::PrivilegeDropFailed(format!("{what}: {}", std::io::Error::last_os_error()))
+    };
+
+    let mut dropped = None;
+
+    // SAFETY: These are plain syscalls with no pointers except the empty
+    // group list, which is allowed to be null when the length is zero.
+    unsafe {
+        if libc::geteuid() == 0 {
+            // If we got here through sudo, go back to being whoever ran it.
+            // Otherwise, fall back to nobody, which can't save anything.
+            let (uid, gid) = match sudo_ids() {
+                Some((uid, gid)) => {
+                    hand_over(files, uid, gid);
+                    (uid, gid)
+                }
+                None => (65534, 65534),
+            };
+
+            if libc::setgroups(0, std::ptr::null()) != 0 {
+                return Err(fail("setgroups"));
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(fail("setgid"));
+            }
+            if libc::setuid(uid) != 0 {
+                return Err(fail("setuid"));
+            }
+            if libc::setuid(0) == 0 {
+                return Err(ArpchatError::PrivilegeDropFailed(
+                    "still able to become root".to_string(),
+                ));
+            }
+            dropped = Some(format!("dropped root, now running as uid {uid}"));
+        }
+
+        // Nothing we do after this should ever need privileges back.
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(fail("prctl"));
+        }
+    }
+
+    if drop_thread_capabilities()? {
+        dropped.get_or_insert_with(|| "dropped capabilities".to_string());
+    }
+    Ok(dropped)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_privileges(_files: &[Option<PathBuf>]) -> Result<Option<String>, ArpchatError> {
+    Ok(None)
+}
+
+/// Clear every capability set of the calling thread. Returns whether there
+/// were any capabilities to clear.
+#[cfg(target_os = "linux")]
+pub fn drop_thread_capabilities() -> Result<bool, ArpchatError> {
+    use caps::CapSet;
+
+    let fail = |err: caps::errors::CapsError| ArpchatError::PrivilegeDropFailed(err.to_string());
+
+    let had_any = !caps::read(None, CapSet::Permitted)
+        .map_err(fail)?
+        .is_empty();
+
+    // The ambient set doesn't exist before Linux 4.3, and if it doesn't
+    // exist there's nothing in it.
+    let _ = caps::clear(None, CapSet::Ambient);
+    for set in [CapSet::Inheritable, CapSet::Effective, CapSet::Permitted] {
+        caps::clear(None, set).map_err(fail)?;
+    }
+    Ok(had_any)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_thread_capabilities() -> Result<bool, ArpchatError> {
+    Ok(false)
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full, holds up to `burst` tokens, and refills at
+    /// `rate` tokens per second.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = Instant::now();
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// Dropped, and this is the first drop since the key was last allowed.
+    JustMuted,
+    Muted,
+}
+
+/// A token bucket per key, all sharing the same rate and burst.
+#[derive(Clone, Debug)]
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<K, (TokenBucket, bool)>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, key: K) -> Verdict {
+        let (rate, burst) = (self.rate, self.burst);
+        let (bucket, muted) = self
+            .buckets
+            .entry(key)
+            .or_insert_with(|| (TokenBucket::new(rate, burst), false));
+
+        match (bucket.try_take(), *muted) {
+            (true, _) => {
+                *muted = false;
+                Verdict::Allowed
+            }
+            (false, false) => {
+                *muted = true;
+                Verdict::JustMuted
+            }
+            (false, true) => Verdict::Muted,
+        }
+    }
+
+    /// Forget about keys whose buckets have refilled, so peers that come
+    /// and go don't pile up forever.
+    pub fn prune(&mut self) {
+        self.buckets.retain(|_, (bucket, _)| !bucket.is_full());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Pretend the bucket was last refilled `secs` seconds ago.
+    fn age(bucket: &mut TokenBucket, secs: f64) {
+        bucket.last_refill = Instant::now()
+            .checked_sub(Duration::from_secs_f64(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refuses() {
+        let mut bucket = TokenBucket::new(0.0, 3.0);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(2.0, 5.0);
+        bucket.tokens = 0.0;
+        age(&mut bucket, 1.0);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn refill_is_capped_at_burst() {
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        bucket.tokens = 0.0;
+        age(&mut bucket, 60.0);
+        assert!(bucket.is_full());
+        bucket.rate = 0.0;
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn just_muted_fires_once() {
+        let mut limiter = RateLimiter::new(0.0, 1.0);
+        assert_eq!(limiter.check('a'), Verdict::Allowed);
+        assert_eq!(limiter.check('a'), Verdict::JustMuted);
+        assert_eq!(limiter.check('a'), Verdict::Muted);
+        assert_eq!(limiter.check('a'), Verdict::Muted);
+    }
+
+    #[test]
+    fn mute_resets_once_allowed_again() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        assert_eq!(limiter.check('a'), Verdict::Allowed);
+        assert_eq!(limiter.check('a'), Verdict::JustMuted);
+
+        age(&mut limiter.buckets.get_mut(&'a').unwrap().0, 1.0);
+        assert_eq!(limiter.check('a'), Verdict::Allowed);
+        assert_eq!(limiter.check('a'), Verdict::JustMuted);
+    }
+
+    #[test]
+    fn keys_have_their_own_buckets() {
+        let mut limiter = RateLimiter::new(0.0, 1.0);
+        assert_eq!(limiter.check('a'), Verdict::Allowed);
+        assert_eq!(limiter.check('a'), Verdict::JustMuted);
+        assert_eq!(limiter.check('b'), Verdict::Allowed);
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let mut limiter = RateLimiter::new(0.0, 1.0);
+        limiter.check('a');
+        limiter.check('b');
+        limiter.buckets.get_mut(&'b').unwrap().0.tokens = 1.0;
+        limiter.prune();
+        assert!(limiter.buckets.contains_key(&'a'));
+        assert!(!limiter.buckets.contains_key(&'b'));
+    }
+}
@@ -1,77 +1,995 @@
 // This is all horrible and needs a humongous refactor.
 // The net code is half-decent though!
 
+mod backlog;
+mod buffers;
+mod chat_log;
+mod commands;
 mod config;
+mod history;
+mod ignore;
+mod images;
 mod init;
+mod mailbox;
+mod messages;
 mod net_thread;
+mod presences;
+mod receipts;
+mod search;
+mod shares;
+mod topics;
+mod transcript;
+mod transfer;
+mod typing;
 mod util;
 
 mod dialog {
     pub mod ether_type;
+    pub mod file;
+    pub mod find;
+    pub mod image;
     pub mod interface;
+    pub mod messages;
+    pub mod passphrase;
+    pub mod presence;
+    pub mod receipts;
     pub mod username;
 }
 
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use chrono::Timelike;
-use crossbeam_channel::unbounded;
+use chrono::{Local, TimeZone};
+use crossbeam_channel::{unbounded, Sender};
 use cursive::backends::crossterm::crossterm::style::Stylize;
-use cursive::views::{Dialog, LinearLayout, NamedView, TextView};
+use cursive::views::{Dialog, EditView};
+use cursive::Cursive;
+use rand::Rng;
 
+use crate::error::ArpchatError;
+use crate::net::{normalize_room, Availability, BacklogEntry, Id, DEFAULT_ROOM};
+use crate::privileges::{drop_thread_capabilities, use_sudo_home};
+
+use self::buffers::{inner_name, Buffers};
+use self::chat_log::ChatLog;
+use self::commands::Command;
 use self::config::CONFIG;
+use self::dialog::ether_type::show_ether_type_dialog;
+use self::dialog::file::show_file_offer_dialog;
+use self::dialog::find::show_find_dialog;
+use self::dialog::image::show_image_viewer;
 use self::dialog::interface::show_iface_dialog;
+use self::dialog::messages::{show_message_picker, show_reactions_dialog, show_thread_dialog};
+use self::dialog::passphrase::show_passphrase_dialog;
+use self::dialog::presence::show_presence_dialog;
+use self::dialog::receipts::show_receipts_dialog;
+use self::history::{EntryKind, History, HistoryEntry};
+use self::images::{draw_pending_sixel, prepare, sixel_supported, Images};
+use self::init::InputPanel;
+use self::messages::{parse_reaction, snippet, Messages, Record};
+use self::presences::Presences;
+use self::receipts::Receipts;
+use self::search::{Query, Search};
+use self::topics::{describe, Topics};
+use self::transcript::{default_name, Format, Transcript};
+use self::transfer::send_file;
+use self::typing::Typing;
 use self::util::{
-    append_txt, color_from_id, ring_bell, update_or_append_txt, update_title, NetCommand,
-    UICommand, UpdatePresenceKind,
+    append_txt, color_from_id, format_message, format_size, hex_id, image_name, message_name,
+    parse_hex_id, progress_bar, ring_bell, transfer_name, update_or_append_txt, update_title,
+    update_txt, Delivery, NetCommand, UICommand, UpdatePresenceKind, ACTION_PREFIX,
 };
 
+/// How often to tell people we're still typing.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+const DEFAULT_AUTO_AWAY_MINUTES: u64 = 10;
+
+/// Longest status line we'll send, in characters.
+const MAX_STATUS_LEN: usize = 64;
+const MAX_TOPIC_LEN: usize = 200;
+
+const DEFAULT_HISTORY_RELOAD: usize = 100;
+
+/// Most messages to send someone catching up.
+const MAX_BACKLOG: usize = 50;
+
+/// Show something to just us in a buffer.
+fn notice(siv: &mut Cursive, buffer: &str, notice: impl Display) {
+    append_txt(
+        siv,
+        &inner_name(buffer),
+        format!("> {notice}").dark_grey().to_string(),
+    );
+}
+
+/// Save something to history, if it's open. If saving fails we give up on
+/// history until next time rather than complaining about every message.
+fn save_history(
+    siv: &mut Cursive,
+    history: &mut Option<History>,
+    buffers: &mut Buffers,
+    entry: HistoryEntry,
+) {
+    let Some(store) = history else {
+        return;
+    };
+    if let Err(err) = store.append(&entry) {
+        *history = None;
+        buffers.set_history(siv, false);
+        notice(
+            siv,
+            buffers.active(),
+            format!("stopped saving history: {err}"),
+        );
+    }
+}
+
+/// A history entry for a notice about someone.
+fn event_entry(id: &Id, username: &str, room: &str, kind: EntryKind, notice: &str) -> HistoryEntry {
+    HistoryEntry {
+        time: chrono::offset::Local::now().timestamp(),
+        kind,
+        id: hex_id(id),
+        msg_id: String::new(),
+        reply_to: None,
+        username: username.to_string(),
+        room: room.to_string(),
+        message: notice.to_string(),
+    }
+}
+
+/// Put what was saved last time back in the rooms it came from, under a
+/// separator so it's clear where it ends.
+fn show_history(
+    siv: &mut Cursive,
+    messages: &mut Messages,
+    rooms: &[String],
+    entries: Vec<HistoryEntry>,
+) {
+    let mut shown: Vec<String> = vec![];
+    for entry in entries {
+        // Entries from before rooms existed were all in the default one.
+        let room = match entry.room.as_str() {
+            "" => DEFAULT_ROOM.to_string(),
+            room => room.to_string(),
+        };
+        if !rooms.contains(&room) {
+            continue;
+        }
+        if !shown.contains(&room) {
+            append_txt(
+                siv,
+                &inner_name(&room),
+                "— history —".dark_grey().to_string(),
+            );
+            shown.push(room.clone());
+        }
+
+        if entry.kind != EntryKind::Message {
+            append_txt(
+                siv,
+                &inner_name(&room),
+                format!("> {}", entry.message).dark_grey().to_string(),
+            );
+            continue;
+        }
+        let author = parse_hex_id(&entry.id).unwrap_or_default();
+        let time = Local
+            .timestamp_opt(entry.time, 0)
+            .single()
+            .unwrap_or_else(Local::now);
+        match parse_hex_id(&entry.msg_id) {
+            Some(msg_id) => {
+                let inserted = messages.insert(
+                    msg_id,
+                    Record {
+                        author,
+                        username: entry.username,
+                        buffer: room.clone(),
+                        time,
+                        msg: entry.message,
+                        reply_to: entry.reply_to.as_deref().and_then(parse_hex_id),
+                        edited: false,
+                        deleted: false,
+                        reactions: vec![],
+                        backfilled: false,
+                    },
+                );
+                if !inserted {
+                    continue;
+                }
+                let print = messages
+                    .render(&msg_id, Delivery::Sent, "")
+                    .unwrap_or_default();
+                update_or_append_txt(siv, &inner_name(&room), &message_name(&msg_id), print);
+            }
+            // Saved before messages had ids.
+            None => append_txt(
+                siv,
+                &inner_name(&room),
+                format_message(
+                    &time,
+                    &author,
+                    &entry.username,
+                    &entry.message,
+                    Delivery::Sent,
+                ),
+            ),
+        }
+    }
+}
+
+/// Show messages we weren't around for, with who they were really from and
+/// when, under `separator` in each buffer they go in. Returns the ones that
+/// were new to us, and which buffer each went in.
+fn show_backfill(
+    siv: &mut Cursive,
+    messages: &mut Messages,
+    buffers: &mut Buffers,
+    separator: &str,
+    entries: Vec<BacklogEntry>,
+) -> Vec<(String, BacklogEntry)> {
+    let mut shown: Vec<String> = vec![];
+    let mut new = vec![];
+    for entry in entries {
+        if messages.get(&entry.msg_id).is_some() {
+            continue;
+        }
+        let buffer = match entry.room.as_str() {
+            "" => {
+                let key = format!("@{}", hex_id(&entry.author));
+                buffers.ensure(siv, &key, &format!("@{}", entry.username));
+                key
+            }
+            room => room.to_string(),
+        };
+        if !shown.contains(&buffer) {
+            append_txt(
+                siv,
+                &inner_name(&buffer),
+                format!("— {separator} —").dark_grey().to_string(),
+            );
+            shown.push(buffer.clone());
+        }
+
+        let time = Local
+            .timestamp_opt(entry.time, 0)
+            .single()
+            .unwrap_or_else(Local::now);
+        messages.insert(
+            entry.msg_id,
+            Record {
+                author: entry.author,
+                username: entry.username.clone(),
+                buffer: buffer.clone(),
+                time,
+                msg: entry.msg.clone(),
+                reply_to: entry.reply_to,
+                edited: false,
+                deleted: false,
+                reactions: vec![],
+                backfilled: true,
+            },
+        );
+        let print = messages
+            .render(&entry.msg_id, Delivery::Sent, "")
+            .unwrap_or_default();
+        update_or_append_txt(
+            siv,
+            &inner_name(&buffer),
+            &message_name(&entry.msg_id),
+            print,
+        );
+        new.push((buffer, entry));
+    }
+    new
+}
+
+/// Say we've read everything in a buffer that just came into view.
+fn mark_read(receipts: &mut Receipts, net_tx: &Sender<NetCommand>, buffer: &str) {
+    let read = receipts.take_unread(buffer);
+    if !read.is_empty() {
+        net_tx.try_send(NetCommand::MarkRead(read)).unwrap();
+    }
+}
+
+/// What the next thing sent from the input box is going to be.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Composing {
+    Message,
+    Reply(Id),
+    Edit(Id),
+}
+
+/// Change what's being composed, and say so above the input box.
+fn set_composing(
+    siv: &mut Cursive,
+    composing: &mut Composing,
+    new: Composing,
+    messages: &Messages,
+) {
+    let title = match new {
+        Composing::Message => None,
+        Composing::Reply(msg_id) => messages.get(&msg_id).map(|parent| {
+            format!(
+                "replying to {}: {}",
+                parent.username,
+                snippet(&parent.msg, 40)
+            )
+        }),
+        Composing::Edit(msg_id) => messages
+            .get(&msg_id)
+            .map(|_| "editing, send it empty to cancel".to_string()),
+    };
+    *composing = if title.is_some() {
+        new
+    } else {
+        Composing::Message
+    };
+    siv.call_on_name("input_panel", |panel: &mut InputPanel| {
+        panel.set_title(title.unwrap_or_default());
+    });
+}
+
+/// Show a message again after it's been changed.
+fn rerender(siv: &mut Cursive, messages: &Messages, receipts: &mut Receipts, msg_id: &Id) {
+    let Some(record) = messages.get(msg_id) else {
+        return;
+    };
+    receipts.shown(msg_id, &record.buffer, &record.msg);
+    let Some(print) = messages.render(msg_id, Delivery::Sent, &receipts.summary(msg_id)) else {
+        return;
+    };
+    update_txt(
+        siv,
+        &inner_name(&record.buffer),
+        &message_name(msg_id),
+        print,
+    );
+}
+
+/// A message, labelled for a message picker.
+fn picker_label(record: &Record) -> String {
+    format!(
+        "{} {}: {}",
+        record.time.format("%H:%M"),
+        record.username,
+        snippet(&record.msg, 50)
+    )
+}
+
+/// The latest messages in a buffer that `filter` lets through, labelled for
+/// a message picker.
+fn picker_items(
+    messages: &Messages,
+    buffer: &str,
+    filter: impl Fn(&Record) -> bool,
+) -> Vec<(String, Id)> {
+    messages
+        .recent(buffer, usize::MAX)
+        .into_iter()
+        .filter(|(_, record)| filter(record))
+        .take(50)
+        .map(|(msg_id, record)| (picker_label(record), msg_id))
+        .collect()
+}
+
+/// Scroll to the match we're on, and say where it is above the input box.
+fn show_match(siv: &mut Cursive, buffers: &Buffers, search: &Search) {
+    let Some(msg_id) = search.current() else {
+        return;
+    };
+    buffers.scroll_to(siv, &search.buffer, &message_name(&msg_id));
+    let (n, total) = search.position();
+    let title = format!(
+        "match {n} of {total} for {}, ctrl-n/ctrl-p to move, ctrl-f to change",
+        search.input
+    );
+    siv.call_on_name("input_panel", |panel: &mut InputPanel| {
+        panel.set_title(title);
+    });
+}
+
+/// Stop searching, take the highlights back out, and go back to following
+/// new messages. Whatever's being composed needs its title putting back.
+fn end_search(
+    siv: &mut Cursive,
+    search: &mut Option<Search>,
+    messages: &mut Messages,
+    receipts: &mut Receipts,
+    buffers: &Buffers,
+) {
+    let Some(search) = search.take() else {
+        return;
+    };
+    messages.set_highlight("", &[], None);
+    for msg_id in search.results() {
+        rerender(siv, messages, receipts, msg_id);
+    }
+    buffers.follow(siv, &search.buffer);
+}
+
+/// Who a message was sent to, if it was a direct message.
+fn direct_peer(record: &Record) -> Option<Id> {
+    record.buffer.strip_prefix('@').and_then(parse_hex_id)
+}
+
 pub fn run() {
+    use_sudo_home();
     let (mut username, mut interface) = ("anonymous".to_string(), "".to_string());
+    let mut unprivileged = false;
+    let mut history: Option<History> = None;
+    // What to show from history once the chat's up.
+    let mut reloaded: Vec<HistoryEntry> = vec![];
+    let mut rooms = CONFIG.lock().unwrap().joined_rooms();
+    let mut buffers = Buffers::new(&rooms);
+    let mut presences = Presences::new();
+    let mut receipts = Receipts::new();
+    let mut messages = Messages::new();
+    let mut images = Images::new();
+    let mut transcript = Transcript::new();
+    let mut topics = Topics::new();
+    let mut search: Option<Search> = None;
+    let use_sixel = sixel_supported();
+    let mut composing = Composing::Message;
+    let mut typing = Typing::new();
+    let mut last_typed: Option<(String, Instant)> = None;
+    let (mut availability, mut status) = (Availability::Available, String::new());
+    // Whether we went away by ourselves, and so should come back by ourselves.
+    let mut auto_away = false;
+    let mut last_active = Instant::now();
+    let auto_away_after = match CONFIG.lock().unwrap().auto_away_minutes {
+        Some(0) => None,
+        minutes => Some(Duration::from_secs(
+            60 * minutes.unwrap_or(DEFAULT_AUTO_AWAY_MINUTES),
+        )),
+    };
+    let local_id: Id = rand::thread_rng().gen();
 
     let (ui_tx, ui_rx) = unbounded::<UICommand>();
     let (net_tx, net_rx) = unbounded::<NetCommand>();
     let net_thread = thread::spawn({
         let ui_tx = ui_tx.clone();
-        move || net_thread::start_net_thread(ui_tx, net_rx)
+        let net_tx = net_tx.clone();
+        move || net_thread::start_net_thread(local_id, ui_tx, net_tx, net_rx)
     });
 
     let mut siv = cursive::default();
     siv.load_toml(include_str!("../assets/theme.toml")).unwrap();
 
-    show_iface_dialog(&mut siv, ui_tx);
+    if CONFIG.lock().unwrap().history == Some(false) {
+        show_iface_dialog(&mut siv, ui_tx.clone(), true);
+    } else {
+        show_passphrase_dialog(&mut siv, ui_tx.clone(), None);
+    }
 
     let mut siv = siv.runner();
     siv.refresh();
     while siv.is_running() {
         while let Ok(cmd) = ui_rx.try_recv() {
             match cmd {
-                UICommand::AlertUser => ring_bell(),
-                UICommand::NewMessage(id, username, msg, is_eager) => {
+                UICommand::AlertUser => {
+                    if availability != Availability::DoNotDisturb {
+                        ring_bell();
+                    }
+                }
+                UICommand::UnlockHistory(passphrase) => match History::open(&passphrase) {
+                    Ok((opened, entries)) => {
+                        history = Some(opened);
+                        let count = CONFIG
+                            .lock()
+                            .unwrap()
+                            .history_reload
+                            .unwrap_or(DEFAULT_HISTORY_RELOAD);
+                        reloaded = entries[entries.len().saturating_sub(count)..].to_vec();
+                        show_iface_dialog(&mut siv, ui_tx.clone(), true);
+                    }
+                    Err(err @ (ArpchatError::WrongPassphrase | ArpchatError::EmptyPassphrase)) => {
+                        show_passphrase_dialog(&mut siv, ui_tx.clone(), Some(err.to_string()));
+                    }
+                    Err(err) => {
+                        let ui_tx = ui_tx.clone();
+                        siv.add_layer(
+                            Dialog::text(err.to_string())
+                                .title("couldn't open history")
+                                .button("Continue without history", move |siv| {
+                                    siv.pop_layer();
+                                    show_iface_dialog(siv, ui_tx.clone(), true);
+                                }),
+                        );
+                    }
+                },
+                UICommand::NewMessage(id, msg_id, reply_to, username, room, msg, delivery) => {
+                    let now = chrono::offset::Local::now();
+                    let inserted = messages.insert(
+                        msg_id,
+                        Record {
+                            author: id,
+                            username: username.clone(),
+                            buffer: room.clone(),
+                            time: now,
+                            msg: msg.clone(),
+                            reply_to,
+                            edited: false,
+                            deleted: false,
+                            reactions: vec![],
+                            backfilled: false,
+                        },
+                    );
+                    // Someone reusing another person's message id.
+                    if !inserted {
+                        continue;
+                    }
+                    if delivery == Delivery::Sent {
+                        if typing.stop(&id) {
+                            typing.show(&mut siv, buffers.active());
+                        }
+                        if receipts.is_ours(&msg_id) {
+                            receipts.shown(&msg_id, &room, &msg);
+                        } else {
+                            receipts.received(&room, id, msg_id);
+                            if room == buffers.active() {
+                                mark_read(&mut receipts, &net_tx, &room);
+                            }
+                        }
+                    }
+                    let print = messages
+                        .render(&msg_id, delivery, &receipts.summary(&msg_id))
+                        .unwrap_or_default();
+
+                    let name = message_name(&msg_id);
+                    update_or_append_txt(&mut siv, &inner_name(&room), &name, print);
+                    if let Some(thumbnail) = images.thumbnail(&msg_id) {
+                        let name = image_name(&msg_id);
+                        update_or_append_txt(&mut siv, &inner_name(&room), &name, thumbnail);
+                    }
+                    if delivery == Delivery::Sent {
+                        if let Some(record) = messages.get(&msg_id) {
+                            transcript.message(msg_id, record);
+                        }
+                        let entry = HistoryEntry {
+                            time: now.timestamp(),
+                            kind: EntryKind::Message,
+                            id: hex_id(&id),
+                            msg_id: hex_id(&msg_id),
+                            reply_to: reply_to.as_ref().map(hex_id),
+                            username,
+                            room: room.clone(),
+                            message: msg.clone(),
+                        };
+                        save_history(&mut siv, &mut history, &mut buffers, entry);
+                    }
+                    if delivery == Delivery::Sent {
+                        buffers.mark_unread(&mut siv, &room);
+                    }
+                }
+                UICommand::NewDirectMessage(
+                    peer,
+                    peer_name,
+                    from,
+                    msg_id,
+                    reply_to,
+                    msg,
+                    delivery,
+                ) => {
                     let now = chrono::offset::Local::now();
+                    let from_name = if from == peer { &peer_name } else { &username };
+                    let key = format!("@{}", hex_id(&peer));
+                    let inserted = messages.insert(
+                        msg_id,
+                        Record {
+                            author: from,
+                            username: from_name.clone(),
+                            buffer: key.clone(),
+                            time: now,
+                            msg: msg.clone(),
+                            reply_to,
+                            edited: false,
+                            deleted: false,
+                            reactions: vec![],
+                            backfilled: false,
+                        },
+                    );
+                    // Someone reusing another person's message id.
+                    if !inserted {
+                        continue;
+                    }
+
+                    buffers.ensure(&mut siv, &key, &format!("@{peer_name}"));
+                    if delivery == Delivery::Sent {
+                        if from == peer {
+                            if typing.stop(&from) {
+                                typing.show(&mut siv, buffers.active());
+                            }
+                            receipts.received(&key, from, msg_id);
+                            if key == buffers.active() {
+                                mark_read(&mut receipts, &net_tx, &key);
+                            }
+                        } else {
+                            receipts.shown(&msg_id, &key, &msg);
+                        }
+                    }
+                    let print = messages
+                        .render(&msg_id, delivery, &receipts.summary(&msg_id))
+                        .unwrap_or_default();
 
-                    let mut print = format!(
-                        "{time} [{username}] {msg}",
-                        time = format!(
-                            "{hours:02}:{mins:02}:{secs:02}",
-                            hours = now.hour(),
-                            mins = now.minute(),
-                            secs = now.second()
-                        )
-                        .dark_grey(),
-                        username = username.with(color_from_id(&id)),
-                    );
-                    if is_eager {
-                        print += &" sending...".dark_grey().to_string();
-                    }
-
-                    update_or_append_txt(&mut siv, "chat_inner", &msg, print);
-                    if !is_eager {
-                        siv.call_on_name(&msg, |child: &mut NamedView<TextView>| {
-                            child.set_name("");
+                    update_or_append_txt(
+                        &mut siv,
+                        &inner_name(&key),
+                        &message_name(&msg_id),
+                        print,
+                    );
+                    if let Some(thumbnail) = images.thumbnail(&msg_id) {
+                        let name = image_name(&msg_id);
+                        update_or_append_txt(&mut siv, &inner_name(&key), &name, thumbnail);
+                    }
+                    match delivery {
+                        Delivery::Sending if buffers.active() != key => {
+                            buffers.switch(&mut siv, &key);
+                            presences.show(&mut siv, &key);
+                            typing.show(&mut siv, &key);
+                            mark_read(&mut receipts, &net_tx, &key);
+                            set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                        }
+                        Delivery::Sending => {}
+                        Delivery::NotEchoed => {}
+                        Delivery::Sent => buffers.mark_unread(&mut siv, &key),
+                    }
+                    if let (Delivery::Sent, Some(record)) = (delivery, messages.get(&msg_id)) {
+                        transcript.message(msg_id, record);
+                    }
+                }
+                UICommand::ExpectReceipts(msg_id, recipients) => {
+                    receipts.expect(msg_id, recipients);
+                }
+                UICommand::Receipts(from, name, delivered, read) => {
+                    for msg_id in receipts.update(from, &name, &delivered, &read) {
+                        rerender(&mut siv, &messages, &mut receipts, &msg_id);
+                    }
+                }
+                UICommand::SwitchBuffer(key) => {
+                    end_search(
+                        &mut siv,
+                        &mut search,
+                        &mut messages,
+                        &mut receipts,
+                        &buffers,
+                    );
+                    buffers.switch(&mut siv, &key);
+                    presences.show(&mut siv, buffers.active());
+                    typing.show(&mut siv, buffers.active());
+                    topics.show(&mut siv, buffers.active());
+                    update_title(
+                        &mut siv,
+                        &username,
+                        &interface,
+                        unprivileged,
+                        topics.title(buffers.active()).as_deref(),
+                    );
+                    mark_read(&mut receipts, &net_tx, buffers.active());
+                    set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                }
+                UICommand::ReplyTo(msg_id) => {
+                    set_composing(
+                        &mut siv,
+                        &mut composing,
+                        Composing::Reply(msg_id),
+                        &messages,
+                    );
+                }
+                UICommand::StartEdit(msg_id) => {
+                    if let Some(record) = messages.get(&msg_id) {
+                        let msg = record.msg.clone();
+                        siv.call_on_name("input", |input: &mut EditView| {
+                            input.set_content(msg);
                         });
+                        set_composing(&mut siv, &mut composing, Composing::Edit(msg_id), &messages);
+                    }
+                }
+                UICommand::EditLast => {
+                    let is_empty = siv
+                        .call_on_name("input", |input: &mut EditView| {
+                            input.get_content().is_empty()
+                        })
+                        .unwrap_or_default();
+                    let last = messages
+                        .recent(buffers.active(), usize::MAX)
+                        .into_iter()
+                        .find(|(_, record)| record.author == local_id && !record.deleted);
+                    if let (true, Some((msg_id, _))) = (is_empty, last) {
+                        ui_tx.try_send(UICommand::StartEdit(msg_id)).unwrap();
+                    }
+                }
+                UICommand::Delete(msg_id) => {
+                    if let Some(record) = messages.get(&msg_id) {
+                        net_tx
+                            .try_send(NetCommand::DeleteMessage(msg_id, direct_peer(record)))
+                            .unwrap();
+                    }
+                }
+                UICommand::MessageEdited(author, msg_id, msg) => {
+                    if let Some(record) = messages.get_mut(&msg_id) {
+                        // Only the author gets to change what they said.
+                        if record.author == author && !record.deleted {
+                            record.msg = msg;
+                            record.edited = true;
+                            rerender(&mut siv, &messages, &mut receipts, &msg_id);
+                        }
+                    }
+                }
+                UICommand::MessageDeleted(author, msg_id) => {
+                    if let Some(record) = messages.get_mut(&msg_id) {
+                        if record.author == author {
+                            record.deleted = true;
+                            let buffer = inner_name(&record.buffer);
+                            rerender(&mut siv, &messages, &mut receipts, &msg_id);
+                            images.remove(&msg_id);
+                            update_txt(&mut siv, &buffer, &image_name(&msg_id), "");
+                        }
+                    }
+                }
+                UICommand::React(msg_id, reaction) => {
+                    if let Some(record) = messages.get_mut(&msg_id) {
+                        let is_added = !record.has_reacted(&local_id, &reaction);
+                        // Show it right away, the echo won't change anything.
+                        record.react(local_id, username.clone(), reaction.clone(), is_added);
+                        let peer = direct_peer(record);
+                        rerender(&mut siv, &messages, &mut receipts, &msg_id);
+                        net_tx
+                            .try_send(NetCommand::React(msg_id, reaction, is_added, peer))
+                            .unwrap();
+                    }
+                }
+                UICommand::Reacted(from, name, msg_id, reaction, is_added) => {
+                    let Some(reaction) = parse_reaction(&reaction) else {
+                        continue;
+                    };
+                    if let Some(record) = messages.get_mut(&msg_id) {
+                        if !record.deleted {
+                            record.react(from, name, reaction, is_added);
+                            rerender(&mut siv, &messages, &mut receipts, &msg_id);
+                        }
+                    }
+                }
+                UICommand::ShowReactions(msg_id) => {
+                    if let Some(record) = messages.get(&msg_id) {
+                        show_reactions_dialog(&mut siv, record.reaction_details());
+                    }
+                }
+                UICommand::Typed => {
+                    let send_typing = CONFIG.lock().unwrap().send_typing != Some(false);
+                    let is_due = last_typed.as_ref().is_none_or(|(buffer, at)| {
+                        buffer != buffers.active() || at.elapsed() > TYPING_INTERVAL
+                    });
+                    if send_typing && is_due {
+                        let buffer = buffers.active().to_string();
+                        net_tx.try_send(NetCommand::Typing(buffer.clone())).unwrap();
+                        last_typed = Some((buffer, Instant::now()));
+                    }
+                }
+                UICommand::Active => {
+                    last_active = Instant::now();
+                    if auto_away {
+                        auto_away = false;
+                        availability = Availability::Available;
+                        net_tx
+                            .try_send(NetCommand::SetStatus(availability, status.clone()))
+                            .unwrap();
+                    }
+                }
+                UICommand::Whois(target) => {
+                    net_tx.try_send(NetCommand::Whois(target)).unwrap();
+                }
+                UICommand::ShowUser(
+                    id,
+                    name,
+                    their_availability,
+                    their_status,
+                    their_rooms,
+                    their_shares,
+                    mac,
+                    since,
+                ) => {
+                    let mut details = their_availability.to_string();
+                    if !their_status.is_empty() {
+                        details += &format!("\n\"{their_status}\"");
+                    }
+                    details += &format!("\nin {}", their_rooms.join(", "));
+                    if !their_shares.is_empty() {
+                        details += &format!("\nsharing {}", their_shares.join(", "));
+                    }
+                    details += &format!(
+                        "\nid {}\nmac {mac}\nlast heard from {}s ago",
+                        hex_id(&id),
+                        since.as_secs()
+                    );
+                    show_presence_dialog(&mut siv, ui_tx.clone(), id, &name, details);
+                }
+                UICommand::Typing(id, name, buffer) => {
+                    typing.start(id, name, buffer);
+                    typing.show(&mut siv, buffers.active());
+                }
+                UICommand::ShowThread(msg_id) => {
+                    let lines = messages
+                        .thread(&msg_id)
+                        .into_iter()
+                        .map(|(depth, record)| "  ".repeat(depth) + &record.line(Delivery::Sent))
+                        .collect();
+                    show_thread_dialog(&mut siv, lines);
+                }
+                UICommand::CollectBacklog(to, wanted) => {
+                    let entries: Vec<BacklogEntry> = messages
+                        .backlog(&wanted, MAX_BACKLOG)
+                        .into_iter()
+                        .map(|(msg_id, record)| BacklogEntry {
+                            msg_id,
+                            author: record.author,
+                            time: record.time.timestamp(),
+                            reply_to: record.reply_to,
+                            username: record.username.clone(),
+                            room: record.buffer.clone(),
+                            msg: record.msg.clone(),
+                        })
+                        .collect();
+                    if !entries.is_empty() {
+                        net_tx
+                            .try_send(NetCommand::SendBacklog(to, entries))
+                            .unwrap();
+                    }
+                }
+                UICommand::Backfill(from, entries) => {
+                    let separator = format!("catching up, sent by {from}");
+                    let new =
+                        show_backfill(&mut siv, &mut messages, &mut buffers, &separator, entries);
+                    for (buffer, entry) in new {
+                        buffers.mark_unread(&mut siv, &buffer);
+                        if let Some(record) = messages.get(&entry.msg_id) {
+                            transcript.message(entry.msg_id, record);
+                        }
+                        let entry = HistoryEntry {
+                            time: entry.time,
+                            kind: EntryKind::Message,
+                            id: hex_id(&entry.author),
+                            msg_id: hex_id(&entry.msg_id),
+                            reply_to: entry.reply_to.as_ref().map(hex_id),
+                            username: entry.username,
+                            room: entry.room,
+                            message: entry.msg,
+                        };
+                        save_history(&mut siv, &mut history, &mut buffers, entry);
+                    }
+                }
+                UICommand::FileOffered(from_name, file_id, buffer, name, size) => {
+                    if buffer.starts_with('@') {
+                        buffers.ensure(&mut siv, &buffer, &format!("@{from_name}"));
+                    }
+                    notice(
+                        &mut siv,
+                        &buffer,
+                        format!("{from_name} is offering {name} ({})", format_size(size)),
+                    );
+                    buffers.mark_unread(&mut siv, &buffer);
+                    if availability != Availability::DoNotDisturb {
+                        ring_bell();
+                    }
+                    show_file_offer_dialog(
+                        &mut siv,
+                        ui_tx.clone(),
+                        &from_name,
+                        file_id,
+                        &name,
+                        size,
+                    );
+                }
+                UICommand::AcceptFile(file_id) => {
+                    net_tx.try_send(NetCommand::AcceptFile(file_id)).unwrap();
+                }
+                UICommand::DeclineFile(file_id) => {
+                    net_tx.try_send(NetCommand::DeclineFile(file_id)).unwrap();
+                }
+                UICommand::TransferProgress(
+                    file_id,
+                    peer,
+                    peer_name,
+                    buffer,
+                    name,
+                    is_upload,
+                    done,
+                    size,
+                ) => {
+                    if buffer.starts_with('@') {
+                        buffers.ensure(&mut siv, &buffer, &format!("@{peer_name}"));
+                    }
+                    let line = match is_upload {
+                        true => format!("sending {name} to {peer_name}"),
+                        false => format!("receiving {name} from {peer_name}"),
+                    };
+                    let line = format!(
+                        "> {line} {} {} of {}",
+                        progress_bar(done, size),
+                        format_size(done),
+                        format_size(size)
+                    );
+                    update_or_append_txt(
+                        &mut siv,
+                        &inner_name(&buffer),
+                        &transfer_name(&file_id, &peer),
+                        line.dark_grey().to_string(),
+                    );
+                }
+                UICommand::TransferDone(file_id, peer, buffer, done) => {
+                    update_or_append_txt(
+                        &mut siv,
+                        &inner_name(&buffer),
+                        &transfer_name(&file_id, &peer),
+                        format!("> {done}").dark_grey().to_string(),
+                    );
+                    buffers.mark_unread(&mut siv, &buffer);
+                }
+                UICommand::Listing(from, path, truncated, entries) => {
+                    let active = buffers.active().to_string();
+                    let heading = match path.trim_matches('/') {
+                        "" => format!("{from} is sharing:"),
+                        path => format!("in {from}'s {path}:"),
+                    };
+                    notice(&mut siv, &active, heading);
+                    if entries.is_empty() {
+                        notice(&mut siv, &active, "  nothing");
+                    }
+                    for entry in entries {
+                        let line = match entry.is_dir {
+                            true => format!("  {}/", entry.name),
+                            false => format!("  {} ({})", entry.name, format_size(entry.size)),
+                        };
+                        notice(&mut siv, &active, line);
+                    }
+                    if truncated {
+                        notice(&mut siv, &active, "  and more that didn't fit");
+                    }
+                }
+                UICommand::Mail(from, entries) => {
+                    let separator = format!("held for you by {from}");
+                    let new =
+                        show_backfill(&mut siv, &mut messages, &mut buffers, &separator, entries);
+                    for (buffer, entry) in new {
+                        receipts.received(&buffer, entry.author, entry.msg_id);
+                        if buffer == buffers.active() {
+                            mark_read(&mut receipts, &net_tx, &buffer);
+                        }
+                        buffers.mark_unread(&mut siv, &buffer);
+                        if let Some(record) = messages.get(&entry.msg_id) {
+                            transcript.message(entry.msg_id, record);
+                        }
+                        // Direct messages never go in history.
+                        if entry.room.is_empty() {
+                            continue;
+                        }
+                        let entry = HistoryEntry {
+                            time: entry.time,
+                            kind: EntryKind::Message,
+                            id: hex_id(&entry.author),
+                            msg_id: hex_id(&entry.msg_id),
+                            reply_to: entry.reply_to.as_ref().map(hex_id),
+                            username: entry.username,
+                            room: entry.room,
+                            message: entry.msg,
+                        };
+                        save_history(&mut siv, &mut history, &mut buffers, entry);
                     }
                 }
+                UICommand::ShowHistory => {
+                    buffers.set_history(&mut siv, history.is_some());
+                    show_history(
+                        &mut siv,
+                        &mut messages,
+                        &rooms,
+                        std::mem::take(&mut reloaded),
+                    );
+                }
                 UICommand::UpdateUsername(new_username) => {
                     if new_username == username {
                         continue;
@@ -81,24 +999,42 @@ pub fn run() {
 
                         let mut config = CONFIG.lock().unwrap();
                         config.username = Some(username.clone());
-                        config.save();
+                        if let Err(err) = config.save() {
+                            notice(&mut siv, buffers.active(), err);
+                        }
                     }
 
                     net_tx
                         .try_send(NetCommand::UpdateUsername(username.clone()))
                         .unwrap();
-                    update_title(&mut siv, &username, &interface);
+                    update_title(
+                        &mut siv,
+                        &username,
+                        &interface,
+                        unprivileged,
+                        topics.title(buffers.active()).as_deref(),
+                    );
                 }
                 UICommand::SetInterface(new_interface) => {
-                    interface = new_interface;
                     net_tx
-                        .try_send(NetCommand::SetInterface(interface.clone()))
+                        .try_send(NetCommand::SetInterface(new_interface))
                         .unwrap();
-                    update_title(&mut siv, &username, &interface);
+                }
+                UICommand::InterfaceChanged(new_interface) => {
+                    interface = new_interface;
+                    update_title(
+                        &mut siv,
+                        &username,
+                        &interface,
+                        unprivileged,
+                        topics.title(buffers.active()).as_deref(),
+                    );
 
                     let mut config = CONFIG.lock().unwrap();
                     config.interface = Some(interface.clone());
-                    config.save();
+                    if let Err(err) = config.save() {
+                        notice(&mut siv, buffers.active(), err);
+                    }
                 }
                 UICommand::SetEtherType(ether_type) => {
                     net_tx
@@ -107,64 +1043,675 @@ pub fn run() {
 
                     let mut config = CONFIG.lock().unwrap();
                     config.ether_type = Some(ether_type);
-                    config.save();
+                    if let Err(err) = config.save() {
+                        notice(&mut siv, buffers.active(), err);
+                    }
                 }
                 UICommand::SendMessage(msg) => {
-                    if msg == "/offline" {
-                        net_tx.try_send(NetCommand::PauseHeartbeat(true)).unwrap();
-                    } else if msg == "/online" {
-                        net_tx.try_send(NetCommand::PauseHeartbeat(false)).unwrap();
-                    } else if !msg.is_empty() {
-                        net_tx.try_send(NetCommand::SendMessage(msg)).unwrap();
+                    if let Composing::Edit(msg_id) = composing {
+                        set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                        if let Some(record) = messages.get(&msg_id) {
+                            if !msg.is_empty() && msg != record.msg {
+                                net_tx
+                                    .try_send(NetCommand::EditMessage(
+                                        msg_id,
+                                        msg,
+                                        direct_peer(record),
+                                    ))
+                                    .unwrap();
+                            }
+                        }
+                    } else {
+                        match commands::parse(&msg) {
+                            Some(Ok(cmd)) => ui_tx.try_send(UICommand::Run(cmd)).unwrap(),
+                            Some(Err(err)) => notice(&mut siv, buffers.active(), err),
+                            None if msg.is_empty() => {}
+                            None => {
+                                let msg = commands::unescape(&msg).to_string();
+                                ui_tx.try_send(UICommand::Say(msg)).unwrap();
+                            }
+                        }
                     }
                 }
-                UICommand::PresenceUpdate(id, username, is_inactive, kind) => {
-                    match kind {
-                        UpdatePresenceKind::JoinOrReconnect => {
-                            append_txt(
+                UICommand::Say(msg) => {
+                    // Whatever gets typed next is a new message.
+                    last_typed = None;
+                    let reply_to = match composing {
+                        Composing::Reply(msg_id) => Some(msg_id),
+                        _ => None,
+                    };
+                    set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                    let cmd = match buffers.active().strip_prefix('@') {
+                        Some(peer) => NetCommand::SendDirect(peer.to_string(), msg, reply_to),
+                        None => {
+                            NetCommand::SendMessage(buffers.active().to_string(), msg, reply_to)
+                        }
+                    };
+                    net_tx.try_send(cmd).unwrap();
+                }
+                UICommand::Run(cmd) => match cmd {
+                    Command::Help(name) => match commands::help(name.as_deref()) {
+                        Ok(lines) => {
+                            for line in lines {
+                                notice(&mut siv, buffers.active(), line);
+                            }
+                        }
+                        Err(err) => notice(&mut siv, buffers.active(), err),
+                    },
+                    Command::Me(action) => {
+                        ui_tx
+                            .try_send(UICommand::Say(format!("{ACTION_PREFIX}{action}")))
+                            .unwrap();
+                    }
+                    Command::Nick(name) => {
+                        ui_tx.try_send(UICommand::UpdateUsername(name)).unwrap();
+                    }
+                    Command::Who => {
+                        net_tx
+                            .try_send(NetCommand::Who(buffers.active().to_string()))
+                            .unwrap();
+                    }
+                    Command::Clear => {
+                        siv.call_on_name(&inner_name(buffers.active()), |log: &mut ChatLog| {
+                            log.clear();
+                        });
+                    }
+                    Command::Quit => siv.quit(),
+                    Command::Protocol => show_ether_type_dialog(&mut siv, ui_tx.clone()),
+                    Command::Interface => show_iface_dialog(&mut siv, ui_tx.clone(), false),
+                    Command::Join(room) => {
+                        end_search(
+                            &mut siv,
+                            &mut search,
+                            &mut messages,
+                            &mut receipts,
+                            &buffers,
+                        );
+                        if !rooms.contains(&room) {
+                            rooms.push(room.clone());
+                            buffers.ensure(&mut siv, &room, &room);
+                            net_tx.try_send(NetCommand::JoinRoom(room.clone())).unwrap();
+
+                            let mut config = CONFIG.lock().unwrap();
+                            config.rooms = Some(rooms.clone());
+                            if let Err(err) = config.save() {
+                                notice(&mut siv, buffers.active(), err);
+                            }
+                        }
+                        buffers.switch(&mut siv, &room);
+                        presences.show(&mut siv, &room);
+                        typing.show(&mut siv, &room);
+                        topics.show(&mut siv, &room);
+                        update_title(
+                            &mut siv,
+                            &username,
+                            &interface,
+                            unprivileged,
+                            topics.title(&room).as_deref(),
+                        );
+                        set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                    }
+                    Command::Part(target) => {
+                        let room = if target.is_empty() {
+                            Some(buffers.active().to_string())
+                        } else {
+                            normalize_room(&target)
+                        };
+                        match room {
+                            Some(room) if rooms.contains(&room) && rooms.len() == 1 => {
+                                notice(
+                                    &mut siv,
+                                    buffers.active(),
+                                    "you can't leave your last room",
+                                );
+                            }
+                            Some(room) if rooms.contains(&room) => {
+                                rooms.retain(|other| *other != room);
+                                if buffers.active() == room {
+                                    buffers.switch(&mut siv, &rooms[0]);
+                                    presences.show(&mut siv, &rooms[0]);
+                                    typing.show(&mut siv, &rooms[0]);
+                                    topics.show(&mut siv, &rooms[0]);
+                                    update_title(
+                                        &mut siv,
+                                        &username,
+                                        &interface,
+                                        unprivileged,
+                                        topics.title(&rooms[0]).as_deref(),
+                                    );
+                                    mark_read(&mut receipts, &net_tx, &rooms[0]);
+                                    set_composing(
+                                        &mut siv,
+                                        &mut composing,
+                                        Composing::Message,
+                                        &messages,
+                                    );
+                                }
+                                receipts.take_unread(&room);
+                                topics.remove(&room);
+                                buffers.remove(&mut siv, &room);
+                                net_tx.try_send(NetCommand::PartRoom(room)).unwrap();
+
+                                let mut config = CONFIG.lock().unwrap();
+                                config.rooms = Some(rooms.clone());
+                                if let Err(err) = config.save() {
+                                    notice(&mut siv, buffers.active(), err);
+                                }
+                            }
+                            Some(room) if target.is_empty() => notice(
                                 &mut siv,
-                                "chat_inner",
-                                format!("> {username} logged on").dark_grey().to_string(),
-                            );
+                                buffers.active(),
+                                format!("{room} isn't a room, try /part #room"),
+                            ),
+                            Some(room) => {
+                                notice(&mut siv, buffers.active(), format!("you're not in {room}"));
+                            }
+                            None => notice(&mut siv, buffers.active(), "usage: /part [#room]"),
                         }
-                        UpdatePresenceKind::UsernameChange(former) if former != username => {
-                            append_txt(
+                    }
+                    Command::Msg(target, text) => {
+                        net_tx
+                            .try_send(NetCommand::SendDirect(target, text, None))
+                            .unwrap();
+                    }
+                    Command::Reply if matches!(composing, Composing::Reply(_)) => {
+                        set_composing(&mut siv, &mut composing, Composing::Message, &messages);
+                    }
+                    Command::Edit(Some(text)) => {
+                        let last = messages
+                            .recent(buffers.active(), usize::MAX)
+                            .into_iter()
+                            .find(|(_, record)| record.author == local_id && !record.deleted);
+                        match last {
+                            Some((msg_id, record)) => {
+                                net_tx
+                                    .try_send(NetCommand::EditMessage(
+                                        msg_id,
+                                        text,
+                                        direct_peer(record),
+                                    ))
+                                    .unwrap();
+                            }
+                            None => {
+                                notice(
+                                    &mut siv,
+                                    buffers.active(),
+                                    "you haven't said anything here",
+                                );
+                            }
+                        }
+                    }
+                    Command::React(reaction) => {
+                        let recent =
+                            picker_items(&messages, buffers.active(), |record| !record.deleted);
+                        show_message_picker(
+                            &mut siv,
+                            ui_tx.clone(),
+                            &format!("react with {reaction}"),
+                            recent,
+                            move |msg_id| UICommand::React(msg_id, reaction.clone()),
+                        );
+                    }
+                    Command::Reply
+                    | Command::Thread
+                    | Command::Edit(None)
+                    | Command::Delete
+                    | Command::Reactions => {
+                        let only_ours = matches!(cmd, Command::Edit(_) | Command::Delete);
+                        let (title, cmd): (&str, fn(Id) -> UICommand) = match cmd {
+                            Command::Reply => ("reply to", UICommand::ReplyTo),
+                            Command::Thread => ("show thread", UICommand::ShowThread),
+                            Command::Edit(_) => ("edit", UICommand::StartEdit),
+                            Command::Reactions => ("show reactions", UICommand::ShowReactions),
+                            _ => ("delete", UICommand::Delete),
+                        };
+                        let recent = picker_items(&messages, buffers.active(), |record| {
+                            !only_ours || (record.author == local_id && !record.deleted)
+                        });
+                        show_message_picker(&mut siv, ui_tx.clone(), title, recent, cmd);
+                    }
+                    Command::Topic(_) | Command::Pin | Command::Unpin
+                        if !buffers.active().starts_with('#') =>
+                    {
+                        notice(
+                            &mut siv,
+                            buffers.active(),
+                            "only rooms have topics and pins",
+                        );
+                    }
+                    Command::Topic(None) => {
+                        let topic = match topics.topic(buffers.active()) {
+                            Some(topic) => describe(topic),
+                            None => "there's no topic here, /topic to set one".to_string(),
+                        };
+                        notice(&mut siv, buffers.active(), topic);
+                    }
+                    Command::Topic(Some(topic)) => {
+                        let topic = topic.chars().take(MAX_TOPIC_LEN).collect();
+                        net_tx
+                            .try_send(NetCommand::SetTopic(buffers.active().to_string(), topic))
+                            .unwrap();
+                    }
+                    Command::Pin => {
+                        let recent =
+                            picker_items(&messages, buffers.active(), |record| !record.deleted);
+                        show_message_picker(&mut siv, ui_tx.clone(), "pin", recent, UICommand::Pin);
+                    }
+                    Command::Unpin => {
+                        let pins = topics
+                            .pins(buffers.active())
+                            .iter()
+                            .rev()
+                            .map(|pin| {
+                                (
+                                    format!("{}: {}", pin.username, snippet(&pin.msg, 50)),
+                                    pin.msg_id,
+                                )
+                            })
+                            .collect();
+                        show_message_picker(
+                            &mut siv,
+                            ui_tx.clone(),
+                            "unpin",
+                            pins,
+                            UICommand::Unpin,
+                        );
+                    }
+                    Command::Pins => topics.toggle(&mut siv, buffers.active()),
+                    Command::Seen => {
+                        show_receipts_dialog(&mut siv, receipts.details(buffers.active()));
+                    }
+                    Command::Retry => net_tx.try_send(NetCommand::Retry).unwrap(),
+                    Command::Status(None) => {
+                        let current = match status.is_empty() {
+                            true => format!("you're {availability}"),
+                            false => format!("you're {availability}: {status}"),
+                        };
+                        notice(&mut siv, buffers.active(), current);
+                    }
+                    Command::Status(Some(_)) | Command::Away(_) | Command::Back => {
+                        (availability, status) = match cmd {
+                            Command::Status(Some((availability, text))) => (availability, text),
+                            Command::Away(text) => (Availability::Away, text),
+                            _ => (Availability::Available, String::new()),
+                        };
+                        status = status.chars().take(MAX_STATUS_LEN).collect();
+                        auto_away = false;
+                        net_tx
+                            .try_send(NetCommand::SetStatus(availability, status.clone()))
+                            .unwrap();
+                    }
+                    Command::Whois(target) => net_tx.try_send(NetCommand::Whois(target)).unwrap(),
+                    Command::Send(args) => {
+                        if let Err(err) = send_file(&args, buffers.active(), &net_tx, &ui_tx) {
+                            notice(&mut siv, buffers.active(), err);
+                        }
+                    }
+                    Command::Img(path) if path.is_empty() => {
+                        let recent =
+                            picker_items(&messages, buffers.active(), |record| !record.deleted)
+                                .into_iter()
+                                .filter(|(_, msg_id)| images.contains(msg_id))
+                                .collect();
+                        show_message_picker(
+                            &mut siv,
+                            ui_tx.clone(),
+                            "look at an image",
+                            recent,
+                            UICommand::ViewImage,
+                        );
+                    }
+                    Command::Img(path) => {
+                        let path = PathBuf::from(path);
+                        let name = path
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        let buffer = buffers.active().to_string();
+                        let net_tx = net_tx.clone();
+                        let ui_tx = ui_tx.clone();
+                        // Big pictures take a while to shrink.
+                        thread::spawn(move || match prepare(&path) {
+                            Ok(image) => net_tx
+                                .try_send(NetCommand::SendImage(buffer, name, image))
+                                .unwrap(),
+                            Err(err) => ui_tx
+                                .try_send(UICommand::Notice(format!("couldn't send {name}: {err}")))
+                                .unwrap(),
+                        });
+                    }
+                    Command::Find(input) if input.is_empty() => {
+                        ui_tx.try_send(UICommand::ShowFind).unwrap();
+                    }
+                    Command::Find(input) => ui_tx.try_send(UICommand::Find(input)).unwrap(),
+                    Command::Share(path) => net_tx.try_send(NetCommand::Share(path)).unwrap(),
+                    Command::Unshare(name) => net_tx.try_send(NetCommand::Unshare(name)).unwrap(),
+                    Command::Ls(target, path) => {
+                        net_tx.try_send(NetCommand::List(target, path)).unwrap();
+                    }
+                    Command::Get(target, path) => {
+                        net_tx.try_send(NetCommand::Get(target, path)).unwrap();
+                    }
+                    Command::Ignore(target) => net_tx.try_send(NetCommand::Ignore(target)).unwrap(),
+                    Command::Unignore(target) => {
+                        net_tx.try_send(NetCommand::Unignore(target)).unwrap();
+                    }
+                    Command::Save(path, format) => {
+                        let buffer = buffers.active().to_string();
+                        let label = buffers.label(&buffer).unwrap_or(&buffer).to_string();
+                        let path = PathBuf::from(path);
+                        let format = format
+                            .or_else(|| Format::parse(path.extension()?.to_str()?))
+                            .unwrap_or(Format::Txt);
+                        let path = if path.as_os_str().is_empty() {
+                            let dir = CONFIG.lock().unwrap().downloads_dir().unwrap_or_default();
+                            dir.join(default_name(&label, format))
+                        } else if path.is_dir() {
+                            path.join(default_name(&label, format))
+                        } else {
+                            path
+                        };
+                        let title = format!(
+                            "{label} on arpchat, saved {}",
+                            Local::now().format("%Y-%m-%d %H:%M")
+                        );
+                        let out = transcript.export(&buffer, &title, &messages, format);
+                        let saved: std::io::Result<()> = try {
+                            if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+                                fs::create_dir_all(dir)?;
+                            }
+                            fs::write(&path, out)?;
+                        };
+                        let result = match saved {
+                            Ok(()) => format!("saved this chat to {}", path.display()),
+                            Err(err) => format!("couldn't save to {}: {err}", path.display()),
+                        };
+                        notice(&mut siv, &buffer, result);
+                    }
+                    Command::Offline => net_tx.try_send(NetCommand::PauseHeartbeat(true)).unwrap(),
+                    Command::Online => net_tx.try_send(NetCommand::PauseHeartbeat(false)).unwrap(),
+                    Command::Wipe => {
+                        history = None;
+                        buffers.set_history(&mut siv, false);
+                        match History::wipe() {
+                            Ok(()) => notice(
                                 &mut siv,
-                                "chat_inner",
-                                format!("> {former} is now known as {username}")
-                                    .dark_grey()
-                                    .to_string(),
-                            );
+                                buffers.active(),
+                                "history wiped, nothing else will be saved until you restart",
+                            ),
+                            Err(err) => notice(&mut siv, buffers.active(), err),
                         }
-                        _ => {}
                     }
-
+                },
+                UICommand::PresenceUpdate(
+                    id,
+                    username,
+                    is_inactive,
+                    kind,
+                    their_rooms,
+                    their_availability,
+                    their_status,
+                ) => {
                     // Update username in presences list.
-                    update_or_append_txt(
+                    let mut label = match (is_inactive, their_availability) {
+                        (true, _) => format!("- {username}").dark_grey().to_string(),
+                        (false, Availability::Available) => {
+                            format!("{} {username}", "*".with(color_from_id(&id)))
+                        }
+                        (false, Availability::Away) => {
+                            format!("~ {username}").dark_grey().to_string()
+                        }
+                        (false, Availability::Busy) => format!("{} {username}", "!".yellow()),
+                        (false, Availability::DoNotDisturb) => {
+                            format!("{} {username}", "x".red())
+                        }
+                    };
+                    if !their_status.is_empty() {
+                        label += &format!(" ({})", snippet(&their_status, 12));
+                    }
+                    let former_rooms = presences.update(
                         &mut siv,
-                        "presences",
-                        &format!("{id:x?}_presence"),
-                        match is_inactive {
-                            true => format!("- {username}").dark_grey().to_string(),
-                            false => format!("{} {username}", "*".with(color_from_id(&id))),
-                        },
+                        id,
+                        label,
+                        their_rooms.clone(),
+                        buffers.active(),
                     );
+
+                    let mut notices = vec![];
+                    let shared = their_rooms.iter().filter(|room| rooms.contains(room));
+                    match kind {
+                        UpdatePresenceKind::JoinOrReconnect => {
+                            notices.extend(shared.map(|room| {
+                                (room, EntryKind::Join, format!("{username} logged on"))
+                            }));
+                        }
+                        UpdatePresenceKind::UsernameChange(former) if former != username => {
+                            notices.extend(shared.map(|room| {
+                                let notice = format!("{former} is now known as {username}");
+                                (room, EntryKind::Nick, notice)
+                            }));
+                        }
+                        _ => {}
+                    }
+                    if let Some(former_rooms) = &former_rooms {
+                        for room in their_rooms
+                            .iter()
+                            .filter(|room| !former_rooms.contains(room))
+                        {
+                            let notice = format!("{username} joined {room}");
+                            notices.push((room, EntryKind::Join, notice));
+                        }
+                        for room in former_rooms
+                            .iter()
+                            .filter(|room| !their_rooms.contains(room))
+                        {
+                            notices.push((
+                                room,
+                                EntryKind::Leave,
+                                format!("{username} left {room}"),
+                            ));
+                        }
+                    }
+                    for (room, kind, notice) in notices {
+                        if !rooms.contains(room) {
+                            continue;
+                        }
+                        append_txt(
+                            &mut siv,
+                            &inner_name(room),
+                            format!("> {notice}").dark_grey().to_string(),
+                        );
+                        transcript.event(room, kind, id, &username, &notice);
+                        let entry = event_entry(&id, &username, room, kind, &notice);
+                        save_history(&mut siv, &mut history, &mut buffers, entry);
+                    }
                 }
                 UICommand::RemovePresence(id, username) => {
+                    let their_rooms = presences.remove(&mut siv, &id).unwrap_or_default();
+                    if typing.stop(&id) {
+                        typing.show(&mut siv, buffers.active());
+                    }
+                    for room in their_rooms.iter().filter(|room| rooms.contains(room)) {
+                        let notice = format!("{username} disconnected, baii~");
+                        append_txt(
+                            &mut siv,
+                            &inner_name(room),
+                            format!("> {notice}").dark_grey().to_string(),
+                        );
+                        transcript.event(room, EntryKind::Leave, id, &username, &notice);
+                        let entry = event_entry(&id, &username, room, EntryKind::Leave, &notice);
+                        save_history(&mut siv, &mut history, &mut buffers, entry);
+                    }
+                }
+                UICommand::HidePresence(id) => {
+                    presences.remove(&mut siv, &id);
+                }
+                UICommand::Ignore(target) => {
+                    net_tx.try_send(NetCommand::Ignore(target)).unwrap();
+                }
+                UICommand::PrivilegesDropped => {
+                    // The net thread can only drop its own capabilities, so
+                    // we have to do ours here.
+                    if let Err(err) = drop_thread_capabilities() {
+                        ui_tx.try_send(UICommand::Error(err)).unwrap();
+                    }
+                    unprivileged = true;
+                    update_title(
+                        &mut siv,
+                        &username,
+                        &interface,
+                        unprivileged,
+                        topics.title(buffers.active()).as_deref(),
+                    );
+                }
+                UICommand::Image(msg_id, name, data) => {
+                    images.insert(msg_id, name, &data);
+                }
+                UICommand::ViewImage(msg_id) => {
+                    if let Some((name, image)) = images.full(&msg_id) {
+                        show_image_viewer(&mut siv, name, image, use_sixel);
+                    }
+                }
+                UICommand::ShowFind => {
+                    let current = search.as_ref().map_or("", |search| &search.input);
+                    show_find_dialog(&mut siv, ui_tx.clone(), current);
+                }
+                UICommand::Find(input) => {
+                    end_search(
+                        &mut siv,
+                        &mut search,
+                        &mut messages,
+                        &mut receipts,
+                        &buffers,
+                    );
+                    // Put back whatever the search's title covered up.
+                    let current = composing;
+                    set_composing(&mut siv, &mut composing, current, &messages);
+                    if input.trim().is_empty() {
+                        continue;
+                    }
+                    let query = match Query::parse(&input) {
+                        Ok(query) => query,
+                        Err(err) => {
+                            notice(&mut siv, buffers.active(), err);
+                            continue;
+                        }
+                    };
+                    let found = Search::new(buffers.active(), input.trim(), query, &messages);
+                    if found.results().is_empty() {
+                        notice(
+                            &mut siv,
+                            buffers.active(),
+                            format!("nothing here matches {}", found.input),
+                        );
+                        continue;
+                    }
+                    messages.set_highlight(&found.buffer, found.query.text(), found.current());
+                    for msg_id in found.results() {
+                        rerender(&mut siv, &messages, &mut receipts, msg_id);
+                    }
+                    show_match(&mut siv, &buffers, &found);
+                    search = Some(found);
+                }
+                UICommand::FindStep(_) | UICommand::JumpTo(_) => {
+                    let Some(found) = &mut search else {
+                        continue;
+                    };
+                    // Whatever it found isn't on screen anymore.
+                    if found.buffer != buffers.active() {
+                        end_search(
+                            &mut siv,
+                            &mut search,
+                            &mut messages,
+                            &mut receipts,
+                            &buffers,
+                        );
+                        // Put back whatever the search's title covered up.
+                        let current = composing;
+                        set_composing(&mut siv, &mut composing, current, &messages);
+                        continue;
+                    }
+                    let moved = match cmd {
+                        UICommand::FindStep(newer) => found.step(newer).map(|(before, _)| before),
+                        UICommand::JumpTo(msg_id) => found.select(&msg_id),
+                        _ => None,
+                    };
+                    if let (Some(before), Some(now)) = (moved, found.current()) {
+                        messages.set_highlight(&found.buffer, found.query.text(), Some(now));
+                        rerender(&mut siv, &messages, &mut receipts, &before);
+                        rerender(&mut siv, &messages, &mut receipts, &now);
+                    }
+                    show_match(&mut siv, &buffers, found);
+                }
+                UICommand::ListMatches => {
+                    let Some(found) = &search else {
+                        continue;
+                    };
+                    let matches = found
+                        .results()
+                        .iter()
+                        .rev()
+                        .filter_map(|msg_id| Some((picker_label(messages.get(msg_id)?), *msg_id)))
+                        .collect();
+                    show_message_picker(
+                        &mut siv,
+                        ui_tx.clone(),
+                        &format!("matches for {}", found.input),
+                        matches,
+                        UICommand::JumpTo,
+                    );
+                }
+                UICommand::RoomInfo(room, topic, pins) => {
+                    let before = topics.set(room.clone(), topic.clone(), pins);
+                    if let Some(topic) = topic.filter(|topic| before.as_ref() != Some(topic)) {
+                        let text = match (&before, topic.text.is_empty()) {
+                            // Someone new to the room wants to know how it is.
+                            (None, _) => describe(&topic),
+                            (Some(_), true) => format!("{} cleared the topic", topic.username),
+                            (Some(_), false) => {
+                                format!("{} set the topic: {}", topic.username, topic.text)
+                            }
+                        };
+                        notice(&mut siv, &room, text);
+                    }
+                    if room == buffers.active() {
+                        topics.show(&mut siv, &room);
+                        update_title(
+                            &mut siv,
+                            &username,
+                            &interface,
+                            unprivileged,
+                            topics.title(&room).as_deref(),
+                        );
+                    }
+                }
+                UICommand::Pin(msg_id) => {
+                    if let Some(record) = messages.get(&msg_id) {
+                        let entry = BacklogEntry {
+                            msg_id,
+                            author: record.author,
+                            time: record.time.timestamp(),
+                            reply_to: record.reply_to,
+                            username: record.username.clone(),
+                            room: record.buffer.clone(),
+                            msg: record.msg.clone(),
+                        };
+                        net_tx
+                            .try_send(NetCommand::Pin(record.buffer.clone(), entry))
+                            .unwrap();
+                    }
+                }
+                UICommand::Unpin(msg_id) => {
+                    net_tx
+                        .try_send(NetCommand::Unpin(buffers.active().to_string(), msg_id))
+                        .unwrap();
+                }
+                UICommand::Notice(notice) => {
                     append_txt(
                         &mut siv,
-                        "chat_inner",
-                        format!("> {username} disconnected, baii~")
-                            .dark_grey()
-                            .to_string(),
+                        &inner_name(buffers.active()),
+                        format!("> {notice}").dark_grey().to_string(),
                     );
-
-                    // Remove from presences list.
-                    siv.call_on_name("presences", |presences: &mut LinearLayout| {
-                        presences
-                            .find_child_from_name(&format!("{id:x?}_presence"))
-                            .map(|presence| presences.remove_child(presence));
-                    });
                 }
                 UICommand::Error(err) => {
                     siv.add_layer(
@@ -177,10 +1724,27 @@ pub fn run() {
             }
             siv.refresh();
         }
+        let is_idle = auto_away_after.is_some_and(|after| last_active.elapsed() > after);
+        if is_idle && availability == Availability::Available {
+            auto_away = true;
+            availability = Availability::Away;
+            net_tx
+                .try_send(NetCommand::SetStatus(availability, status.clone()))
+                .unwrap();
+        }
+        if typing.expire() {
+            typing.show(&mut siv, buffers.active());
+            siv.refresh();
+        }
         siv.step();
+        draw_pending_sixel();
     }
 
     net_tx.try_send(NetCommand::Terminate).unwrap();
     net_thread.join().unwrap();
-    CONFIG.lock().unwrap().save();
+    // Put the terminal back first so this can actually be seen.
+    drop(siv);
+    if let Err(err) = CONFIG.lock().unwrap().save() {
+        eprintln!("{err}");
+    }
 }
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::net::{BacklogEntry, Id, Packet};
+use crate::ringbuffer::Ringbuffer;
+
+use super::net_thread::Net;
+use super::UICommand;
+
+/// How long after showing up we'll take a backlog from someone.
+const BACKLOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest we'll wait before answering a backlog request, so that if more
+/// than one of us thinks it's their turn they don't all answer at once.
+const BACKLOG_JITTER_MS: u64 = 500;
+
+/// Keep the backlog entries we actually want: ones in rooms we're in that
+/// we haven't already seen come in.
+fn wanted_backlog(
+    entries: Vec<BacklogEntry>,
+    rooms: &[String],
+    seen_messages: &Ringbuffer<Id>,
+) -> Vec<BacklogEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| rooms.contains(&entry.room) && !seen_messages.contains(&entry.msg_id))
+        .collect()
+}
+
+/// Catching up on what was said before we got here, and helping anyone who
+/// shows up after us do the same.
+pub struct Backlogs {
+    /// When we asked for one.
+    requested: Option<Instant>,
+    /// The ones we owe people, with when they're due and what rooms they're
+    /// for.
+    due: HashMap<Id, (Instant, Vec<String>)>,
+}
+
+impl Backlogs {
+    pub fn new() -> Self {
+        Self {
+            requested: None,
+            due: HashMap::new(),
+        }
+    }
+
+    /// See what we missed before we got here.
+    pub fn request(&mut self, net: &mut Net, rooms: Vec<String>) {
+        net.send(Packet::HistoryReq(net.local_id, rooms));
+        self.requested = Some(Instant::now());
+    }
+
+    /// It's our turn to answer someone's request, after a moment in case
+    /// anyone else thinks it's theirs too.
+    pub fn owe(&mut self, to: Id, rooms: Vec<String>) {
+        let jitter = rand::thread_rng().gen_range(0..=BACKLOG_JITTER_MS);
+        let due = Instant::now() + Duration::from_millis(jitter);
+        self.due.insert(to, (due, rooms));
+    }
+
+    /// Someone answered a request. If it was ours, show what we didn't
+    /// have, and either way nobody else needs to answer it.
+    pub fn received(
+        &mut self,
+        net: &Net,
+        from: Id,
+        to: Id,
+        entries: Vec<BacklogEntry>,
+        rooms: &[String],
+        seen_messages: &Ringbuffer<Id>,
+    ) {
+        // Someone else already got to it.
+        self.due.remove(&to);
+
+        let expected = self
+            .requested
+            .is_some_and(|asked| asked.elapsed() < BACKLOG_TIMEOUT);
+        if to == net.local_id && expected {
+            let entries = wanted_backlog(entries, rooms, seen_messages);
+            if !entries.is_empty() {
+                net.tx
+                    .try_send(UICommand::Backfill(net.name_of(&from), entries))
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Ask the UI to put together whatever backlogs are due, since that's
+    /// where the history is.
+    pub fn collect_due(&mut self, net: &Net) {
+        let due: Vec<Id> = self
+            .due
+            .iter()
+            .filter(|(_, (due, _))| Instant::now() >= *due)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in due {
+            if let Some((_, rooms)) = self.due.remove(&id) {
+                net.tx
+                    .try_send(UICommand::CollectBacklog(id, rooms))
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::unbounded;
+
+    fn entry(n: u8, room: &str) -> BacklogEntry {
+        BacklogEntry {
+            msg_id: [n; 8],
+            author: [9; 8],
+            time: 0,
+            reply_to: None,
+            username: "someone".to_string(),
+            room: room.to_string(),
+            msg: "hi".to_string(),
+        }
+    }
+
+    #[test]
+    fn only_backlogs_we_asked_for_are_shown() {
+        let (tx, rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let local_id = [1; 8];
+        let mut net = Net::new(local_id, &tx, &macs, &online);
+        let mut backlogs = Backlogs::new();
+        let rooms = vec!["#general".to_string()];
+        let mut seen = Ringbuffer::with_capacity(8);
+        seen.push([2; 8]);
+
+        let entries = || {
+            vec![
+                entry(1, "#general"),
+                entry(2, "#general"),
+                entry(3, "#elsewhere"),
+            ]
+        };
+        backlogs.received(&net, [5; 8], local_id, entries(), &rooms, &seen);
+        assert!(rx.try_recv().is_err());
+
+        backlogs.request(&mut net, rooms.clone());
+        backlogs.received(&net, [5; 8], local_id, entries(), &rooms, &seen);
+        match rx.try_recv() {
+            Ok(UICommand::Backfill(_, entries)) => assert_eq!(entries, [entry(1, "#general")]),
+            _ => panic!("nothing was shown"),
+        }
+    }
+
+    #[test]
+    fn someone_else_answering_lets_us_off() {
+        let (tx, rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let net = Net::new([1; 8], &tx, &macs, &online);
+        let mut backlogs = Backlogs::new();
+        let seen = Ringbuffer::with_capacity(8);
+
+        backlogs.owe([2; 8], vec!["#general".to_string()]);
+        backlogs.received(&net, [3; 8], [2; 8], vec![], &[], &seen);
+        std::thread::sleep(Duration::from_millis(BACKLOG_JITTER_MS + 10));
+        backlogs.collect_due(&net);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use cursive::traits::{Nameable, Resizable};
+use cursive::views::{HideableView, LinearLayout, NamedView, ResizedView, SelectView};
+use cursive::Cursive;
+
+use super::chat_log::ChatLog;
+
+type BufferView = HideableView<ResizedView<ResizedView<NamedView<ChatLog>>>>;
+
+/// Name of the `ChatLog` that holds a buffer's lines.
+pub fn inner_name(key: &str) -> String {
+    format!("chat_inner_{key}")
+}
+
+fn view_name(key: &str) -> String {
+    format!("buffer_{key}")
+}
+
+/// Build the scrolling view for a buffer, hidden unless it's `visible`.
+pub fn buffer_view(key: &str, visible: bool) -> NamedView<BufferView> {
+    let mut view = HideableView::new(
+        ChatLog::new()
+            .with_name(inner_name(key))
+            .full_height()
+            .full_width(),
+    );
+    view.set_visible(visible);
+    view.with_name(view_name(key))
+}
+
+/// Every conversation that's shown in the chat panel, keyed by room name or
+/// by `@` and a peer's hex id. Only one of them is visible at a time, and the
+/// rest get an unread marker in the sidebar when something happens in them.
+pub struct Buffers {
+    active: String,
+    labels: HashMap<String, String>,
+    unread: HashSet<String>,
+    /// Whether history is being saved, which every log wants to know.
+    history: bool,
+}
+
+impl Buffers {
+    /// Start out with the views `init_app` makes for each room, the first
+    /// of which is visible.
+    pub fn new(rooms: &[String]) -> Self {
+        Self {
+            active: rooms.first().cloned().unwrap_or_default(),
+            labels: rooms
+                .iter()
+                .map(|room| (room.clone(), room.clone()))
+                .collect(),
+            unread: HashSet::new(),
+            history: false,
+        }
+    }
+
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// What a buffer's called in the sidebar.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    /// Create a buffer if it doesn't exist yet, otherwise update its label.
+    pub fn ensure(&mut self, siv: &mut Cursive, key: &str, label: &str) {
+        if self.labels.contains_key(key) {
+            self.labels.insert(key.to_string(), label.to_string());
+            self.update_label(siv, key);
+            return;
+        }
+
+        self.labels.insert(key.to_string(), label.to_string());
+        siv.call_on_name("buffer_views", |views: &mut LinearLayout| {
+            views.add_child(buffer_view(key, false));
+        });
+        let history = self.history;
+        siv.call_on_name(&inner_name(key), |log: &mut ChatLog| {
+            log.set_history(history)
+        });
+        siv.call_on_name("buffers", |list: &mut SelectView<String>| {
+            list.add_item(label, key.to_string());
+        });
+    }
+
+    pub fn switch(&mut self, siv: &mut Cursive, key: &str) {
+        if !self.labels.contains_key(key) {
+            return;
+        }
+        for other in self.labels.keys() {
+            siv.call_on_name(&view_name(other), |view: &mut BufferView| {
+                view.set_visible(other == key);
+            });
+        }
+        self.active = key.to_string();
+        self.unread.remove(key);
+        self.update_label(siv, key);
+
+        siv.call_on_name("buffers", |list: &mut SelectView<String>| {
+            let index = list.iter().position(|(_, other)| other == key);
+            if let Some(index) = index {
+                list.set_selection(index);
+            }
+        });
+    }
+
+    /// Get rid of a buffer and everything in it. Switch away from it first.
+    pub fn remove(&mut self, siv: &mut Cursive, key: &str) {
+        if self.labels.remove(key).is_none() {
+            return;
+        }
+        self.unread.remove(key);
+
+        siv.call_on_name("buffer_views", |views: &mut LinearLayout| {
+            if let Some(index) = views.find_child_from_name(&view_name(key)) {
+                views.remove_child(index);
+            }
+        });
+        siv.call_on_name("buffers", |list: &mut SelectView<String>| {
+            let index = list.iter().position(|(_, other)| other == key);
+            if let Some(index) = index {
+                list.remove_item(index);
+            }
+        });
+    }
+
+    /// Scroll a buffer so the line with the given name is in the middle,
+    /// which stops it following new lines. Returns whether it's there.
+    pub fn scroll_to(&self, siv: &mut Cursive, key: &str, name: &str) -> bool {
+        siv.call_on_name(&inner_name(key), |log: &mut ChatLog| log.scroll_to(name))
+            .unwrap_or(false)
+    }
+
+    /// Tell every log whether history is being saved.
+    pub fn set_history(&mut self, siv: &mut Cursive, on: bool) {
+        self.history = on;
+        for key in self.labels.keys() {
+            siv.call_on_name(&inner_name(key), |log: &mut ChatLog| log.set_history(on));
+        }
+    }
+
+    /// Go back to following new lines as they come in.
+    pub fn follow(&self, siv: &mut Cursive, key: &str) {
+        siv.call_on_name(&inner_name(key), |log: &mut ChatLog| log.follow());
+    }
+
+    pub fn mark_unread(&mut self, siv: &mut Cursive, key: &str) {
+        if key != self.active && self.unread.insert(key.to_string()) {
+            self.update_label(siv, key);
+        }
+    }
+
+    fn update_label(&self, siv: &mut Cursive, key: &str) {
+        let mut label = self.labels.get(key).cloned().unwrap_or_default();
+        if self.unread.contains(key) {
+            label += " *";
+        }
+        siv.call_on_name("buffers", |list: &mut SelectView<String>| {
+            let index = list.iter().position(|(_, other)| other == key);
+            if let Some((old_label, _)) = index.and_then(|index| list.get_item_mut(index)) {
+                *old_label = label.into();
+            }
+        });
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{HashMap, VecDeque};
+
+use cursive::backends::crossterm::crossterm::style::Stylize;
+use cursive::direction::Direction;
+use cursive::event::{Event, EventResult, Key, MouseButton, MouseEvent};
+use cursive::theme::ColorStyle;
+use cursive::utils::lines::spans::{LinesIterator, Row};
+use cursive::utils::markup::StyledString;
+use cursive::view::CannotFocus;
+use cursive::{Printer, Vec2, View};
+
+/// Most lines a buffer keeps on screen. Older ones are let go, and only
+/// what went to history when it came in can be found again.
+const MAX_LINES: usize = 2000;
+
+/// How far one notch of the mouse wheel scrolls.
+const WHEEL_ROWS: usize = 3;
+
+struct Line {
+    seq: u64,
+    name: Option<String>,
+    content: StyledString,
+    /// The line wrapped to the width it was last laid out at.
+    rows: Option<(usize, Vec<Row>)>,
+}
+
+impl Line {
+    fn new(seq: u64, name: Option<String>, content: StyledString) -> Self {
+        Self {
+            seq,
+            name,
+            content,
+            rows: None,
+        }
+    }
+}
+
+/// A buffer's lines. Only the ones on screen get wrapped and drawn, so it
+/// doesn't matter how long a session goes on.
+pub struct ChatLog {
+    lines: VecDeque<Line>,
+    /// Where named lines are, by sequence number.
+    names: HashMap<String, u64>,
+    next_seq: u64,
+    /// How many lines fell off the front.
+    dropped: usize,
+    /// Whether history is being saved, so the marker can say if what fell
+    /// off is anywhere else.
+    history: bool,
+    /// The line at the top of the screen and how many of its rows are
+    /// scrolled past, or `None` to stick to the bottom.
+    anchor: Option<(u64, usize)>,
+    /// Where the last layout started drawing from, as an index and rows.
+    shown: (usize, usize),
+    size: Vec2,
+    scrollable: bool,
+}
+
+impl ChatLog {
+    pub fn new() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            names: HashMap::new(),
+            next_seq: 0,
+            dropped: 0,
+            history: false,
+            anchor: None,
+            shown: (0, 0),
+            size: Vec2::zero(),
+            scrollable: false,
+        }
+    }
+
+    fn push(&mut self, name: Option<String>, content: StyledString) {
+        if let Some(name) = &name {
+            self.names.insert(name.clone(), self.next_seq);
+        }
+        self.lines
+            .push_back(Line::new(self.next_seq, name, content));
+        self.next_seq += 1;
+
+        if self.lines.len() <= MAX_LINES {
+            return;
+        }
+        // The front is the marker once anything's been dropped.
+        if self.dropped > 0 {
+            self.lines.pop_front();
+        }
+        let Some(line) = self.lines.pop_front() else {
+            return;
+        };
+        if let Some(name) = line.name {
+            self.names.remove(&name);
+        }
+        self.dropped += 1;
+        self.lines
+            .push_front(Line::new(line.seq, None, self.marker()));
+    }
+
+    fn marker(&self) -> StyledString {
+        let marker = match self.history {
+            true => format!(
+                "— {} older lines let go. messages are still in history, notices are gone for good —",
+                self.dropped
+            ),
+            false => format!(
+                "— {} older lines let go for good, history isn't on —",
+                self.dropped
+            ),
+        };
+        marker.dark_grey().to_string().into()
+    }
+
+    /// Say whether history is being saved, and fix up the marker if there
+    /// is one.
+    pub fn set_history(&mut self, on: bool) {
+        self.history = on;
+        if self.dropped > 0 {
+            let marker = self.marker();
+            let line = &mut self.lines[0];
+            line.content = marker;
+            line.rows = None;
+        }
+    }
+
+    pub fn append<S: Into<StyledString>>(&mut self, content: S) {
+        self.push(None, content.into());
+    }
+
+    /// Set the content of the line with this name, if it's still around.
+    pub fn update<S: Into<StyledString>>(&mut self, name: &str, content: S) -> bool {
+        let Some(index) = self.index_of(name) else {
+            return false;
+        };
+        let line = &mut self.lines[index];
+        line.content = content.into();
+        line.rows = None;
+        true
+    }
+
+    /// Set the content of the line with this name, or add it if it's not
+    /// there.
+    pub fn set<S: Into<StyledString>>(&mut self, name: &str, content: S) {
+        let content = content.into();
+        if !self.update(name, content.clone()) {
+            self.push(Some(name.to_string()), content);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.names.clear();
+        self.dropped = 0;
+        self.anchor = None;
+        self.shown = (0, 0);
+    }
+
+    /// Scroll so the line with this name is in the middle, which stops
+    /// following new lines. Returns whether it's there.
+    pub fn scroll_to(&mut self, name: &str) -> bool {
+        let Some(index) = self.index_of(name) else {
+            return false;
+        };
+        let above = (self.size.y / 2).saturating_sub(self.rows(index) / 2);
+        let (index, skip) = self.move_up((index, 0), above);
+        self.anchor = Some((self.lines[index].seq, skip));
+        true
+    }
+
+    /// Go back to following new lines as they come in.
+    pub fn follow(&mut self) {
+        self.anchor = None;
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        let seq = self.names.get(name)?;
+        let front = self.lines.front()?.seq;
+        let index = usize::try_from(seq - front).ok()?;
+        (index < self.lines.len()).then_some(index)
+    }
+
+    fn width(&self) -> usize {
+        // Leave room for the scrollbar.
+        self.size.x.saturating_sub(1).max(1)
+    }
+
+    /// How many rows a line takes, wrapping it if it hasn't been yet.
+    fn rows(&mut self, index: usize) -> usize {
+        let width = self.width();
+        let line = &mut self.lines[index];
+        match &line.rows {
+            Some((wrapped, rows)) if *wrapped == width => rows.len(),
+            _ => {
+                let rows: Vec<Row> = LinesIterator::new(&line.content, width).collect();
+                let count = rows.len();
+                line.rows = Some((width, rows));
+                count
+            }
+        }
+    }
+
+    fn move_up(&mut self, (mut index, mut skip): (usize, usize), mut by: usize) -> (usize, usize) {
+        while by > 0 {
+            if skip > 0 {
+                let step = skip.min(by);
+                skip -= step;
+                by -= step;
+            } else if index > 0 {
+                index -= 1;
+                skip = self.rows(index);
+            } else {
+                break;
+            }
+        }
+        (index, skip)
+    }
+
+    fn move_down(
+        &mut self,
+        (mut index, mut skip): (usize, usize),
+        mut by: usize,
+    ) -> (usize, usize) {
+        while by > 0 && index < self.lines.len() {
+            let rows = self.rows(index);
+            if skip + by < rows {
+                skip += by;
+                break;
+            }
+            by -= rows - skip;
+            index += 1;
+            skip = 0;
+        }
+        (index, skip)
+    }
+
+    fn scroll_up(&mut self, by: usize) {
+        let (index, skip) = self.move_up(self.shown, by);
+        if let Some(line) = self.lines.get(index) {
+            self.anchor = Some((line.seq, skip));
+        }
+    }
+
+    /// Scroll down, and pick up following again if that reaches the bottom.
+    fn scroll_down(&mut self, by: usize) {
+        let (index, skip) = self.move_down(self.shown, by);
+        self.anchor = self.lines.get(index).map(|line| (line.seq, skip));
+        self.layout(self.size);
+    }
+
+    /// Where drawing starts when sticking to the bottom, and whether
+    /// everything fits.
+    fn bottom(&mut self) -> ((usize, usize), bool) {
+        let mut rows = 0;
+        for index in (0..self.lines.len()).rev() {
+            rows += self.rows(index);
+            if rows >= self.size.y {
+                let start = (index, rows - self.size.y);
+                return (start, start == (0, 0));
+            }
+        }
+        ((0, 0), true)
+    }
+}
+
+impl View for ChatLog {
+    fn draw(&self, printer: &Printer) {
+        let (mut index, mut skip) = self.shown;
+        let mut y = 0;
+        while y < printer.size.y {
+            let Some(line) = self.lines.get(index) else {
+                break;
+            };
+            let Some((_, rows)) = &line.rows else {
+                break;
+            };
+            for row in rows.iter().skip(skip) {
+                if y >= printer.size.y {
+                    break;
+                }
+                let mut x = 0;
+                for span in row.resolve(&line.content) {
+                    printer.with_style(*span.attr, |printer| {
+                        printer.print((x, y), span.content);
+                    });
+                    x += span.width;
+                }
+                y += 1;
+            }
+            index += 1;
+            skip = 0;
+        }
+
+        if !self.scrollable || printer.size.y == 0 {
+            return;
+        }
+        let x = printer.size.x.saturating_sub(1);
+        let height = printer.size.y;
+        let visible = index.saturating_sub(self.shown.0).max(1);
+        let thumb = (height * visible / self.lines.len().max(1)).clamp(1, height);
+        let hidden = self.lines.len().saturating_sub(visible).max(1);
+        let start = ((height - thumb) * self.shown.0 / hidden).min(height - thumb);
+        printer.print_vline((x, 0), height, "|");
+        let color = match printer.focused {
+            true => ColorStyle::highlight(),
+            false => ColorStyle::highlight_inactive(),
+        };
+        printer.with_color(color, |printer| {
+            printer.print_vline((x, start), thumb, "▒");
+        });
+    }
+
+    fn layout(&mut self, size: Vec2) {
+        self.size = size;
+        if let Some((seq, skip)) = self.anchor {
+            let front = self.lines.front().map_or(0, |line| line.seq);
+            // If what we were looking at has been let go, start at the top.
+            let (index, skip) = match seq.checked_sub(front) {
+                Some(index) => (index as usize, skip),
+                None => (0, 0),
+            };
+            if index < self.lines.len() {
+                let skip = skip.min(self.rows(index).saturating_sub(1));
+                // Wrap what'll be drawn, and see if there's more below it.
+                if self.move_down((index, skip), size.y).0 < self.lines.len() {
+                    self.shown = (index, skip);
+                    self.scrollable = true;
+                    return;
+                }
+            }
+            // Scrolled right down, so go back to following.
+            self.anchor = None;
+        }
+        let (start, fits) = self.bottom();
+        self.shown = start;
+        self.scrollable = !fits;
+    }
+
+    fn required_size(&mut self, constraint: Vec2) -> Vec2 {
+        constraint
+    }
+
+    fn take_focus(&mut self, _: Direction) -> Result<EventResult, CannotFocus> {
+        match self.scrollable {
+            true => Ok(EventResult::consumed()),
+            false => Err(CannotFocus),
+        }
+    }
+
+    fn on_event(&mut self, event: Event) -> EventResult {
+        let page = self.size.y.saturating_sub(1).max(1);
+        match event {
+            Event::Key(Key::Up) => self.scroll_up(1),
+            Event::Key(Key::Down) => self.scroll_down(1),
+            Event::Key(Key::PageUp) => self.scroll_up(page),
+            Event::Key(Key::PageDown) => self.scroll_down(page),
+            Event::Key(Key::Home) => {
+                self.anchor = self.lines.front().map(|line| (line.seq, 0));
+            }
+            Event::Key(Key::End) => self.follow(),
+            Event::Mouse {
+                event: MouseEvent::WheelUp,
+                ..
+            } => self.scroll_up(WHEEL_ROWS),
+            Event::Mouse {
+                event: MouseEvent::WheelDown,
+                ..
+            } => self.scroll_down(WHEEL_ROWS),
+            Event::Mouse {
+                event: MouseEvent::Press(MouseButton::Left),
+                ..
+            } if self.scrollable => return EventResult::consumed(),
+            _ => return EventResult::Ignored,
+        }
+        EventResult::consumed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(log: &ChatLog) -> String {
+        log.lines[0].content.source().to_string()
+    }
+
+    #[test]
+    fn marker_only_mentions_history_when_its_on() {
+        let mut log = ChatLog::new();
+        for i in 0..=MAX_LINES {
+            log.append(format!("line {i}"));
+        }
+        assert!(marker(&log).contains("1 older lines"));
+        assert!(!marker(&log).contains("still in history"));
+
+        log.set_history(true);
+        assert!(marker(&log).contains("still in history"));
+        log.append("one more");
+        assert!(marker(&log).contains("2 older lines"));
+        assert!(marker(&log).contains("gone for good"));
+    }
+}
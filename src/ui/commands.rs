@@ -0,0 +1,444 @@
+use crate::net::{normalize_room, Availability};
+
+use super::messages::parse_reaction;
+use super::transcript::Format;
+
+/// Everything that can be typed after a `/`.
+pub enum Command {
+    Help(Option<String>),
+    Me(String),
+    Nick(String),
+    Who,
+    Clear,
+    Quit,
+    Protocol,
+    Interface,
+    Join(String),
+    /// Whatever was typed after it, which might be nothing.
+    Part(String),
+    /// Recipient and message.
+    Msg(String, String),
+    Reply,
+    Thread,
+    Edit(Option<String>),
+    Delete,
+    React(String),
+    Reactions,
+    Seen,
+    Retry,
+    /// The new availability and status line, or nothing to just show them.
+    Status(Option<(Availability, String)>),
+    Away(String),
+    Back,
+    Whois(String),
+    /// Whatever was typed after it: a path, maybe followed by who to send
+    /// it to.
+    Send(String),
+    /// A path to an image, or nothing to pick one to look at.
+    Img(String),
+    /// What to search for, or nothing to ask.
+    Find(String),
+    /// Whatever was typed after it: a folder, or nothing to list what's
+    /// shared.
+    Share(String),
+    Unshare(String),
+    /// Whose shares and the path to list, which might be empty.
+    Ls(String, String),
+    /// Whose shares and the path of the file.
+    Get(String, String),
+    /// Whoever to ignore, or nothing to list everyone ignored.
+    Ignore(String),
+    Unignore(String),
+    /// Where to write this chat's transcript, which might be nothing, and
+    /// what as, if it was said.
+    Save(String, Option<Format>),
+    /// The room's new topic, which is empty to clear it, or nothing to show
+    /// it.
+    Topic(Option<String>),
+    Pin,
+    Unpin,
+    Pins,
+    Offline,
+    Online,
+    Wipe,
+}
+
+struct Spec {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    /// Turns the arguments, trimmed, into a command, or `None` if they don't
+    /// make sense.
+    parse: fn(&str) -> Option<Command>,
+}
+
+const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "help",
+        usage: "[command]",
+        help: "list commands, or explain one",
+        parse: |args| Some(Command::Help((!args.is_empty()).then(|| args.to_string()))),
+    },
+    Spec {
+        name: "me",
+        usage: "<action>",
+        help: "say you're doing something",
+        parse: |args| (!args.is_empty()).then(|| Command::Me(args.to_string())),
+    },
+    Spec {
+        name: "nick",
+        usage: "<name>",
+        help: "change your username",
+        parse: |args| (!args.is_empty()).then(|| Command::Nick(args.to_string())),
+    },
+    Spec {
+        name: "who",
+        usage: "",
+        help: "list who's here",
+        parse: |args| args.is_empty().then_some(Command::Who),
+    },
+    Spec {
+        name: "clear",
+        usage: "",
+        help: "clear this chat's scrollback",
+        parse: |args| args.is_empty().then_some(Command::Clear),
+    },
+    Spec {
+        name: "quit",
+        usage: "",
+        help: "leave arpchat",
+        parse: |args| args.is_empty().then_some(Command::Quit),
+    },
+    Spec {
+        name: "protocol",
+        usage: "",
+        help: "switch the ethertype packets are sent with",
+        parse: |args| args.is_empty().then_some(Command::Protocol),
+    },
+    Spec {
+        name: "interface",
+        usage: "",
+        help: "switch network interface",
+        parse: |args| args.is_empty().then_some(Command::Interface),
+    },
+    Spec {
+        name: "join",
+        usage: "<#room>",
+        help: "join a room, or switch to it",
+        parse: |args| normalize_room(args).map(Command::Join),
+    },
+    Spec {
+        name: "part",
+        usage: "[#room]",
+        help: "leave a room, this one by default",
+        parse: |args| Some(Command::Part(args.to_string())),
+    },
+    Spec {
+        name: "msg",
+        usage: "<user> <message>",
+        help: "send someone a direct message",
+        parse: |args| {
+            let (target, text) = args.split_once(' ')?;
+            let text = text.trim();
+            (!text.is_empty()).then(|| Command::Msg(target.to_string(), text.to_string()))
+        },
+    },
+    Spec {
+        name: "reply",
+        usage: "",
+        help: "reply to a message, or stop replying",
+        parse: |args| args.is_empty().then_some(Command::Reply),
+    },
+    Spec {
+        name: "thread",
+        usage: "",
+        help: "show a message and its replies",
+        parse: |args| args.is_empty().then_some(Command::Thread),
+    },
+    Spec {
+        name: "edit",
+        usage: "[message]",
+        help: "edit a message, or replace your last one",
+        parse: |args| Some(Command::Edit((!args.is_empty()).then(|| args.to_string()))),
+    },
+    Spec {
+        name: "delete",
+        usage: "",
+        help: "delete one of your messages",
+        parse: |args| args.is_empty().then_some(Command::Delete),
+    },
+    Spec {
+        name: "react",
+        usage: "<emoji or :shortcode:>",
+        help: "react to a message, again to take it back",
+        parse: |args| parse_reaction(args).map(Command::React),
+    },
+    Spec {
+        name: "reactions",
+        usage: "",
+        help: "see who reacted to a message",
+        parse: |args| args.is_empty().then_some(Command::Reactions),
+    },
+    Spec {
+        name: "seen",
+        usage: "",
+        help: "see who's read your messages",
+        parse: |args| args.is_empty().then_some(Command::Seen),
+    },
+    Spec {
+        name: "retry",
+        usage: "",
+        help: "resend messages that didn't go out",
+        parse: |args| args.is_empty().then_some(Command::Retry),
+    },
+    Spec {
+        name: "status",
+        usage: "[available|away|busy|dnd] [status]",
+        help: "set your status, or show it",
+        parse: |args| {
+            if args.is_empty() {
+                return Some(Command::Status(None));
+            }
+            let (name, text) = args.split_once(' ').unwrap_or((args, ""));
+            let availability = Availability::parse(name)?;
+            Some(Command::Status(Some((
+                availability,
+                text.trim().to_string(),
+            ))))
+        },
+    },
+    Spec {
+        name: "away",
+        usage: "[status]",
+        help: "say you're away",
+        parse: |args| Some(Command::Away(args.to_string())),
+    },
+    Spec {
+        name: "back",
+        usage: "",
+        help: "say you're back",
+        parse: |args| args.is_empty().then_some(Command::Back),
+    },
+    Spec {
+        name: "whois",
+        usage: "<user>",
+        help: "show someone's details",
+        parse: |args| (!args.is_empty()).then(|| Command::Whois(args.to_string())),
+    },
+    Spec {
+        name: "send",
+        usage: "<path> [user]",
+        help: "offer someone a file, or everyone here",
+        parse: |args| (!args.is_empty()).then(|| Command::Send(args.to_string())),
+    },
+    Spec {
+        name: "img",
+        usage: "[path]",
+        help: "send a picture, or look at one full size",
+        parse: |args| Some(Command::Img(args.to_string())),
+    },
+    Spec {
+        name: "find",
+        usage: "[words] [from:user] [after:time] [before:time]",
+        help: "search this chat's scrollback, ctrl-f works too",
+        parse: |args| Some(Command::Find(args.to_string())),
+    },
+    Spec {
+        name: "share",
+        usage: "[folder]",
+        help: "let everyone see and fetch files in a folder, or list what you're sharing",
+        parse: |args| Some(Command::Share(args.to_string())),
+    },
+    Spec {
+        name: "unshare",
+        usage: "<name>",
+        help: "stop sharing a folder",
+        parse: |args| (!args.is_empty()).then(|| Command::Unshare(args.to_string())),
+    },
+    Spec {
+        name: "ls",
+        usage: "<user> [path]",
+        help: "see what someone's sharing",
+        parse: |args| {
+            let (target, path) = args.split_once(' ').unwrap_or((args, ""));
+            (!target.is_empty()).then(|| Command::Ls(target.to_string(), path.trim().to_string()))
+        },
+    },
+    Spec {
+        name: "get",
+        usage: "<user> <path>",
+        help: "fetch a file someone's sharing",
+        parse: |args| {
+            let (target, path) = args.split_once(' ')?;
+            let path = path.trim();
+            (!path.is_empty()).then(|| Command::Get(target.to_string(), path.to_string()))
+        },
+    },
+    Spec {
+        name: "ignore",
+        usage: "[user or mac]",
+        help: "ignore someone, or list who's ignored",
+        parse: |args| Some(Command::Ignore(args.to_string())),
+    },
+    Spec {
+        name: "unignore",
+        usage: "<user or mac>",
+        help: "stop ignoring someone",
+        parse: |args| (!args.is_empty()).then(|| Command::Unignore(args.to_string())),
+    },
+    Spec {
+        name: "save",
+        usage: "[path] [--format txt|md|json|html]",
+        help: "write out everything said here this session",
+        parse: |args| {
+            let (path, format) = match args.rsplit_once("--format") {
+                Some((path, format)) => {
+                    let format = format.trim().trim_start_matches('=').trim_start();
+                    (path.trim(), Some(Format::parse(format)?))
+                }
+                None => (args, None),
+            };
+            Some(Command::Save(path.to_string(), format))
+        },
+    },
+    Spec {
+        name: "topic",
+        usage: "[topic|--clear]",
+        help: "show this room's topic, or set it",
+        parse: |args| match args {
+            "" => Some(Command::Topic(None)),
+            "--clear" => Some(Command::Topic(Some(String::new()))),
+            topic => Some(Command::Topic(Some(topic.to_string()))),
+        },
+    },
+    Spec {
+        name: "pin",
+        usage: "",
+        help: "pin a message in this room",
+        parse: |args| args.is_empty().then_some(Command::Pin),
+    },
+    Spec {
+        name: "unpin",
+        usage: "",
+        help: "take down a pinned message",
+        parse: |args| args.is_empty().then_some(Command::Unpin),
+    },
+    Spec {
+        name: "pins",
+        usage: "",
+        help: "show or hide what's pinned here",
+        parse: |args| args.is_empty().then_some(Command::Pins),
+    },
+    Spec {
+        name: "offline",
+        usage: "",
+        help: "stop sending heartbeats",
+        parse: |args| args.is_empty().then_some(Command::Offline),
+    },
+    Spec {
+        name: "online",
+        usage: "",
+        help: "start sending heartbeats again",
+        parse: |args| args.is_empty().then_some(Command::Online),
+    },
+    Spec {
+        name: "wipe",
+        usage: "",
+        help: "delete your saved history",
+        parse: |args| args.is_empty().then_some(Command::Wipe),
+    },
+];
+
+fn find(name: &str) -> Option<&'static Spec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+fn usage(spec: &Spec) -> String {
+    match spec.usage {
+        "" => format!("/{}", spec.name),
+        usage => format!("/{} {usage}", spec.name),
+    }
+}
+
+/// Parse something typed into the input box. Returns `None` if it's a
+/// message rather than a command, and what went wrong if it's a command that
+/// doesn't make sense.
+pub fn parse(input: &str) -> Option<Result<Command, String>> {
+    let input = input.strip_prefix('/')?;
+    if input.starts_with('/') {
+        return None;
+    }
+    let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+    let Some(spec) = find(name) else {
+        return Some(Err(format!("unknown command /{name}, try /help")));
+    };
+    Some((spec.parse)(args.trim()).ok_or_else(|| format!("usage: {}", usage(spec))))
+}
+
+/// A message as it should be sent. Starting one with `//` sends it with a
+/// single `/` instead of running a command.
+pub fn unescape(msg: &str) -> &str {
+    match msg.strip_prefix("//") {
+        Some(_) => &msg[1..],
+        None => msg,
+    }
+}
+
+/// Every command, or just the one asked about, one per line.
+pub fn help(name: Option<&str>) -> Result<Vec<String>, String> {
+    let line = |spec: &Spec| format!("{} - {}", usage(spec), spec.help);
+    match name {
+        Some(name) => {
+            let name = name.strip_prefix('/').unwrap_or(name);
+            let spec = find(name).ok_or_else(|| format!("there's no /{name}"))?;
+            Ok(vec![line(spec)])
+        }
+        None => {
+            let mut lines: Vec<String> = COMMANDS.iter().map(line).collect();
+            lines.push("start a message with // to send it as-is".to_string());
+            Ok(lines)
+        }
+    }
+}
+
+/// What tab does to the input box.
+pub enum Completion {
+    Nothing,
+    /// Fill in as much of a command's name as we can.
+    Complete(String),
+    /// There's nothing more to fill in, but it could be any of these.
+    Ambiguous(Vec<&'static str>),
+}
+
+pub fn complete(input: &str) -> Completion {
+    let Some(partial) = input.strip_prefix('/') else {
+        return Completion::Nothing;
+    };
+    if partial.contains(' ') {
+        return Completion::Nothing;
+    }
+    let names: Vec<&str> = COMMANDS
+        .iter()
+        .map(|spec| spec.name)
+        .filter(|name| name.starts_with(partial))
+        .collect();
+    match names.as_slice() {
+        [] => Completion::Nothing,
+        [name] => Completion::Complete(format!("/{name} ")),
+        [first, rest @ ..] => {
+            let common = rest.iter().fold(first.len(), |len, name| {
+                first
+                    .bytes()
+                    .zip(name.bytes())
+                    .take(len)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+            if common > partial.len() {
+                Completion::Complete(format!("/{}", &first[..common]))
+            } else {
+                Completion::Ambiguous(names)
+            }
+        }
+    }
+}
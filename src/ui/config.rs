@@ -2,16 +2,100 @@ use once_cell::sync::Lazy;
 use std::path::PathBuf;
 use std::{fs, sync::Mutex};
 
-use directories::ProjectDirs;
+use directories::{ProjectDirs, UserDirs};
 use serde::{Deserialize, Serialize};
 
-use crate::net::EtherType;
+use crate::error::ArpchatError;
+use crate::net::{EtherType, DEFAULT_ROOM};
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
     pub username: Option<String>,
     pub interface: Option<String>,
     pub ether_type: Option<EtherType>,
+    /// Set to false to never save history, or be asked for a passphrase.
+    pub history: Option<bool>,
+    /// How many of the most recent history entries to show on startup.
+    pub history_reload: Option<usize>,
+    /// Forget history older than this many days.
+    pub history_retention_days: Option<u32>,
+    /// Only keep this many of the most recent history entries.
+    pub history_retention_messages: Option<usize>,
+    pub rooms: Option<Vec<String>>,
+    /// Set to false to stop telling people when you're typing.
+    pub send_typing: Option<bool>,
+    /// Go away after this many minutes without touching anything, or never
+    /// if it's 0.
+    pub auto_away_minutes: Option<u64>,
+    /// Set to false to stop holding messages for people who've gone offline.
+    pub mailbox: Option<bool>,
+    /// Where files people send get saved. Defaults to the downloads folder.
+    pub downloads: Option<String>,
+    /// Whether the terminal can show sixel graphics, for looking at images
+    /// full size. Guessed from the terminal's name if it's not set.
+    pub sixel: Option<bool>,
+    // Tables have to come after plain values for TOML serialization to work.
+    #[serde(default)]
+    pub ignored: Vec<IgnoredPeer>,
+    #[serde(default)]
+    pub shares: Vec<SharedFolder>,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+/// Someone whose traffic is dropped, matched by MAC address.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IgnoredPeer {
+    /// What they were called when they got ignored, for `/unignore`.
+    pub name: String,
+    /// Entries from before this was the only thing matched on might not
+    /// have one, and they're skipped.
+    #[serde(default)]
+    pub mac: String,
+}
+
+/// A folder anyone can list and fetch files from, but not change.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SharedFolder {
+    /// What everyone else sees it as.
+    pub name: String,
+    pub path: String,
+    /// Files bigger than this many megabytes aren't shared. Defaults to 100.
+    pub max_size_mb: Option<u64>,
+}
+
+/// Limits on incoming traffic, applied per source MAC and per sender id.
+/// Rates are in packets per second, bursts in packets.
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[serde(default)]
+pub struct RateLimits {
+    pub message_rate: f64,
+    pub message_burst: f64,
+    pub presence_rate: f64,
+    pub presence_burst: f64,
+    pub typing_rate: f64,
+    pub typing_burst: f64,
+    /// For file transfer chunks and their acknowledgements.
+    pub transfer_rate: f64,
+    pub transfer_burst: f64,
+    /// Minimum time between answers to presence requests.
+    pub presence_reply_interval_ms: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            message_rate: 2.0,
+            message_burst: 10.0,
+            presence_rate: 1.0,
+            presence_burst: 5.0,
+            typing_rate: 0.5,
+            typing_burst: 2.0,
+            transfer_rate: 128.0,
+            transfer_burst: 256.0,
+            presence_reply_interval_ms: 1000,
+        }
+    }
 }
 
 impl Config {
@@ -21,13 +105,37 @@ impl Config {
         toml::from_slice(&data).unwrap_or_default()
     }
 
-    pub fn save(&self) {
-        let _: Option<()> = try {
-            let data = toml::to_vec(&self).ok()?;
-            let path = Self::get_config_path()?;
-            fs::create_dir_all(path.parent()?).ok()?;
-            fs::write(path, data).ok()?;
+    pub fn save(&self) -> Result<(), ArpchatError> {
+        let path = Self::get_config_path()
+            .ok_or_else(|| ArpchatError::ConfigSaveFailed("no config directory".to_string()))?;
+        let fail = |err: std::io::Error| {
+            ArpchatError::ConfigSaveFailed(format!("{}: {err}", path.display()))
         };
+        let data =
+            toml::to_vec(&self).map_err(|err| ArpchatError::ConfigSaveFailed(err.to_string()))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(fail)?;
+        }
+        fs::write(&path, data).map_err(fail)
+    }
+
+    /// The rooms we're in, or just the default one if nobody's said otherwise.
+    pub fn joined_rooms(&self) -> Vec<String> {
+        match &self.rooms {
+            Some(rooms) if !rooms.is_empty() => rooms.clone(),
+            _ => vec![DEFAULT_ROOM.to_string()],
+        }
+    }
+
+    pub fn downloads_dir(&self) -> Option<PathBuf> {
+        if let Some(downloads) = &self.downloads {
+            return Some(PathBuf::from(downloads));
+        }
+        if let Some(dir) = UserDirs::new().and_then(|dirs| Some(dirs.download_dir()?.to_owned())) {
+            return Some(dir);
+        }
+        let dirs = ProjectDirs::from("dev", "kognise", "arpchat")?;
+        Some(dirs.data_dir().join("downloads"))
     }
 
     pub fn get_config_path() -> Option<PathBuf> {
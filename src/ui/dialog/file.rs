@@ -0,0 +1,37 @@
+use crossbeam_channel::Sender;
+use cursive::traits::Resizable;
+use cursive::views::{Dialog, TextView};
+use cursive::Cursive;
+
+use crate::net::Id;
+
+use crate::ui::util::{format_size, UICommand};
+
+pub fn show_file_offer_dialog(
+    siv: &mut Cursive,
+    ui_tx: Sender<UICommand>,
+    from: &str,
+    file_id: Id,
+    name: &str,
+    size: u64,
+) {
+    let decline_tx = ui_tx.clone();
+    siv.add_layer(
+        Dialog::around(TextView::new(format!(
+            "{from} wants to send you {name} ({}).",
+            format_size(size)
+        )))
+        .title("incoming file")
+        .button("Accept", move |siv| {
+            ui_tx.try_send(UICommand::AcceptFile(file_id)).unwrap();
+            siv.pop_layer();
+        })
+        .button("Decline", move |siv| {
+            decline_tx
+                .try_send(UICommand::DeclineFile(file_id))
+                .unwrap();
+            siv.pop_layer();
+        })
+        .min_width(32),
+    );
+}
@@ -0,0 +1,67 @@
+use crossbeam_channel::Sender;
+use cursive::traits::{Nameable, Resizable};
+use cursive::views::{Dialog, EditView, LinearLayout, TextView};
+use cursive::Cursive;
+
+use crate::ui::util::UICommand;
+
+fn query(siv: &mut Cursive) -> String {
+    siv.call_on_name("find_input", |input: &mut EditView| input.get_content())
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn show_find_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>, current: &str) {
+    if siv.find_name::<Dialog>("find_dialog").is_some() {
+        return;
+    }
+
+    siv.add_layer(
+        Dialog::new()
+            .title("find")
+            .content(
+                LinearLayout::vertical()
+                    .child(
+                        EditView::new()
+                            .content(current)
+                            .on_submit({
+                                let ui_tx = ui_tx.clone();
+                                move |siv, query| {
+                                    siv.pop_layer();
+                                    ui_tx.try_send(UICommand::Find(query.to_string())).unwrap();
+                                }
+                            })
+                            .with_name("find_input"),
+                    )
+                    .child(TextView::new(
+                        "narrow it down with from:name, after:14:30 or before:2h\n\
+                         ctrl-n and ctrl-p go between matches",
+                    )),
+            )
+            .button("Find", {
+                let ui_tx = ui_tx.clone();
+                move |siv| {
+                    let query = query(siv);
+                    siv.pop_layer();
+                    ui_tx.try_send(UICommand::Find(query)).unwrap();
+                }
+            })
+            .button("List", {
+                let ui_tx = ui_tx.clone();
+                move |siv| {
+                    let query = query(siv);
+                    siv.pop_layer();
+                    ui_tx.try_send(UICommand::Find(query)).unwrap();
+                    ui_tx.try_send(UICommand::ListMatches).unwrap();
+                }
+            })
+            .button("Clear", move |siv| {
+                siv.pop_layer();
+                ui_tx.try_send(UICommand::Find(String::new())).unwrap();
+            })
+            .dismiss_button("Cancel")
+            .with_name("find_dialog")
+            .full_width()
+            .max_width(56),
+    );
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use cursive::theme::ColorStyle;
+use cursive::views::Dialog;
+use cursive::{Cursive, Printer, Vec2, View};
+use image::RgbImage;
+
+use crate::ui::images::{fit, half_blocks, queue_sixel, sixel};
+
+/// An image drawn as big as it'll go, with half blocks or, if the terminal
+/// can do it, sixels.
+struct ImageView {
+    image: RgbImage,
+    sixel: Option<Arc<String>>,
+    blocks: Vec<Vec<ColorStyle>>,
+}
+
+impl View for ImageView {
+    fn layout(&mut self, size: Vec2) {
+        if self.sixel.is_none() {
+            self.blocks = half_blocks(&fit(&self.image, size.x, size.y));
+        }
+    }
+
+    fn draw(&self, printer: &Printer) {
+        if let Some(sixel) = &self.sixel {
+            // Cursive doesn't know about sixels, so they go on top once it's
+            // done.
+            queue_sixel(printer.offset, sixel.clone());
+            return;
+        }
+        let width = self.blocks.first().map_or(0, |row| row.len());
+        let left = printer.size.x.saturating_sub(width) / 2;
+        let top = printer.size.y.saturating_sub(self.blocks.len()) / 2;
+        for (y, row) in self.blocks.iter().enumerate() {
+            for (x, style) in row.iter().enumerate() {
+                printer.with_color(*style, |printer| printer.print((left + x, top + y), "▀"));
+            }
+        }
+    }
+
+    fn required_size(&mut self, constraint: Vec2) -> Vec2 {
+        constraint
+    }
+}
+
+pub fn show_image_viewer(siv: &mut Cursive, name: &str, image: &RgbImage, use_sixel: bool) {
+    let view = ImageView {
+        image: image.clone(),
+        sixel: use_sixel.then(|| Arc::new(sixel(image))),
+        blocks: vec![],
+    };
+    siv.add_fullscreen_layer(Dialog::around(view).title(name).dismiss_button("Close"));
+}
@@ -9,7 +9,7 @@ use crate::ui::util::UICommand;
 
 use super::username::show_username_dialog;
 
-pub fn show_iface_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
+pub fn show_iface_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>, init_after: bool) {
     let interfaces = sorted_usable_interfaces();
     let preferred_index: Option<usize> = try {
         let config = CONFIG.lock().ok()?;
@@ -20,7 +20,11 @@ pub fn show_iface_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
 
     siv.add_layer(
         Dialog::new()
-            .title("select an interface")
+            .title(if init_after {
+                "select an interface"
+            } else {
+                "switch interface"
+            })
             .content(
                 SelectView::new()
                     .with_all(interfaces.into_iter().map(|iface| {
@@ -43,7 +47,9 @@ pub fn show_iface_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                             .try_send(UICommand::SetInterface(name.clone()))
                             .unwrap();
                         siv.pop_layer();
-                        show_username_dialog(siv, ui_tx.clone(), true);
+                        if init_after {
+                            show_username_dialog(siv, ui_tx.clone(), true);
+                        }
                     }),
             )
             .min_width(32),
@@ -0,0 +1,66 @@
+use crossbeam_channel::Sender;
+use cursive::traits::{Resizable, Scrollable};
+use cursive::views::{Dialog, SelectView, TextView};
+use cursive::Cursive;
+
+use crate::net::Id;
+use crate::ui::util::UICommand;
+
+/// Pick one of the recent messages in a buffer, newest first, and send
+/// whatever `cmd` makes of it.
+pub fn show_message_picker(
+    siv: &mut Cursive,
+    ui_tx: Sender<UICommand>,
+    title: &str,
+    messages: Vec<(String, Id)>,
+    cmd: impl Fn(Id) -> UICommand + 'static,
+) {
+    if messages.is_empty() {
+        siv.add_layer(
+            Dialog::text("there's nothing here yet")
+                .title(title)
+                .dismiss_button("Close"),
+        );
+        return;
+    }
+
+    siv.add_layer(
+        Dialog::around(
+            SelectView::new()
+                .with_all(messages)
+                .on_submit(move |siv, msg_id: &Id| {
+                    siv.pop_layer();
+                    ui_tx.try_send(cmd(*msg_id)).unwrap();
+                })
+                .scrollable()
+                .max_height(16),
+        )
+        .title(title)
+        .dismiss_button("Cancel")
+        .max_width(72),
+    );
+}
+
+pub fn show_reactions_dialog(siv: &mut Cursive, details: String) {
+    let details = match details.is_empty() {
+        true => "nobody's reacted to this yet".to_string(),
+        false => details,
+    };
+    siv.add_layer(
+        Dialog::around(TextView::new(details).scrollable())
+            .title("reactions")
+            .dismiss_button("Close")
+            .min_width(32)
+            .max_height(16),
+    );
+}
+
+pub fn show_thread_dialog(siv: &mut Cursive, lines: Vec<String>) {
+    siv.add_layer(
+        Dialog::around(TextView::new(lines.join("\n")).scrollable())
+            .title("thread")
+            .dismiss_button("Close")
+            .max_width(96)
+            .max_height(24),
+    );
+}
@@ -0,0 +1,91 @@
+use crossbeam_channel::Sender;
+use cursive::traits::{Nameable, Resizable};
+use cursive::views::{Dialog, EditView, LinearLayout, TextView};
+use cursive::Cursive;
+
+use crate::ui::history::History;
+use crate::ui::util::UICommand;
+
+use super::interface::show_iface_dialog;
+
+/// Hand the passphrase over, unless it's empty or, when making a new store,
+/// doesn't match the confirmation. A typo there would lock you out for good.
+fn submit(siv: &mut Cursive, ui_tx: &Sender<UICommand>, creating: bool) {
+    let content = |siv: &mut Cursive, name: &str| {
+        siv.call_on_name(name, |input: &mut EditView| input.get_content())
+            .unwrap()
+    };
+    let passphrase = content(siv, "passphrase_input");
+    let error = if passphrase.is_empty() {
+        Some("the passphrase can't be empty".to_string())
+    } else if creating && content(siv, "passphrase_confirm") != passphrase {
+        Some("those didn't match".to_string())
+    } else {
+        None
+    };
+
+    siv.pop_layer();
+    match error {
+        Some(error) => show_passphrase_dialog(siv, ui_tx.clone(), Some(error)),
+        None => ui_tx
+            .try_send(UICommand::UnlockHistory(passphrase.to_string()))
+            .unwrap(),
+    }
+}
+
+pub fn show_passphrase_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>, error: Option<String>) {
+    let creating = !History::exists();
+    let explanation = match (creating, error) {
+        (_, Some(error)) => format!("{error}, try again.\n "),
+        (false, None) => "enter your passphrase to unlock chat history.\n ".to_string(),
+        (true, None) => {
+            "choose a passphrase to encrypt chat history with. there's no way to recover it if you forget!\n ".to_string()
+        }
+    };
+
+    let mut layout = LinearLayout::vertical()
+        .child(TextView::new(explanation))
+        .child(
+            EditView::new()
+                .secret()
+                .on_submit({
+                    let ui_tx = ui_tx.clone();
+                    move |siv, _| match creating {
+                        true => {
+                            let _ = siv.focus_name("passphrase_confirm");
+                        }
+                        false => submit(siv, &ui_tx, creating),
+                    }
+                })
+                .with_name("passphrase_input"),
+        );
+    if creating {
+        layout = layout
+            .child(TextView::new(" \nonce more to be sure:"))
+            .child(
+                EditView::new()
+                    .secret()
+                    .on_submit({
+                        let ui_tx = ui_tx.clone();
+                        move |siv, _| submit(siv, &ui_tx, creating)
+                    })
+                    .with_name("passphrase_confirm"),
+            );
+    }
+
+    siv.add_layer(
+        Dialog::new()
+            .title("chat history")
+            .content(layout)
+            .button("Unlock", {
+                let ui_tx = ui_tx.clone();
+                move |siv| submit(siv, &ui_tx, creating)
+            })
+            .button("Skip", move |siv| {
+                siv.pop_layer();
+                show_iface_dialog(siv, ui_tx.clone(), true);
+            })
+            .full_width()
+            .max_width(48),
+    );
+}
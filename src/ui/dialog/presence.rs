@@ -0,0 +1,27 @@
+use crossbeam_channel::Sender;
+use cursive::traits::Resizable;
+use cursive::views::{Dialog, TextView};
+use cursive::Cursive;
+
+use crate::net::Id;
+
+use crate::ui::util::{hex_id, UICommand};
+
+pub fn show_presence_dialog(
+    siv: &mut Cursive,
+    ui_tx: Sender<UICommand>,
+    id: Id,
+    name: &str,
+    details: String,
+) {
+    siv.add_layer(
+        Dialog::around(TextView::new(details))
+            .title(name)
+            .button("Ignore", move |siv| {
+                ui_tx.try_send(UICommand::Ignore(hex_id(&id))).unwrap();
+                siv.pop_layer();
+            })
+            .dismiss_button("Close")
+            .min_width(32),
+    );
+}
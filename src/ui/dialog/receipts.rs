@@ -0,0 +1,36 @@
+use cursive::traits::{Resizable, Scrollable};
+use cursive::views::{Dialog, SelectView, TextView};
+use cursive::Cursive;
+
+/// List our messages with who's seen them, and show the details of whichever
+/// one gets picked.
+pub fn show_receipts_dialog(siv: &mut Cursive, messages: Vec<(String, String)>) {
+    if messages.is_empty() {
+        siv.add_layer(
+            Dialog::text("you haven't sent anything here yet")
+                .title("receipts")
+                .dismiss_button("Close"),
+        );
+        return;
+    }
+
+    siv.add_layer(
+        Dialog::around(
+            SelectView::new()
+                .with_all(messages)
+                .on_submit(|siv, details: &String| {
+                    siv.add_layer(
+                        Dialog::around(TextView::new(details))
+                            .title("seen by")
+                            .dismiss_button("Close")
+                            .min_width(32),
+                    );
+                })
+                .scrollable()
+                .max_height(16),
+        )
+        .title("receipts")
+        .dismiss_button("Close")
+        .min_width(32),
+    );
+}
@@ -0,0 +1,259 @@
+//! Chat history on disk, encrypted with a key derived from a passphrase so
+//! it's useless to anyone who walks off with the laptop.
+//!
+//! The file is a header followed by a list of records, each sealed on its
+//! own so appending doesn't mean rewriting everything:
+//!
+//! ```text
+//! magic | salt | check record | record | record | ...
+//! ```
+//!
+//! Every record is a big-endian u32 length, a random nonce, then that many
+//! bytes of ciphertext. The check record holds a known plaintext so a wrong
+//! passphrase is caught up front instead of looking like corruption.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use directories::ProjectDirs;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ArpchatError;
+
+use super::config::CONFIG;
+
+const MAGIC: &[u8] = b"arpchat-history-1";
+const CHECK: &[u8] = b"uwu";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+
+/// What happened. Everything but messages is saved as the notice that was
+/// shown for it.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    #[default]
+    Message,
+    Join,
+    Leave,
+    Nick,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    /// Unix timestamp, in seconds.
+    pub time: i64,
+    #[serde(default)]
+    pub kind: EntryKind,
+    pub id: String,
+    #[serde(default)]
+    pub msg_id: String,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    pub username: String,
+    /// Empty for entries saved before there were rooms.
+    #[serde(default)]
+    pub room: String,
+    pub message: String,
+}
+
+pub struct History {
+    cipher: XChaCha20Poly1305,
+    file: File,
+}
+
+fn io_err(err: std::io::Error) -> ArpchatError {
+    ArpchatError::HistoryError(err.to_string())
+}
+
+fn corrupted() -> ArpchatError {
+    ArpchatError::HistoryError("the history file is corrupted".to_string())
+}
+
+impl History {
+    pub fn get_history_path() -> Option<PathBuf> {
+        let dirs = ProjectDirs::from("dev", "kognise", "arpchat")?;
+        Some(dirs.data_dir().join("history.bin"))
+    }
+
+    pub fn exists() -> bool {
+        Self::get_history_path().is_some_and(|path| path.exists())
+    }
+
+    /// Open the history store, creating it if it doesn't exist yet, and
+    /// throw away anything older than the configured retention. Returns
+    /// whatever's left in it too, oldest first.
+    pub fn open(passphrase: &str) -> Result<(Self, Vec<HistoryEntry>), ArpchatError> {
+        if passphrase.is_empty() {
+            return Err(ArpchatError::EmptyPassphrase);
+        }
+        let path = Self::get_history_path()
+            .ok_or_else(|| ArpchatError::HistoryError("no data directory".to_string()))?;
+
+        if !path.exists() {
+            let salt: [u8; SALT_SIZE] = rand::thread_rng().gen();
+            let cipher = derive_cipher(passphrase, &salt)?;
+            write_store(&path, &salt, &cipher, &[])?;
+        }
+
+        let data = fs::read(&path).map_err(io_err)?;
+        if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + SALT_SIZE {
+            return Err(corrupted());
+        }
+        let salt = &data[MAGIC.len()..MAGIC.len() + SALT_SIZE];
+        let cipher = derive_cipher(passphrase, salt)?;
+
+        let mut records = RecordIter {
+            data: &data[MAGIC.len() + SALT_SIZE..],
+        };
+        let check = records.next().ok_or_else(corrupted)?;
+        match open_record(&cipher, check) {
+            Some(check) if check == CHECK => {}
+            _ => return Err(ArpchatError::WrongPassphrase),
+        }
+
+        let entries = records
+            .by_ref()
+            .map(|record| {
+                let plaintext = open_record(&cipher, record).ok_or_else(corrupted)?;
+                serde_json::from_slice(&plaintext).map_err(|_| corrupted())
+            })
+            .collect::<Result<Vec<HistoryEntry>, ArpchatError>>()?;
+
+        // Leftovers mean we crashed partway through an append. Rewriting
+        // drops the partial record so new ones don't end up misaligned.
+        let truncated = !records.data.is_empty();
+        let kept = apply_retention(&entries);
+        if kept.len() != entries.len() || truncated {
+            write_store(&path, salt, &cipher, kept)?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(io_err)?;
+        Ok((Self { cipher, file }, kept.to_vec()))
+    }
+
+    pub fn append(&mut self, entry: &HistoryEntry) -> Result<(), ArpchatError> {
+        let plaintext = serde_json::to_vec(entry).map_err(|_| corrupted())?;
+        let record = seal_record(&self.cipher, &plaintext)?;
+        self.file.write_all(&record).map_err(io_err)
+    }
+
+    /// Overwrite the store with random bytes before deleting it, so the
+    /// ciphertext doesn't linger in free blocks. Copy-on-write filesystems
+    /// and SSD wear leveling can still keep old copies around, which is
+    /// exactly why it's encrypted in the first place.
+    ///
+    /// This works without the passphrase, so any open `History` should be
+    /// dropped first.
+    pub fn wipe() -> Result<(), ArpchatError> {
+        let path = Self::get_history_path()
+            .filter(|path| path.exists())
+            .ok_or_else(|| ArpchatError::HistoryError("there's no history to wipe".to_string()))?;
+
+        let len = fs::metadata(&path).map_err(io_err)?.len() as usize;
+        let mut noise = vec![0; len];
+        rand::thread_rng().fill(&mut noise[..]);
+
+        let mut file = OpenOptions::new().write(true).open(&path).map_err(io_err)?;
+        file.write_all(&noise).map_err(io_err)?;
+        file.sync_all().map_err(io_err)?;
+        drop(file);
+
+        fs::remove_file(&path).map_err(io_err)
+    }
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305, ArpchatError> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| ArpchatError::HistoryError(err.to_string()))?;
+    XChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|_| ArpchatError::HistoryError("bad key length".to_string()))
+}
+
+fn seal_record(cipher: &XChaCha20Poly1305, plaintext: &[u8]) -> Result<Vec<u8>, ArpchatError> {
+    let nonce: [u8; NONCE_SIZE] = rand::thread_rng().gen();
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| ArpchatError::HistoryError("couldn't encrypt".to_string()))?;
+    Ok([
+        &(ciphertext.len() as u32).to_be_bytes() as &[u8],
+        &nonce,
+        &ciphertext,
+    ]
+    .concat())
+}
+
+fn open_record(cipher: &XChaCha20Poly1305, record: (&[u8], &[u8])) -> Option<Vec<u8>> {
+    let (nonce, ciphertext) = record;
+    cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+}
+
+/// Write a whole new store next to the old one, then move it into place so
+/// a crash halfway through can't lose everything.
+fn write_store(
+    path: &PathBuf,
+    salt: &[u8],
+    cipher: &XChaCha20Poly1305,
+    entries: &[HistoryEntry],
+) -> Result<(), ArpchatError> {
+    let mut data = [MAGIC, salt, &seal_record(cipher, CHECK)?].concat();
+    for entry in entries {
+        let plaintext = serde_json::to_vec(entry).map_err(|_| corrupted())?;
+        data.extend(seal_record(cipher, &plaintext)?);
+    }
+
+    let parent = path.parent().ok_or_else(corrupted)?;
+    fs::create_dir_all(parent).map_err(io_err)?;
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path).map_err(io_err)?;
+    file.write_all(&data).map_err(io_err)?;
+    file.sync_all().map_err(io_err)?;
+    fs::rename(tmp_path, path).map_err(io_err)
+}
+
+fn apply_retention(entries: &[HistoryEntry]) -> &[HistoryEntry] {
+    let config = CONFIG.lock().unwrap();
+    let mut start = 0;
+    if let Some(days) = config.history_retention_days {
+        let cutoff = chrono::offset::Local::now().timestamp() - days as i64 * 24 * 60 * 60;
+        start = entries
+            .iter()
+            .position(|entry| entry.time >= cutoff)
+            .unwrap_or(entries.len());
+    }
+    if let Some(max) = config.history_retention_messages {
+        start = start.max(entries.len().saturating_sub(max));
+    }
+    &entries[start..]
+}
+
+/// Splits the body of the store into `(nonce, ciphertext)` pairs.
+struct RecordIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = u32::from_be_bytes(self.data.get(..4)?.try_into().ok()?) as usize;
+        let nonce = self.data.get(4..4 + NONCE_SIZE)?;
+        let ciphertext = self.data.get(4 + NONCE_SIZE..4 + NONCE_SIZE + len)?;
+        self.data = &self.data[4 + NONCE_SIZE + len..];
+        Some((nonce, ciphertext))
+    }
+}
@@ -0,0 +1,101 @@
+use pnet::util::MacAddr;
+
+use crate::error::ArpchatError;
+
+use super::config::{IgnoredPeer, CONFIG};
+
+struct Entry {
+    name: String,
+    mac: MacAddr,
+}
+
+/// Peers whose packets get dropped by the net thread before the UI ever
+/// hears about them. Mirrored to the config so it survives restarts.
+///
+/// It only goes by MAC address. Ids are just whatever's in the packet, so
+/// anyone could pick someone else's and get them ignored, or get a new one
+/// and be heard again.
+pub struct IgnoreList {
+    entries: Vec<Entry>,
+}
+
+impl IgnoreList {
+    pub fn load() -> Self {
+        let entries = CONFIG
+            .lock()
+            .unwrap()
+            .ignored
+            .iter()
+            .filter_map(|peer| {
+                Some(Entry {
+                    name: peer.name.clone(),
+                    mac: peer.mac.parse().ok()?,
+                })
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Write the ignore list back to the config.
+    pub fn save(&self) -> Result<(), ArpchatError> {
+        let mut config = CONFIG.lock().unwrap();
+        config.ignored = self
+            .entries
+            .iter()
+            .map(|entry| IgnoredPeer {
+                name: entry.name.clone(),
+                mac: entry.mac.to_string(),
+            })
+            .collect();
+        config.save()
+    }
+
+    pub fn add(&mut self, name: String, mac: MacAddr) {
+        self.entries.push(Entry { name, mac });
+    }
+
+    /// Remove every entry matching the name or MAC address given. Returns
+    /// the names of the removed entries.
+    pub fn remove(&mut self, target: &str) -> Vec<String> {
+        let mac: Option<MacAddr> = target.parse().ok();
+
+        let mut removed = vec![];
+        self.entries.retain(|entry| {
+            let matches = entry.name == target || mac == Some(entry.mac);
+            if matches {
+                removed.push(entry.name.clone());
+            }
+            !matches
+        });
+        removed
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect()
+    }
+
+    pub fn matches(&self, src_mac: MacAddr) -> bool {
+        self.entries.iter().any(|entry| entry.mac == src_mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_mac_counts() {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut ignored = IgnoreList { entries: vec![] };
+        ignored.add("mallory".to_string(), mac);
+
+        assert!(ignored.matches(mac));
+        assert!(!ignored.matches(MacAddr::new(2, 0, 0, 0, 0, 2)));
+
+        assert_eq!(ignored.remove("02:00:00:00:00:01"), ["mallory"]);
+        assert!(!ignored.matches(mac));
+    }
+}
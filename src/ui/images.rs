@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{stdout, Cursor, Write};
+use std::iter;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cursive::theme::{Color, ColorStyle};
+use cursive::utils::markup::StyledString;
+use cursive::Vec2;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{ImageFormat, RgbImage};
+
+use crate::net::Id;
+
+use super::config::CONFIG;
+
+/// Biggest an image can be on either side, in pixels. Anything bigger gets
+/// shrunk before it's sent, and anything that comes in bigger is ignored.
+const MAX_IMAGE_DIM: u32 = 256;
+
+/// Most an image can take up once it's encoded. Packets can't be much more
+/// than 60KiB, and every part of one is a frame on the wire.
+const MAX_IMAGE_SIZE: usize = 24 * 1024;
+
+const JPEG_QUALITY: u8 = 75;
+
+/// How much room a thumbnail gets in the chat, in characters.
+const THUMBNAIL_COLS: u32 = 40;
+const THUMBNAIL_ROWS: u32 = 12;
+
+/// How many images to keep around to look at full size.
+const MAX_IMAGES: usize = 64;
+
+/// Load an image from disk and shrink it down into a JPEG small enough to
+/// send.
+pub fn prepare(path: &Path) -> Result<Vec<u8>, String> {
+    let image = image::open(path).map_err(|err| err.to_string())?;
+    let mut dim = MAX_IMAGE_DIM;
+    loop {
+        let small = match image.width() > dim || image.height() > dim {
+            true => image.thumbnail(dim, dim),
+            false => image.clone(),
+        };
+        let mut data = vec![];
+        JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)
+            .encode_image(&small.to_rgb8())
+            .map_err(|err| err.to_string())?;
+        if data.len() <= MAX_IMAGE_SIZE || dim < 32 {
+            return Ok(data);
+        }
+        dim = dim * 3 / 4;
+    }
+}
+
+/// Decode an image someone sent. They're only ever JPEGs no bigger than
+/// we'd send, so anything else is refused before it's even decoded.
+pub fn decode(data: &[u8]) -> Option<RgbImage> {
+    let mut reader = Reader::with_format(Cursor::new(data), ImageFormat::Jpeg);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIM);
+    limits.max_image_height = Some(MAX_IMAGE_DIM);
+    limits.max_alloc = Some(16 * 1024 * 1024);
+    reader.limits(limits);
+    Some(reader.decode().ok()?.to_rgb8())
+}
+
+/// Shrink or stretch an image to fit in `cols` by `rows` characters, at two
+/// pixels to a character.
+pub fn fit(image: &RgbImage, cols: usize, rows: usize) -> RgbImage {
+    let (width, height) = (image.width() as f64, image.height() as f64);
+    let scale = (cols as f64 / width).min(rows as f64 * 2.0 / height);
+    let width = ((width * scale) as u32).max(1);
+    let height = ((height * scale) as u32).max(1);
+    image::imageops::resize(image, width, height, FilterType::Triangle)
+}
+
+fn rgb(image: &RgbImage, x: u32, y: u32) -> Color {
+    let [r, g, b] = image.get_pixel(x, y).0;
+    Color::Rgb(r, g, b)
+}
+
+/// The colours of the half block for each character: the top pixel and
+/// the bottom one, if there is one.
+pub fn half_blocks(image: &RgbImage) -> Vec<Vec<ColorStyle>> {
+    (0..image.height())
+        .step_by(2)
+        .map(|y| {
+            (0..image.width())
+                .map(|x| {
+                    let top = rgb(image, x, y);
+                    match y + 1 < image.height() {
+                        true => ColorStyle::new(top, rgb(image, x, y + 1)),
+                        false => ColorStyle::front(top),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn thumbnail(image: &RgbImage) -> StyledString {
+    let small = fit(
+        image,
+        THUMBNAIL_COLS.min(image.width()) as usize,
+        THUMBNAIL_ROWS.min(image.height() / 2).max(1) as usize,
+    );
+    let mut thumbnail = StyledString::new();
+    for (y, row) in half_blocks(&small).into_iter().enumerate() {
+        if y > 0 {
+            thumbnail.append_plain("\n");
+        }
+        for style in row {
+            thumbnail.append_styled("▀", style);
+        }
+    }
+    thumbnail
+}
+
+/// Whether the terminal can show sixel graphics. There's no asking it
+/// without getting in cursive's way, so it's set in the config or guessed
+/// from the terminal's name.
+pub fn sixel_supported() -> bool {
+    if let Some(sixel) = CONFIG.lock().unwrap().sixel {
+        return sixel;
+    }
+    let term = env::var("TERM").unwrap_or_default();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    term.contains("sixel")
+        || term.starts_with("foot")
+        || term.starts_with("mlterm")
+        || program == "WezTerm"
+}
+
+/// An image as sixel data, with colours cut down to a 6x6x6 cube, which is
+/// plenty for a picture in a chat.
+pub fn sixel(image: &RgbImage) -> String {
+    let level = |c: u8| (c as usize * 5 + 127) / 255;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let colors: Vec<usize> = image
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.0;
+            level(r) * 36 + level(g) * 6 + level(b)
+        })
+        .collect();
+
+    let mut out = format!("\x1bPq\"1;1;{width};{height}");
+    for color in 0..216 {
+        let (r, g, b) = (color / 36, color / 6 % 6, color % 6);
+        out += &format!("#{color};2;{};{};{}", r * 20, g * 20, b * 20);
+    }
+    for band in (0..height).step_by(6) {
+        let rows = band..(band + 6).min(height);
+        let mut used = [false; 216];
+        for y in rows.clone() {
+            for &color in &colors[y * width..(y + 1) * width] {
+                used[color] = true;
+            }
+        }
+        for color in (0..216).filter(|color| used[*color]) {
+            out += &format!("#{color}");
+            let sixel_at = |x: usize| {
+                let bits = rows
+                    .clone()
+                    .filter(|y| colors[y * width + x] == color)
+                    .fold(0, |bits, y| bits | 1 << (y - band));
+                (63 + bits) as u8 as char
+            };
+            // Run-length encoded, since most of a band is empty for most
+            // colours.
+            let mut x = 0;
+            while x < width {
+                let sixel = sixel_at(x);
+                let mut count = 1;
+                while x + count < width && sixel_at(x + count) == sixel {
+                    count += 1;
+                }
+                match count {
+                    1..=3 => out.extend(iter::repeat_n(sixel, count)),
+                    _ => out += &format!("!{count}{sixel}"),
+                }
+                x += count;
+            }
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out + "\x1b\\"
+}
+
+/// A sixel image to draw once cursive's done drawing, and where.
+static PENDING_SIXEL: Mutex<Option<(Vec2, Arc<String>)>> = Mutex::new(None);
+
+/// Draw a sixel image at a spot on the screen next time there's a chance.
+/// Anything cursive draws over it wipes it out, so this has to be done again
+/// after every redraw.
+pub fn queue_sixel(pos: Vec2, sixel: Arc<String>) {
+    *PENDING_SIXEL.lock().unwrap() = Some((pos, sixel));
+}
+
+pub fn draw_pending_sixel() {
+    let Some((pos, sixel)) = PENDING_SIXEL.lock().unwrap().take() else {
+        return;
+    };
+    let mut stdout = stdout();
+    // Save the cursor, draw, and put the cursor back.
+    let _ = write!(stdout, "\x1b7\x1b[{};{}H{sixel}\x1b8", pos.y + 1, pos.x + 1);
+    let _ = stdout.flush();
+}
+
+struct Image {
+    name: String,
+    full: RgbImage,
+    thumbnail: StyledString,
+}
+
+/// Images people have sent, by message id, so they can be looked at full
+/// size.
+pub struct Images {
+    images: HashMap<Id, Image>,
+    order: VecDeque<Id>,
+}
+
+impl Images {
+    pub fn new() -> Self {
+        Self {
+            images: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether it could be decoded.
+    pub fn insert(&mut self, msg_id: Id, name: String, data: &[u8]) -> bool {
+        let Some(full) = decode(data) else {
+            return false;
+        };
+        if !self.images.contains_key(&msg_id) {
+            self.order.push_back(msg_id);
+        }
+        let thumbnail = thumbnail(&full);
+        self.images.insert(
+            msg_id,
+            Image {
+                name,
+                full,
+                thumbnail,
+            },
+        );
+        while self.order.len() > MAX_IMAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.images.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, msg_id: &Id) {
+        self.images.remove(msg_id);
+        self.order.retain(|other| other != msg_id);
+    }
+
+    pub fn contains(&self, msg_id: &Id) -> bool {
+        self.images.contains_key(msg_id)
+    }
+
+    pub fn thumbnail(&self, msg_id: &Id) -> Option<StyledString> {
+        Some(self.images.get(msg_id)?.thumbnail.clone())
+    }
+
+    /// The image's name and the image itself.
+    pub fn full(&self, msg_id: &Id) -> Option<(&str, &RgbImage)> {
+        let image = self.images.get(msg_id)?;
+        Some((&image.name, &image.full))
+    }
+}
@@ -1,75 +1,200 @@
 use crossbeam_channel::Sender;
-use cursive::event::Key;
+use cursive::event::{Event, EventResult, EventTrigger, Key, MouseEvent};
 use cursive::traits::{Nameable, Resizable, Scrollable};
 use cursive::view::ScrollStrategy;
-use cursive::views::{EditView, LinearLayout, Panel};
+use cursive::views::{
+    EditView, HideableView, LinearLayout, NamedView, OnEventView, Panel, SelectView, TextView,
+};
 use cursive::Cursive;
 
+use crate::net::Id;
+
+use super::buffers::buffer_view;
+use super::commands::{complete, Completion};
+use super::config::CONFIG;
 use super::dialog::ether_type::show_ether_type_dialog;
+use super::dialog::interface::show_iface_dialog;
 use super::dialog::username::show_username_dialog;
-use super::util::UICommand;
+use super::util::{hex_id, UICommand};
+
+pub type InputPanel = Panel<OnEventView<NamedView<EditView>>>;
 
 pub fn init_app(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
+    let rooms = CONFIG.lock().unwrap().joined_rooms();
+    let mut buffer_views = LinearLayout::vertical();
+    for (i, room) in rooms.iter().enumerate() {
+        buffer_views.add_child(buffer_view(room, i == 0));
+    }
+
     siv.menubar()
         .add_leaf("set username", {
             let ui_tx = ui_tx.clone();
             move |siv| show_username_dialog(siv, ui_tx.clone(), false)
         })
+        .add_leaf("switch interface", {
+            let ui_tx = ui_tx.clone();
+            move |siv| show_iface_dialog(siv, ui_tx.clone(), false)
+        })
         .add_leaf("switch protocol", {
             let ui_tx = ui_tx.clone();
             move |siv| show_ether_type_dialog(siv, ui_tx.clone())
         })
         .add_leaf("quit", |siv| siv.quit());
     siv.set_autohide_menu(false);
+    ui_tx.try_send(UICommand::ShowHistory).unwrap();
     siv.add_global_callback(Key::Esc, |siv| siv.select_menubar());
+    let find_keys: [(char, fn() -> UICommand); 3] = [
+        ('f', || UICommand::ShowFind),
+        ('n', || UICommand::FindStep(true)),
+        ('p', || UICommand::FindStep(false)),
+    ];
+    for (key, cmd) in find_keys {
+        let ui_tx = ui_tx.clone();
+        siv.add_global_callback(Event::CtrlChar(key), move |_| {
+            ui_tx.try_send(cmd()).unwrap();
+        });
+    }
+    siv.set_on_pre_event_inner(
+        EventTrigger::from_fn(|event| {
+            matches!(
+                event,
+                Event::Char(_)
+                    | Event::CtrlChar(_)
+                    | Event::AltChar(_)
+                    | Event::Key(_)
+                    | Event::Mouse {
+                        event: MouseEvent::Press(_),
+                        ..
+                    }
+            )
+        }),
+        {
+            let ui_tx = ui_tx.clone();
+            move |_| {
+                ui_tx.try_send(UICommand::Active).unwrap();
+                None
+            }
+        },
+    );
 
     siv.add_fullscreen_layer(
         LinearLayout::horizontal()
             .child(
                 LinearLayout::vertical()
                     .child(
-                        Panel::new(
-                            LinearLayout::vertical()
-                                .with_name("chat_inner")
-                                .full_height()
-                                .full_width()
-                                .scrollable()
-                                .scroll_strategy(ScrollStrategy::StickToBottom),
+                        HideableView::new(
+                            Panel::new(TextView::new("").with_name("pins").scrollable())
+                                .title("pinned")
+                                .max_height(8),
                         )
-                        .title("arpchat")
-                        .with_name("chat_panel")
-                        .full_height()
-                        .full_width(),
+                        .hidden()
+                        .with_name("pins_panel"),
+                    )
+                    .child(
+                        Panel::new(buffer_views.with_name("buffer_views"))
+                            .title("arpchat")
+                            .with_name("chat_panel")
+                            .full_height()
+                            .full_width(),
                     )
+                    .child(TextView::new("").with_name("typing"))
                     .child(
                         Panel::new(
-                            EditView::new()
-                                .on_submit(move |siv, msg| {
-                                    siv.call_on_name("input", |input: &mut EditView| {
-                                        input.set_content("");
-                                    });
-                                    ui_tx
-                                        .try_send(UICommand::SendMessage(msg.to_string()))
-                                        .unwrap();
-                                })
-                                .with_name("input"),
+                            OnEventView::new(
+                                EditView::new()
+                                    .on_edit({
+                                        let ui_tx = ui_tx.clone();
+                                        move |_, msg, _| {
+                                            // Commands aren't anyone's business.
+                                            if !msg.is_empty() && !msg.starts_with('/') {
+                                                ui_tx.try_send(UICommand::Typed).unwrap();
+                                            }
+                                        }
+                                    })
+                                    .on_submit({
+                                        let ui_tx = ui_tx.clone();
+                                        move |siv, msg| {
+                                            siv.call_on_name("input", |input: &mut EditView| {
+                                                input.set_content("");
+                                            });
+                                            ui_tx
+                                                .try_send(UICommand::SendMessage(msg.to_string()))
+                                                .unwrap();
+                                        }
+                                    })
+                                    .with_name("input"),
+                            )
+                            .on_pre_event_inner(Key::Tab, {
+                                let ui_tx = ui_tx.clone();
+                                move |input: &mut NamedView<EditView>, _| {
+                                    let mut input = input.get_mut();
+                                    match complete(&input.get_content()) {
+                                        // Let tab move focus like it usually does.
+                                        Completion::Nothing => None,
+                                        Completion::Complete(completed) => {
+                                            Some(EventResult::Consumed(Some(
+                                                input.set_content(completed),
+                                            )))
+                                        }
+                                        Completion::Ambiguous(names) => {
+                                            let names: Vec<String> = names
+                                                .iter()
+                                                .map(|name| format!("/{name}"))
+                                                .collect();
+                                            ui_tx
+                                                .try_send(UICommand::Notice(names.join(" ")))
+                                                .unwrap();
+                                            Some(EventResult::Consumed(None))
+                                        }
+                                    }
+                                }
+                            })
+                            .on_pre_event(Key::Up, {
+                                let ui_tx = ui_tx.clone();
+                                move |_| ui_tx.try_send(UICommand::EditLast).unwrap()
+                            }),
                         )
+                        .with_name("input_panel")
                         .full_width(),
                     )
                     .full_width(),
             )
             .child(
-                Panel::new(
-                    LinearLayout::vertical()
-                        .with_name("presences")
-                        .full_height()
-                        .full_width()
-                        .scrollable()
-                        .scroll_strategy(ScrollStrategy::StickToBottom),
-                )
-                .title("online users")
-                .full_height()
-                .fixed_width(32),
+                LinearLayout::vertical()
+                    .child(
+                        Panel::new(
+                            SelectView::<String>::new()
+                                .with_all_str(rooms)
+                                .on_select({
+                                    let ui_tx = ui_tx.clone();
+                                    move |_, key: &String| {
+                                        ui_tx
+                                            .try_send(UICommand::SwitchBuffer(key.clone()))
+                                            .unwrap();
+                                    }
+                                })
+                                .with_name("buffers")
+                                .scrollable()
+                                .max_height(8),
+                        )
+                        .title("chats"),
+                    )
+                    .child(
+                        Panel::new(
+                            SelectView::<Id>::new()
+                                .on_submit(move |_, id: &Id| {
+                                    ui_tx.try_send(UICommand::Whois(hex_id(id))).unwrap();
+                                })
+                                .with_name("presences")
+                                .full_height()
+                                .full_width()
+                                .scrollable()
+                                .scroll_strategy(ScrollStrategy::StickToBottom),
+                        )
+                        .title("online users")
+                        .full_height(),
+                    )
+                    .fixed_width(32),
             ),
     );
 }
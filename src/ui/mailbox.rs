@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pnet::util::MacAddr;
+use rand::Rng;
+
+use crate::net::{backlog_fits, BacklogEntry, Id, Packet};
+use crate::ringbuffer::Ringbuffer;
+
+use super::net_thread::Net;
+use super::util::parse_hex_id;
+use super::UICommand;
+
+/// How long to hold messages for someone, and to wait for them to come back.
+const MAIL_EXPIRY: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Most messages to hold for any one person.
+const MAX_HELD_PER_PEER: usize = 32;
+
+/// Most people to wait for at once.
+const MAX_ABSENT: usize = 64;
+
+/// Longest to wait before handing over mail to someone who's back, so
+/// whoever else is holding the same mail doesn't hand it over at the same
+/// time.
+const MAIL_JITTER_MS: u64 = 500;
+
+struct Absent {
+    username: String,
+    /// Where they were talking from, so someone else can't just take their
+    /// id and their mail with it.
+    mac: Option<MacAddr>,
+    since: Instant,
+}
+
+struct Held {
+    entry: BacklogEntry,
+    since: Instant,
+}
+
+/// Messages for people who've gone offline, held until they come back.
+///
+/// There's no key to encrypt anything to, so messages are held as they were
+/// sent, which was in the clear anyway.
+pub struct Mailbox {
+    absent: HashMap<Id, Absent>,
+    held: HashMap<Id, Vec<Held>>,
+    /// When to hand over what we're holding for people who just came back,
+    /// and where to.
+    due: HashMap<Id, (Instant, MacAddr)>,
+}
+
+impl Mailbox {
+    pub fn new() -> Self {
+        Self {
+            absent: HashMap::new(),
+            held: HashMap::new(),
+            due: HashMap::new(),
+        }
+    }
+
+    /// Someone's gone, so start holding their messages.
+    pub fn away(&mut self, id: Id, username: String, mac: Option<MacAddr>) {
+        if self.absent.len() >= MAX_ABSENT && !self.absent.contains_key(&id) {
+            let longest = self
+                .absent
+                .iter()
+                .min_by_key(|(_, absent)| absent.since)
+                .map(|(id, _)| *id);
+            if let Some(longest) = longest {
+                self.absent.remove(&longest);
+            }
+        }
+        let absent = Absent {
+            username,
+            mac,
+            since: Instant::now(),
+        };
+        self.absent.insert(id, absent);
+    }
+
+    /// Someone's back, from `mac`. Anything we're holding for them is
+    /// handed over in a moment, but only if they're back where they left
+    /// from. Otherwise it's probably someone else using their id, so we
+    /// keep waiting for the real one. Returns whether anything's on its way.
+    pub fn back(&mut self, id: &Id, mac: MacAddr) -> bool {
+        match self.absent.get(id) {
+            Some(absent) if absent.mac == Some(mac) => {
+                self.absent.remove(id);
+                if !self.holding(id) {
+                    return false;
+                }
+                let jitter = rand::thread_rng().gen_range(0..=MAIL_JITTER_MS);
+                let due = Instant::now() + Duration::from_millis(jitter);
+                self.due.entry(*id).or_insert((due, mac));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Hand over whatever's due to people who came back, along with who
+    /// it's from. It goes where they came back from, even if someone else
+    /// has claimed their id since.
+    pub fn deliver(&mut self, net: &mut Net, presence: impl Fn() -> Packet) {
+        let due: Vec<(Id, MacAddr)> = self
+            .due
+            .iter()
+            .filter(|(_, (due, _))| Instant::now() >= *due)
+            .map(|(id, (_, mac))| (*id, *mac))
+            .collect();
+        for (id, mac) in due {
+            self.due.remove(&id);
+            let entries = self.take(&id);
+            if !entries.is_empty() {
+                net.send_to(presence(), mac);
+                net.send_to(Packet::Mail(net.local_id, id, entries), mac);
+            }
+            // More than fit in one go, so the rest goes next time round.
+            if self.holding(&id) {
+                self.due.insert(id, (Instant::now(), mac));
+            }
+        }
+    }
+
+    /// Whether there's anything held for someone.
+    pub fn holding(&self, id: &Id) -> bool {
+        self.held.get(id).is_some_and(|held| !held.is_empty())
+    }
+
+    pub fn username(&self, id: &Id) -> Option<&str> {
+        Some(self.absent.get(id)?.username.as_str())
+    }
+
+    /// Find someone who's away by hex id or username.
+    pub fn find(&self, target: &str) -> Option<Id> {
+        if let Some(id) = parse_hex_id(target) {
+            return self.absent.contains_key(&id).then_some(id);
+        }
+        self.absent
+            .iter()
+            .find(|(_, absent)| absent.username == target)
+            .map(|(id, _)| *id)
+    }
+
+    /// Hold a message for whoever's away and should get it: the recipient
+    /// if it's direct, or anyone it mentions by name if it isn't.
+    pub fn hold(&mut self, entry: &BacklogEntry, to: Option<Id>) {
+        let recipients: Vec<Id> = match to {
+            Some(to) if self.absent.contains_key(&to) => vec![to],
+            Some(_) => vec![],
+            None => self
+                .absent
+                .iter()
+                .filter(|(id, absent)| {
+                    **id != entry.author
+                        && !absent.username.is_empty()
+                        && entry.msg.contains(&absent.username)
+                })
+                .map(|(id, _)| *id)
+                .collect(),
+        };
+        for id in recipients {
+            let held = self.held.entry(id).or_default();
+            if held.iter().any(|held| held.entry.msg_id == entry.msg_id) {
+                continue;
+            }
+            if held.len() >= MAX_HELD_PER_PEER {
+                held.remove(0);
+            }
+            held.push(Held {
+                entry: entry.clone(),
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Hand over as much of what's held for someone as fits in one packet,
+    /// oldest first. The rest stays held for the next one.
+    pub fn take(&mut self, id: &Id) -> Vec<BacklogEntry> {
+        let Some(held) = self.held.get_mut(id) else {
+            return vec![];
+        };
+        // Anything too big to fit on its own never will, so it goes anyway
+        // rather than holding up everything behind it.
+        let count = backlog_fits(held.iter().map(|held| &held.entry)).max(1);
+        let taken = held
+            .drain(..count.min(held.len()))
+            .map(|held| held.entry)
+            .collect();
+        if held.is_empty() {
+            self.held.remove(id);
+        }
+        taken
+    }
+
+    /// Someone's got these, maybe from someone else, so stop holding them.
+    pub fn delivered(&mut self, id: &Id, msg_ids: &[Id]) {
+        if let Some(held) = self.held.get_mut(id) {
+            held.retain(|held| !msg_ids.contains(&held.entry.msg_id));
+        }
+    }
+
+    pub fn expire(&mut self) {
+        self.absent
+            .retain(|_, absent| absent.since.elapsed() < MAIL_EXPIRY);
+        for held in self.held.values_mut() {
+            held.retain(|held| held.since.elapsed() < MAIL_EXPIRY);
+        }
+        self.held.retain(|_, held| !held.is_empty());
+    }
+}
+
+/// Mail someone handed over to us. Every mailbox hears that we've got it,
+/// so nobody hands it over again. Returns whatever we hadn't already seen.
+pub fn open_mail(
+    net: &mut Net,
+    from: Id,
+    entries: Vec<BacklogEntry>,
+    rooms: &[String],
+    seen_messages: &mut Ringbuffer<Id>,
+) -> Vec<BacklogEntry> {
+    let msg_ids: Vec<Id> = entries.iter().map(|entry| entry.msg_id).collect();
+    net.send(Packet::MailAck(net.local_id, msg_ids));
+
+    let entries: Vec<BacklogEntry> = entries
+        .into_iter()
+        .filter(|entry| {
+            !seen_messages.contains(&entry.msg_id)
+                && (entry.room.is_empty() || rooms.contains(&entry.room))
+        })
+        .collect();
+    for entry in &entries {
+        seen_messages.push(entry.msg_id);
+    }
+    if !entries.is_empty() {
+        net.tx.try_send(UICommand::AlertUser).unwrap();
+        net.tx
+            .try_send(UICommand::Mail(net.name_of(&from), entries.clone()))
+            .unwrap();
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u8) -> BacklogEntry {
+        BacklogEntry {
+            msg_id: [n; 8],
+            author: [0; 8],
+            time: 0,
+            reply_to: None,
+            username: "someone".to_string(),
+            room: String::new(),
+            // Hex doesn't squash down much, so this stays big.
+            msg: (0..1000u32)
+                .map(|i| format!("{:x}", i * 7 + n as u32))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn take_leaves_what_doesnt_fit_for_later() {
+        let mut mailbox = Mailbox::new();
+        mailbox.away([1; 8], "them".to_string(), None);
+        for n in 0..MAX_HELD_PER_PEER as u8 {
+            mailbox.hold(&entry(n), Some([1; 8]));
+        }
+
+        let mut taken = mailbox.take(&[1; 8]);
+        assert!(taken.len() < MAX_HELD_PER_PEER);
+        assert!(mailbox.holding(&[1; 8]));
+        while mailbox.holding(&[1; 8]) {
+            taken.extend(mailbox.take(&[1; 8]));
+        }
+
+        let ids: Vec<Id> = taken.iter().map(|entry| entry.msg_id).collect();
+        let expected: Vec<Id> = (0..MAX_HELD_PER_PEER as u8).map(|n| [n; 8]).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn mail_only_goes_back_to_the_same_mac() {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut mailbox = Mailbox::new();
+        mailbox.away([1; 8], "them".to_string(), Some(mac));
+        mailbox.hold(&entry(0), Some([1; 8]));
+
+        assert!(!mailbox.back(&[1; 8], MacAddr::new(2, 0, 0, 0, 0, 2)));
+        assert!(mailbox.back(&[1; 8], mac));
+    }
+
+    #[test]
+    fn mail_is_delivered_where_they_came_back() {
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let mut net = Net::new([9; 8], &tx, &macs, &online);
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut mailbox = Mailbox::new();
+        mailbox.away([1; 8], "them".to_string(), Some(mac));
+        mailbox.hold(&entry(0), Some([1; 8]));
+
+        mailbox.back(&[1; 8], mac);
+        std::thread::sleep(Duration::from_millis(MAIL_JITTER_MS + 10));
+        mailbox.deliver(&mut net, || Packet::PresenceReq);
+        let sent = net.sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(_, to)| *to == mac));
+        assert!(matches!(sent[1].0, Packet::Mail(_, id, _) if id == [1; 8]));
+        assert!(!mailbox.holding(&[1; 8]));
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{DateTime, Local};
+use cursive::backends::crossterm::crossterm::style::Stylize;
+
+use crate::net::Id;
+
+use super::search::highlight;
+use super::util::{format_message, Delivery, ACTION_PREFIX};
+
+/// How many messages to remember, across every buffer.
+const MAX_MESSAGES: usize = 1000;
+
+/// Longest reaction anyone can send, in bytes.
+const MAX_REACTION_LEN: usize = 32;
+
+const SHORTCODES: &[(&str, &str)] = &[
+    (":+1:", "👍"),
+    (":thumbsup:", "👍"),
+    (":-1:", "👎"),
+    (":thumbsdown:", "👎"),
+    (":heart:", "❤"),
+    (":joy:", "😂"),
+    (":tada:", "🎉"),
+    (":eyes:", "👀"),
+    (":fire:", "🔥"),
+    (":ok:", "👌"),
+    (":pray:", "🙏"),
+    (":wave:", "👋"),
+];
+
+/// Turn whatever someone typed after `/react` into a reaction, expanding
+/// shortcodes like `:+1:`, or `None` if it's too long to be one.
+pub fn parse_reaction(reaction: &str) -> Option<String> {
+    let reaction = reaction.trim();
+    let reaction = SHORTCODES
+        .iter()
+        .find(|(code, _)| *code == reaction)
+        .map_or(reaction, |(_, emoji)| emoji);
+    if reaction.is_empty()
+        || reaction.len() > MAX_REACTION_LEN
+        || reaction
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return None;
+    }
+    Some(reaction.to_string())
+}
+
+/// How far up a thread to look for where it started, in case someone makes
+/// a loop.
+const MAX_DEPTH: usize = 32;
+
+pub struct Record {
+    pub author: Id,
+    pub username: String,
+    pub buffer: String,
+    pub time: DateTime<Local>,
+    pub msg: String,
+    pub reply_to: Option<Id>,
+    pub edited: bool,
+    pub deleted: bool,
+    /// Each reaction and who reacted with it, by id and name.
+    pub reactions: Vec<(String, Vec<(Id, String)>)>,
+    /// Whether it came from someone else's backlog rather than live.
+    pub backfilled: bool,
+}
+
+impl Record {
+    /// The line shown in the chat for this message, without any quote.
+    pub fn line(&self, delivery: Delivery) -> String {
+        self.line_with(delivery, &self.msg)
+    }
+
+    /// The line shown in the chat, with `msg` in place of the message.
+    fn line_with(&self, delivery: Delivery, msg: &str) -> String {
+        let msg = if self.deleted {
+            "message deleted".dark_grey().to_string()
+        } else if self.edited {
+            format!("{msg} {}", "(edited)".dark_grey())
+        } else {
+            msg.to_string()
+        };
+        let msg = match self.backfilled {
+            true => format!("{msg} {}", "(backfilled)".dark_grey()),
+            false => msg,
+        };
+        format_message(&self.time, &self.author, &self.username, &msg, delivery)
+    }
+
+    pub fn has_reacted(&self, id: &Id, reaction: &str) -> bool {
+        self.reactions
+            .iter()
+            .any(|(other, who)| other == reaction && who.iter().any(|(other, _)| other == id))
+    }
+
+    pub fn react(&mut self, id: Id, name: String, reaction: String, is_added: bool) {
+        let index = self
+            .reactions
+            .iter()
+            .position(|(other, _)| *other == reaction);
+        match (index, is_added) {
+            (Some(index), true) => {
+                let who = &mut self.reactions[index].1;
+                if !who.iter().any(|(other, _)| *other == id) {
+                    who.push((id, name));
+                }
+            }
+            (None, true) => self.reactions.push((reaction, vec![(id, name)])),
+            (Some(index), false) => {
+                self.reactions[index].1.retain(|(other, _)| *other != id);
+                if self.reactions[index].1.is_empty() {
+                    self.reactions.remove(index);
+                }
+            }
+            (None, false) => {}
+        }
+    }
+
+    /// Who reacted with what, one reaction per line.
+    pub fn reaction_details(&self) -> String {
+        self.reactions
+            .iter()
+            .map(|(reaction, who)| {
+                let names: Vec<&str> = who.iter().map(|(_, name)| name.as_str()).collect();
+                format!("{reaction} {}", names.join(", "))
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// The start of a message, squashed onto one line.
+pub fn snippet(msg: &str, len: usize) -> String {
+    let flat = msg.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mut snippet: String = flat.chars().take(len).collect();
+    if snippet.len() < flat.len() {
+        snippet += "...";
+    }
+    snippet
+}
+
+/// Recent messages by id, so they can be quoted and replied to.
+pub struct Messages {
+    records: HashMap<Id, Record>,
+    order: VecDeque<Id>,
+    /// What's being searched for, lowercased, where, and the match we're
+    /// on.
+    highlight: Vec<char>,
+    highlight_buffer: String,
+    current_match: Option<Id>,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+            order: VecDeque::new(),
+            highlight: vec![],
+            highlight_buffer: String::new(),
+            current_match: None,
+        }
+    }
+
+    /// Pick out `text` wherever it's in a message in a buffer, or stop if
+    /// it's empty.
+    pub fn set_highlight(&mut self, buffer: &str, text: &[char], current: Option<Id>) {
+        self.highlight = text.to_vec();
+        self.highlight_buffer = buffer.to_string();
+        self.current_match = current;
+    }
+
+    /// Add a message we haven't seen before. Returns false if the id's
+    /// already taken by someone else, since otherwise anyone could take over
+    /// a message just by sending their own with the same id.
+    ///
+    /// If it's the same author, like when our own echo comes back, what we
+    /// have stays as it is, edits and reactions and all.
+    pub fn insert(&mut self, msg_id: Id, record: Record) -> bool {
+        if let Some(existing) = self.records.get(&msg_id) {
+            return existing.author == record.author;
+        }
+        self.records.insert(msg_id, record);
+        self.order.push_back(msg_id);
+        if self.order.len() > MAX_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.records.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn get(&self, msg_id: &Id) -> Option<&Record> {
+        self.records.get(msg_id)
+    }
+
+    pub fn get_mut(&mut self, msg_id: &Id) -> Option<&mut Record> {
+        self.records.get_mut(msg_id)
+    }
+
+    /// Everything shown in the chat for a message, quote and reactions and
+    /// all, with `suffix` after the message itself.
+    pub fn render(&self, msg_id: &Id, delivery: Delivery, suffix: &str) -> Option<String> {
+        let record = self.get(msg_id)?;
+        if record.deleted {
+            return Some(record.line(delivery));
+        }
+
+        let line = match self.highlight.is_empty() || record.buffer != self.highlight_buffer {
+            true => record.line(delivery),
+            false => {
+                // The prefix has to stay as it is to be recognized.
+                let (prefix, msg) = match record.msg.strip_prefix(ACTION_PREFIX) {
+                    Some(action) => (ACTION_PREFIX, action),
+                    None => ("", record.msg.as_str()),
+                };
+                let current = self.current_match == Some(*msg_id);
+                let msg = prefix.to_string() + &highlight(msg, &self.highlight, current);
+                record.line_with(delivery, &msg)
+            }
+        };
+        let mut print = line + suffix;
+        if let Some(reply_to) = &record.reply_to {
+            print = self.quote(reply_to) + "\n" + &print;
+        }
+        if !record.reactions.is_empty() {
+            let reactions: Vec<String> = record
+                .reactions
+                .iter()
+                .map(|(reaction, who)| format!("{reaction} {}", who.len()))
+                .collect();
+            print += &format!("\n  {}", reactions.join("  "));
+        }
+        Some(print)
+    }
+
+    /// The most recent messages in a buffer, newest first.
+    pub fn recent(&self, buffer: &str, count: usize) -> Vec<(Id, &Record)> {
+        self.order
+            .iter()
+            .rev()
+            .filter_map(|msg_id| Some((*msg_id, self.records.get(msg_id)?)))
+            .filter(|(_, record)| record.buffer == buffer)
+            .take(count)
+            .collect()
+    }
+
+    /// The most recent messages in any of these rooms that haven't been
+    /// deleted, oldest first.
+    pub fn backlog(&self, rooms: &[String], count: usize) -> Vec<(Id, &Record)> {
+        let mut backlog: Vec<(Id, &Record)> = self
+            .order
+            .iter()
+            .rev()
+            .filter_map(|msg_id| Some((*msg_id, self.records.get(msg_id)?)))
+            .filter(|(_, record)| !record.deleted && rooms.contains(&record.buffer))
+            .take(count)
+            .collect();
+        backlog.reverse();
+        backlog
+    }
+
+    /// The line shown above a reply.
+    pub fn quote(&self, reply_to: &Id) -> String {
+        match self.get(reply_to) {
+            Some(parent) if parent.deleted => "  ┌ a deleted message".to_string(),
+            Some(parent) => format!("  ┌ {}: {}", parent.username, snippet(&parent.msg, 60)),
+            None => "  ┌ a message you don't have".to_string(),
+        }
+        .dark_grey()
+        .to_string()
+    }
+
+    /// Every message in the thread a message is part of, in order, with how
+    /// deep in the thread each one is.
+    pub fn thread(&self, msg_id: &Id) -> Vec<(usize, &Record)> {
+        let mut root = *msg_id;
+        for _ in 0..MAX_DEPTH {
+            match self.get(&root).and_then(|record| record.reply_to) {
+                Some(parent) if self.records.contains_key(&parent) => root = parent,
+                _ => break,
+            }
+        }
+
+        let mut thread = vec![];
+        let mut visited = HashSet::new();
+        let mut stack = vec![(0, root)];
+        while let Some((depth, id)) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let Some(record) = self.get(&id) else {
+                continue;
+            };
+            thread.push((depth, record));
+            // Reversed so the oldest reply gets popped first.
+            for child in self.order.iter().rev() {
+                if self.get(child).and_then(|record| record.reply_to) == Some(id) {
+                    stack.push((depth + 1, *child));
+                }
+            }
+        }
+        thread
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(author: Id, msg: &str) -> Record {
+        Record {
+            author,
+            username: "someone".to_string(),
+            buffer: "#general".to_string(),
+            time: Local::now(),
+            msg: msg.to_string(),
+            reply_to: None,
+            edited: false,
+            deleted: false,
+            reactions: vec![],
+            backfilled: false,
+        }
+    }
+
+    #[test]
+    fn someone_else_cant_take_over_a_message() {
+        let mut messages = Messages::new();
+        assert!(messages.insert([1; 8], record([2; 8], "hi")));
+        assert!(!messages.insert([1; 8], record([3; 8], "mine now")));
+
+        let kept = messages.get(&[1; 8]).unwrap();
+        assert_eq!(kept.author, [2; 8]);
+        assert_eq!(kept.msg, "hi");
+    }
+
+    #[test]
+    fn echo_keeps_what_we_have() {
+        let mut messages = Messages::new();
+        messages.insert([1; 8], record([2; 8], "hi"));
+        messages.get_mut(&[1; 8]).unwrap().edited = true;
+        assert!(messages.insert([1; 8], record([2; 8], "hi")));
+        assert!(messages.get(&[1; 8]).unwrap().edited);
+        assert_eq!(messages.recent("#general", 10).len(), 1);
+    }
+}
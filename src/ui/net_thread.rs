@@ -1,19 +1,439 @@
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::time::{Duration, Instant};
 
 use crossbeam_channel::{Receiver, Sender};
+use pnet::util::MacAddr;
 use rand::Rng;
 
+use crate::confusables::{is_confusable, skeleton};
 use crate::error::ArpchatError;
-use crate::net::{sorted_usable_interfaces, Channel, Id, Packet};
+use crate::net::{sorted_usable_interfaces, Availability, BacklogEntry, Channel, Id, Packet};
+use crate::privileges::drop_privileges;
+use crate::ratelimit::{RateLimiter, Verdict};
+use crate::ringbuffer::Ringbuffer;
 
-use super::config::CONFIG;
-use super::util::UpdatePresenceKind;
+use super::backlog::Backlogs;
+use super::config::{Config, RateLimits, CONFIG};
+use super::history::History;
+use super::ignore::IgnoreList;
+use super::mailbox::{open_mail, Mailbox};
+use super::shares::Shares;
+use super::topics::RoomInfos;
+use super::transfer::{Incoming, Offer, Transfers};
+use super::util::{hex_id, image_caption, parse_hex_id, short_id, Delivery, UpdatePresenceKind};
 use super::{NetCommand, UICommand};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
 const INACTIVE_TIMEOUT: Duration = Duration::from_secs(6);
 const OFFLINE_TIMEOUT: Duration = Duration::from_secs(12);
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+const RECEIPT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECEIPT_IDS: usize = 32;
+
+pub(super) struct Peer {
+    last_heartbeat: Instant,
+    username: String,
+    rooms: Vec<String>,
+    availability: Availability,
+    status: String,
+    shares: Vec<String>,
+}
+
+pub(super) type Online = HashMap<Id, Peer>;
+
+/// The name to show for a peer. If anyone else online has a name that looks
+/// the same, it gets a short id suffix so the two can be told apart.
+fn display_name(id: &Id, username: &str, online: &Online) -> String {
+    let skeleton = skeleton(username);
+    let collides = online
+        .iter()
+        .any(|(other_id, other)| other_id != id && self::skeleton(&other.username) == skeleton);
+    if collides {
+        format!("{username}#{}", short_id(id))
+    } else {
+        username.to_string()
+    }
+}
+
+fn name_of(id: &Id, online: &Online) -> String {
+    match online.get(id) {
+        Some(peer) => display_name(id, &peer.username, online),
+        None => "unknown".to_string(),
+    }
+}
+
+/// The name to show for the other person in a direct conversation, who
+/// might have gone offline.
+fn peer_name(id: &Id, online: &Online, mailbox: &Mailbox) -> String {
+    match (online.contains_key(id), mailbox.username(id)) {
+        (false, Some(username)) => username.to_string(),
+        _ => name_of(id, online),
+    }
+}
+
+/// Re-send presence list entries for everyone whose name is confusable with
+/// one of `names`, since their suffix might have just appeared or gone away.
+fn refresh_confusable(tx: &Sender<UICommand>, online: &Online, except: &Id, names: &[&str]) {
+    for (id, peer) in online.iter() {
+        if id == except || !names.iter().any(|name| is_confusable(name, &peer.username)) {
+            continue;
+        }
+        tx.try_send(UICommand::PresenceUpdate(
+            *id,
+            display_name(id, &peer.username, online),
+            peer.last_heartbeat.elapsed() > INACTIVE_TIMEOUT,
+            UpdatePresenceKind::Boring,
+            peer.rooms.clone(),
+            peer.availability,
+            peer.status.clone(),
+        ))
+        .unwrap();
+    }
+}
+
+fn open_channel(name: &str) -> Result<Channel, ArpchatError> {
+    let interface = sorted_usable_interfaces()
+        .into_iter()
+        .find(|iface| iface.name == name)
+        .ok_or_else(|| ArpchatError::InvalidInterface(name.to_string()))?;
+    Channel::from_interface(interface)
+}
+
+/// Find an online peer by hex id or by the name they're displayed with.
+fn find_peer(target: &str, online: &Online) -> Option<Id> {
+    if let Some(id) = parse_hex_id(target) {
+        return Some(id);
+    }
+    online
+        .iter()
+        .find(|(id, peer)| display_name(id, &peer.username, online) == target)
+        .map(|(id, _)| *id)
+}
+
+/// Find an online peer who's sharing something.
+fn find_sharer(target: &str, online: &Online) -> Result<Id, String> {
+    let id = find_peer(target, online)
+        .filter(|id| online.contains_key(id))
+        .ok_or_else(|| format!("nobody called {target} is online"))?;
+    match online[&id].shares.is_empty() {
+        true => Err(format!("{target} isn't sharing anything")),
+        false => Ok(id),
+    }
+}
+
+/// Tack on a save failure to a notice, so changes that won't survive a
+/// restart don't look like they will.
+fn and_saved(notice: String, saved: Result<(), ArpchatError>) -> String {
+    match saved {
+        Ok(()) => notice,
+        Err(err) => format!("{notice}, but {err}"),
+    }
+}
+
+/// Whether something claiming to be from `id` came from the MAC they've
+/// been talking from. Anyone we haven't heard from yet doesn't count.
+fn sent_by(
+    id: &Id,
+    src_mac: MacAddr,
+    local_id: &Id,
+    local_mac: MacAddr,
+    macs: &HashMap<Id, MacAddr>,
+) -> bool {
+    match id == local_id {
+        true => src_mac == local_mac,
+        false => macs.get(id) == Some(&src_mac),
+    }
+}
+
+/// What handlers that live outside the net thread get to work with: who's
+/// who, a way to tell the UI things, and somewhere to put packets. The
+/// packets go out once the handler's done with them, so handlers don't need
+/// a network to be tried out.
+pub(super) struct Net<'a> {
+    pub local_id: Id,
+    pub tx: &'a Sender<UICommand>,
+    macs: &'a HashMap<Id, MacAddr>,
+    online: &'a Online,
+    outbox: Vec<(Packet, MacAddr)>,
+}
+
+impl<'a> Net<'a> {
+    pub fn new(
+        local_id: Id,
+        tx: &'a Sender<UICommand>,
+        macs: &'a HashMap<Id, MacAddr>,
+        online: &'a Online,
+    ) -> Self {
+        Self {
+            local_id,
+            tx,
+            macs,
+            online,
+            outbox: vec![],
+        }
+    }
+
+    /// Where to find someone. If we haven't heard from them, everyone.
+    pub fn mac_of(&self, id: &Id) -> MacAddr {
+        self.macs
+            .get(id)
+            .copied()
+            .unwrap_or_else(MacAddr::broadcast)
+    }
+
+    pub fn name_of(&self, id: &Id) -> String {
+        name_of(id, self.online)
+    }
+
+    pub fn notice(&self, notice: String) {
+        self.tx.try_send(UICommand::Notice(notice)).unwrap();
+    }
+
+    pub fn send(&mut self, packet: Packet) {
+        self.send_to(packet, MacAddr::broadcast());
+    }
+
+    pub fn send_to(&mut self, packet: Packet, mac: MacAddr) {
+        self.outbox.push((packet, mac));
+    }
+
+    pub fn send_to_peer(&mut self, packet: Packet, id: &Id) {
+        let mac = self.mac_of(id);
+        self.send_to(packet, mac);
+    }
+
+    #[cfg(test)]
+    pub fn sent(&self) -> &[(Packet, MacAddr)] {
+        &self.outbox
+    }
+
+    fn flush(self, channel: &mut Channel) -> Result<(), ArpchatError> {
+        for (packet, mac) in self.outbox {
+            channel.send_to(packet, mac)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drop a peer from the online list without announcing a disconnect.
+fn forget_peer(tx: &Sender<UICommand>, online: &mut Online, id: &Id) {
+    if let Some(peer) = online.remove(id) {
+        tx.try_send(UICommand::HidePresence(*id)).unwrap();
+        refresh_confusable(tx, online, id, &[&peer.username]);
+    }
+}
+
+/// One of our messages that hasn't come back to us yet.
+struct Pending {
+    sent: Instant,
+    packet: Packet,
+    timed_out: bool,
+}
+
+/// Show one of our own messages in the chat.
+fn echo(
+    tx: &Sender<UICommand>,
+    packet: &Packet,
+    username: &str,
+    online: &Online,
+    mailbox: &Mailbox,
+    delivery: Delivery,
+) {
+    match packet {
+        Packet::Message(id, msg_id, reply_to, room, msg) => {
+            tx.try_send(UICommand::NewMessage(
+                *id,
+                *msg_id,
+                *reply_to,
+                username.to_string(),
+                room.clone(),
+                msg.clone(),
+                delivery,
+            ))
+            .unwrap();
+        }
+        Packet::DirectMessage(from, to, msg_id, reply_to, msg) => {
+            tx.try_send(UICommand::NewDirectMessage(
+                *to,
+                peer_name(to, online, mailbox),
+                *from,
+                *msg_id,
+                *reply_to,
+                msg.clone(),
+                delivery,
+            ))
+            .unwrap();
+        }
+        Packet::Image(from, msg_id, Some(to), _, name, _) => {
+            tx.try_send(UICommand::NewDirectMessage(
+                *to,
+                peer_name(to, online, mailbox),
+                *from,
+                *msg_id,
+                None,
+                image_caption(name),
+                delivery,
+            ))
+            .unwrap();
+        }
+        Packet::Image(id, msg_id, None, room, name, _) => {
+            tx.try_send(UICommand::NewMessage(
+                *id,
+                *msg_id,
+                None,
+                username.to_string(),
+                room.clone(),
+                image_caption(name),
+                delivery,
+            ))
+            .unwrap();
+        }
+        _ => {}
+    }
+}
+
+/// Everyone who should get one of our messages, and what they're called.
+fn recipients(
+    packet: &Packet,
+    local_id: &Id,
+    online: &Online,
+    mailbox: &Mailbox,
+) -> Vec<(Id, String)> {
+    match packet {
+        Packet::Message(_, _, _, room, _) | Packet::Image(_, _, None, room, _, _) => online
+            .iter()
+            .filter(|(id, peer)| *id != local_id && peer.rooms.contains(room))
+            .map(|(id, peer)| (*id, display_name(id, &peer.username, online)))
+            .collect(),
+        Packet::DirectMessage(_, to, _, _, _) | Packet::Image(_, _, Some(to), _, _, _) => {
+            vec![(*to, peer_name(to, online, mailbox))]
+        }
+        _ => vec![],
+    }
+}
+
+/// Where to send a packet. If we haven't heard from a direct message's
+/// recipient we don't know where they are, so fall back to broadcasting.
+fn destination(packet: &Packet, macs: &HashMap<Id, MacAddr>) -> MacAddr {
+    match packet {
+        Packet::DirectMessage(_, to, _, _, _)
+        | Packet::Receipt(_, to, _, _)
+        | Packet::Image(_, _, Some(to), _, _, _) => {
+            macs.get(to).copied().unwrap_or_else(MacAddr::broadcast)
+        }
+        _ => MacAddr::broadcast(),
+    }
+}
+
+/// Whether we're the one who should answer a backlog request: whoever has
+/// the lowest id of everyone in any of the rooms asked about.
+fn should_send_backlog(local_id: &Id, from: &Id, rooms: &[String], online: &Online) -> bool {
+    online
+        .iter()
+        .filter(|(id, peer)| *id != from && peer.rooms.iter().any(|room| rooms.contains(room)))
+        .map(|(id, _)| id)
+        .min()
+        == Some(local_id)
+}
+
+/// The same as what was sent, to hold onto for someone.
+fn held_entry(
+    author: Id,
+    msg_id: Id,
+    reply_to: Option<Id>,
+    username: String,
+    room: &str,
+    msg: &str,
+) -> BacklogEntry {
+    BacklogEntry {
+        msg_id,
+        author,
+        time: chrono::offset::Local::now().timestamp(),
+        reply_to,
+        username,
+        room: room.to_string(),
+        msg: msg.to_string(),
+    }
+}
+
+/// Incoming rate limits, tracked by both source MAC and sender id so that
+/// spoofing either one alone doesn't get a flooder very far.
+struct FloodGuard {
+    messages_by_mac: RateLimiter<MacAddr>,
+    messages_by_id: RateLimiter<Id>,
+    presences_by_mac: RateLimiter<MacAddr>,
+    presences_by_id: RateLimiter<Id>,
+    receipts_by_mac: RateLimiter<MacAddr>,
+    receipts_by_id: RateLimiter<Id>,
+    typing_by_mac: RateLimiter<MacAddr>,
+    typing_by_id: RateLimiter<Id>,
+    transfers_by_mac: RateLimiter<MacAddr>,
+    transfers_by_id: RateLimiter<Id>,
+}
+
+impl FloodGuard {
+    fn new(limits: &RateLimits) -> Self {
+        Self {
+            messages_by_mac: RateLimiter::new(limits.message_rate, limits.message_burst),
+            messages_by_id: RateLimiter::new(limits.message_rate, limits.message_burst),
+            presences_by_mac: RateLimiter::new(limits.presence_rate, limits.presence_burst),
+            presences_by_id: RateLimiter::new(limits.presence_rate, limits.presence_burst),
+            receipts_by_mac: RateLimiter::new(limits.message_rate, limits.message_burst),
+            receipts_by_id: RateLimiter::new(limits.message_rate, limits.message_burst),
+            typing_by_mac: RateLimiter::new(limits.typing_rate, limits.typing_burst),
+            typing_by_id: RateLimiter::new(limits.typing_rate, limits.typing_burst),
+            transfers_by_mac: RateLimiter::new(limits.transfer_rate, limits.transfer_burst),
+            transfers_by_id: RateLimiter::new(limits.transfer_rate, limits.transfer_burst),
+        }
+    }
+
+    fn check(&mut self, src_mac: MacAddr, packet: &Packet) -> Verdict {
+        let (by_mac, by_id) = match packet {
+            Packet::Message(_, _, _, _, _)
+            | Packet::DirectMessage(_, _, _, _, _)
+            | Packet::Edit(_, _, _)
+            | Packet::Delete(_, _)
+            | Packet::Reaction(_, _, _, _)
+            | Packet::Mail(_, _, _)
+            | Packet::FileOffer(_, _, _, _, _, _, _)
+            | Packet::FileCancel(_, _, _)
+            | Packet::ListReq(_, _, _)
+            | Packet::Listing(_, _, _, _, _)
+            | Packet::GetReq(_, _, _, _)
+            | Packet::ShareError(_, _, _, _)
+            | Packet::Image(_, _, _, _, _, _)
+            | Packet::RoomInfo(_, _, _, _, _) => {
+                (&mut self.messages_by_mac, &mut self.messages_by_id)
+            }
+            Packet::Receipt(_, _, _, _) | Packet::MailAck(_, _) => {
+                (&mut self.receipts_by_mac, &mut self.receipts_by_id)
+            }
+            Packet::Typing(_, _, _) => (&mut self.typing_by_mac, &mut self.typing_by_id),
+            Packet::FileAccept(_, _, _, _) | Packet::FileChunk(_, _, _, _, _) => {
+                (&mut self.transfers_by_mac, &mut self.transfers_by_id)
+            }
+            _ => (&mut self.presences_by_mac, &mut self.presences_by_id),
+        };
+        match (by_mac.check(src_mac), packet.sender()) {
+            (Verdict::Allowed, Some(id)) => by_id.check(id),
+            (verdict, _) => verdict,
+        }
+    }
+
+    fn prune(&mut self) {
+        self.messages_by_mac.prune();
+        self.messages_by_id.prune();
+        self.presences_by_mac.prune();
+        self.presences_by_id.prune();
+        self.receipts_by_mac.prune();
+        self.receipts_by_id.prune();
+        self.typing_by_mac.prune();
+        self.typing_by_id.prune();
+        self.transfers_by_mac.prune();
+        self.transfers_by_id.prune();
+    }
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum NetThreadState {
@@ -22,32 +442,83 @@ enum NetThreadState {
     Ready,
 }
 
-pub(super) fn start_net_thread(tx: Sender<UICommand>, rx: Receiver<NetCommand>) {
-    let local_id: Id = rand::thread_rng().gen();
+pub(super) fn start_net_thread(
+    local_id: Id,
+    tx: Sender<UICommand>,
+    net_tx: Sender<NetCommand>,
+    rx: Receiver<NetCommand>,
+) {
     let mut local_username: String = "".to_string();
+    let mut local_rooms = CONFIG.lock().unwrap().joined_rooms();
+    let mut local_availability = Availability::Available;
+    let mut local_status = String::new();
     let mut channel: Option<Channel> = None;
+    let mut interface_name = String::new();
+    let mut spare_channels: HashMap<String, Channel> = HashMap::new();
+    let mut privileges_notice: Option<String> = None;
 
     let mut last_heartbeat = Instant::now();
-    let mut online: HashMap<Id, (Instant, String)> = HashMap::new();
+    let mut online: Online = HashMap::new();
     let mut offline: HashSet<Id> = HashSet::new();
+    let mut macs: HashMap<Id, MacAddr> = HashMap::new();
+    let mut ignored = IgnoreList::load();
+    let mut pending: HashMap<Id, Pending> = HashMap::new();
+    let mut seen_messages: Ringbuffer<Id> = Ringbuffer::with_capacity(256);
+    // Receipts we owe, keyed by message author, sent in batches.
+    let mut receipts: HashMap<Id, (Vec<Id>, Vec<Id>)> = HashMap::new();
+    let mut last_receipts = Instant::now();
 
     let mut state = NetThreadState::NeedsUsername;
     let mut pause_heartbeat = false;
 
+    let rate_limits = CONFIG.lock().unwrap().rate_limits;
+    let mut flood_guard = FloodGuard::new(&rate_limits);
+    let presence_reply_interval = Duration::from_millis(rate_limits.presence_reply_interval_ms);
+    let mut last_presence_reply: Option<Instant> = None;
+
+    let mut backlogs = Backlogs::new();
+
+    let use_mailbox = CONFIG.lock().unwrap().mailbox.unwrap_or(true);
+    let mut mailbox = Mailbox::new();
+
+    let downloads_dir = CONFIG.lock().unwrap().downloads_dir();
+    let mut transfers = Transfers::new(downloads_dir, net_tx);
+    let mut shares = Shares::load();
+
+    let mut room_info = RoomInfos::new();
+
     loop {
         let res: Result<(), ArpchatError> = try {
             if channel.is_none() {
                 if let Ok(NetCommand::SetInterface(name)) = rx.try_recv() {
-                    let interface = sorted_usable_interfaces()
-                        .into_iter()
-                        .find(|iface| iface.name == name)
-                        .ok_or(ArpchatError::InvalidInterface(name))?;
-
-                    let mut new_channel = Channel::from_interface(interface)?;
+                    let mut new_channel = open_channel(&name)?;
                     if let Some(ether_type) = CONFIG.lock().unwrap().ether_type {
                         new_channel.set_ether_type(ether_type);
                     }
                     channel = Some(new_channel);
+                    interface_name = name.clone();
+                    tx.try_send(UICommand::InterfaceChanged(name)).unwrap();
+
+                    // We won't be able to open anything once privileges are
+                    // gone, so grab every other interface we might want to
+                    // switch to while we still can.
+                    if cfg!(target_os = "linux") {
+                        for iface in sorted_usable_interfaces() {
+                            if iface.name == interface_name {
+                                continue;
+                            }
+                            let name = iface.name.clone();
+                            if let Ok(spare) = Channel::from_interface(iface) {
+                                spare_channels.insert(name, spare);
+                            }
+                        }
+                    }
+                    let ours = [Config::get_config_path(), History::get_history_path()];
+                    if let Some(dropped) = drop_privileges(&ours)? {
+                        tx.try_send(UICommand::PrivilegesDropped).unwrap();
+                        // The chat isn't up yet, so this waits until we're in.
+                        privileges_notice = Some(dropped);
+                    }
                 } else {
                     continue;
                 }
@@ -56,17 +527,424 @@ pub(super) fn start_net_thread(tx: Sender<UICommand>, rx: Receiver<NetCommand>)
             let channel = unsafe { channel.as_mut().unwrap_unchecked() };
 
             match rx.try_recv() {
-                Ok(NetCommand::SetInterface(_)) => Err(ArpchatError::InterfaceAlreadySet)?,
+                Ok(NetCommand::SetInterface(name)) if name != interface_name => {
+                    let new_channel = match spare_channels.remove(&name) {
+                        Some(spare) => Some(spare),
+                        // Without privileges we couldn't open anything new.
+                        None if cfg!(target_os = "linux") => None,
+                        None => Some(open_channel(&name)?),
+                    };
+
+                    match new_channel {
+                        Some(mut new_channel) => {
+                            let _ = channel.send(Packet::Disconnect(local_id));
+                            // Spares aren't read while they wait, so whatever
+                            // built up in the meantime is old news.
+                            new_channel.drain();
+                            new_channel.set_ether_type(channel.ether_type());
+                            let old_channel = std::mem::replace(channel, new_channel);
+                            spare_channels.insert(interface_name.clone(), old_channel);
+                            interface_name = name.clone();
+
+                            // Everyone we knew is on the other network.
+                            for id in online.keys().copied().collect::<Vec<Id>>() {
+                                forget_peer(&tx, &mut online, &id);
+                            }
+                            macs.clear();
+                            offline.clear();
+                            channel.send(Packet::PresenceReq)?;
+                            state = NetThreadState::NeedsInitialPresence;
+
+                            tx.try_send(UICommand::InterfaceChanged(name)).unwrap();
+                        }
+                        None => {
+                            tx.try_send(UICommand::Notice(format!(
+                                "can't switch to {name}, it couldn't be opened before privileges were dropped"
+                            )))
+                            .unwrap();
+                        }
+                    }
+                }
+                Ok(NetCommand::SetInterface(_)) => {}
                 Ok(NetCommand::SetEtherType(ether_type)) => channel.set_ether_type(ether_type),
-                Ok(NetCommand::SendMessage(msg)) => {
-                    tx.try_send(UICommand::NewMessage(
-                        local_id,
-                        local_username.clone(),
-                        msg.clone(),
-                        true,
-                    ))
-                    .unwrap();
-                    channel.send(Packet::Message(local_id, msg))?;
+                Ok(NetCommand::SendMessage(room, msg, reply_to)) => {
+                    let msg_id: Id = rand::thread_rng().gen();
+                    let packet = Packet::Message(local_id, msg_id, reply_to, room, msg);
+                    let expected = recipients(&packet, &local_id, &online, &mailbox);
+                    tx.try_send(UICommand::ExpectReceipts(msg_id, expected))
+                        .unwrap();
+                    echo(
+                        &tx,
+                        &packet,
+                        &local_username,
+                        &online,
+                        &mailbox,
+                        Delivery::Sending,
+                    );
+                    channel.send(packet.clone())?;
+                    pending.insert(
+                        msg_id,
+                        Pending {
+                            sent: Instant::now(),
+                            packet,
+                            timed_out: false,
+                        },
+                    );
+                }
+                Ok(NetCommand::Retry) => {
+                    let mut retried = false;
+                    for pending in pending.values_mut().filter(|pending| pending.timed_out) {
+                        echo(
+                            &tx,
+                            &pending.packet,
+                            &local_username,
+                            &online,
+                            &mailbox,
+                            Delivery::Sending,
+                        );
+                        channel
+                            .send_to(pending.packet.clone(), destination(&pending.packet, &macs))?;
+                        pending.sent = Instant::now();
+                        pending.timed_out = false;
+                        retried = true;
+                    }
+                    if !retried {
+                        tx.try_send(UICommand::Notice("nothing to retry".to_string()))
+                            .unwrap();
+                    }
+                }
+                Ok(NetCommand::MarkRead(read)) => {
+                    for (author, msg_id) in read {
+                        let (delivered, read) = receipts.entry(author).or_default();
+                        // Reading implies delivery.
+                        delivered.retain(|other| *other != msg_id);
+                        read.push(msg_id);
+                    }
+                }
+                Ok(NetCommand::EditMessage(msg_id, msg, peer)) => {
+                    let dest = peer
+                        .and_then(|peer| macs.get(&peer).copied())
+                        .unwrap_or_else(MacAddr::broadcast);
+                    channel.send_to(Packet::Edit(local_id, msg_id, msg), dest)?;
+                }
+                Ok(NetCommand::DeleteMessage(msg_id, peer)) => {
+                    let dest = peer
+                        .and_then(|peer| macs.get(&peer).copied())
+                        .unwrap_or_else(MacAddr::broadcast);
+                    channel.send_to(Packet::Delete(local_id, msg_id), dest)?;
+                }
+                Ok(NetCommand::React(msg_id, reaction, is_added, peer)) => {
+                    let dest = peer
+                        .and_then(|peer| macs.get(&peer).copied())
+                        .unwrap_or_else(MacAddr::broadcast);
+                    let packet = Packet::Reaction(local_id, msg_id, reaction, is_added);
+                    channel.send_to(packet, dest)?;
+                }
+                Ok(NetCommand::SendImage(buffer, name, image)) => {
+                    let msg_id: Id = rand::thread_rng().gen();
+                    tx.try_send(UICommand::Image(msg_id, name.clone(), image.clone()))
+                        .unwrap();
+                    let to = buffer.strip_prefix('@').and_then(parse_hex_id);
+                    let room = match to {
+                        Some(_) => String::new(),
+                        None => buffer,
+                    };
+                    let packet = Packet::Image(local_id, msg_id, to, room, name, image);
+                    let expected = recipients(&packet, &local_id, &online, &mailbox);
+                    tx.try_send(UICommand::ExpectReceipts(msg_id, expected))
+                        .unwrap();
+                    echo(
+                        &tx,
+                        &packet,
+                        &local_username,
+                        &online,
+                        &mailbox,
+                        Delivery::Sending,
+                    );
+                    channel.send_to(packet.clone(), destination(&packet, &macs))?;
+                    pending.insert(
+                        msg_id,
+                        Pending {
+                            sent: Instant::now(),
+                            packet,
+                            timed_out: false,
+                        },
+                    );
+                }
+                Ok(NetCommand::Typing(buffer)) if state == NetThreadState::Ready => {
+                    match buffer.strip_prefix('@').and_then(parse_hex_id) {
+                        // Not worth a broadcast if we don't know where they are.
+                        Some(to) => {
+                            if let Some(mac) = macs.get(&to) {
+                                let packet = Packet::Typing(local_id, Some(to), String::new());
+                                channel.send_to(packet, *mac)?;
+                            }
+                        }
+                        None => channel.send(Packet::Typing(local_id, None, buffer))?,
+                    }
+                }
+                Ok(NetCommand::Typing(_)) => {}
+                Ok(NetCommand::SetStatus(availability, status)) => {
+                    local_availability = availability;
+                    local_status = status;
+                    if state == NetThreadState::Ready {
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    }
+                }
+                Ok(NetCommand::Who(buffer)) => {
+                    let mut names: Vec<String> = online
+                        .iter()
+                        .filter(|(_, peer)| {
+                            !buffer.starts_with('#') || peer.rooms.contains(&buffer)
+                        })
+                        .map(|(id, peer)| {
+                            let name = display_name(id, &peer.username, &online);
+                            match peer.availability {
+                                Availability::Available => name,
+                                availability => format!("{name} ({availability})"),
+                            }
+                        })
+                        .collect();
+                    names.sort();
+                    let notice = match buffer.starts_with('#') {
+                        true => format!("in {buffer}: {}", names.join(", ")),
+                        false => format!("online: {}", names.join(", ")),
+                    };
+                    tx.try_send(UICommand::Notice(notice)).unwrap();
+                }
+                Ok(NetCommand::Whois(target)) => {
+                    let peer =
+                        find_peer(&target, &online).and_then(|id| Some((id, online.get(&id)?)));
+                    match peer {
+                        Some((id, peer)) => {
+                            let mac = macs.get(&id).map(|mac| mac.to_string());
+                            tx.try_send(UICommand::ShowUser(
+                                id,
+                                display_name(&id, &peer.username, &online),
+                                peer.availability,
+                                peer.status.clone(),
+                                peer.rooms.clone(),
+                                peer.shares.clone(),
+                                mac.unwrap_or_else(|| "unknown".to_string()),
+                                peer.last_heartbeat.elapsed(),
+                            ))
+                            .unwrap();
+                        }
+                        None => {
+                            tx.try_send(UICommand::Notice(format!(
+                                "nobody called {target} is online"
+                            )))
+                            .unwrap();
+                        }
+                    }
+                }
+                Ok(NetCommand::SendBacklog(to, entries)) => {
+                    // Only worth sending to someone we know where to find.
+                    if let Some(mac) = macs.get(&to) {
+                        channel.send_to(Packet::HistoryResp(local_id, to, entries), *mac)?;
+                    }
+                }
+                Ok(NetCommand::OfferFile(file, name, size, hash, target, buffer)) => {
+                    let to = match &target {
+                        Some(target) => match find_peer(target, &online) {
+                            Some(to) => Some(to),
+                            None => {
+                                tx.try_send(UICommand::Notice(format!(
+                                    "nobody called {target} is online"
+                                )))
+                                .unwrap();
+                                continue;
+                            }
+                        },
+                        None => buffer.strip_prefix('@').and_then(parse_hex_id),
+                    };
+                    let buffer = match to {
+                        Some(to) => format!("@{}", hex_id(&to)),
+                        None => buffer,
+                    };
+                    let offer = Offer {
+                        file,
+                        name,
+                        size,
+                        to,
+                        buffer,
+                        since: Instant::now(),
+                        served: false,
+                    };
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.offer(&mut net, offer, hash);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::AcceptFile(file_id)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.accept(&mut net, file_id);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::DeclineFile(file_id)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.decline(&mut net, file_id);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::Share(path)) => {
+                    let notice = if path.is_empty() {
+                        match shares.describe().as_slice() {
+                            [] => "you're not sharing anything".to_string(),
+                            described => format!("sharing {}", described.join(", ")),
+                        }
+                    } else {
+                        match shares.add(Path::new(&path)) {
+                            Ok(name) => {
+                                and_saved(format!("sharing {path} as {name}"), shares.save())
+                            }
+                            Err(err) => err,
+                        }
+                    };
+                    tx.try_send(UICommand::Notice(notice)).unwrap();
+                    if state == NetThreadState::Ready {
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    }
+                }
+                Ok(NetCommand::Unshare(name)) => {
+                    let notice = match shares.remove(&name) {
+                        true => and_saved(format!("stopped sharing {name}"), shares.save()),
+                        false => format!("you're not sharing anything called {name}"),
+                    };
+                    tx.try_send(UICommand::Notice(notice)).unwrap();
+                    if state == NetThreadState::Ready {
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    }
+                }
+                Ok(NetCommand::List(target, path)) => match find_sharer(&target, &online) {
+                    Ok(to) => {
+                        let dest = macs.get(&to).copied().unwrap_or_else(MacAddr::broadcast);
+                        channel.send_to(Packet::ListReq(local_id, to, path), dest)?;
+                    }
+                    Err(err) => tx.try_send(UICommand::Notice(err)).unwrap(),
+                },
+                Ok(NetCommand::Get(target, path)) => match find_sharer(&target, &online) {
+                    Ok(to) => {
+                        let mut net = Net::new(local_id, &tx, &macs, &online);
+                        transfers.get(&mut net, to, &target, path);
+                        net.flush(channel)?;
+                    }
+                    Err(err) => tx.try_send(UICommand::Notice(err)).unwrap(),
+                },
+                Ok(NetCommand::ServeFile(file_id, asked, offer, hash)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.hashed(&mut net, file_id, asked, offer, hash);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::JoinRoom(room)) => {
+                    if !local_rooms.contains(&room) {
+                        local_rooms.push(room);
+                    }
+                    // Let everyone know now rather than at the next heartbeat.
+                    if state == NetThreadState::Ready {
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    }
+                }
+                Ok(NetCommand::PartRoom(room)) => {
+                    local_rooms.retain(|other| *other != room);
+                    room_info.part(&room);
+                    if state == NetThreadState::Ready {
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    }
+                }
+                Ok(NetCommand::SetTopic(room, text)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    room_info.set_topic(&mut net, &room, text, &local_username);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::Pin(room, entry)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    room_info.pin(&mut net, &room, entry);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::Unpin(room, msg_id)) => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    room_info.unpin(&mut net, &room, msg_id);
+                    net.flush(channel)?;
+                }
+                Ok(NetCommand::SendDirect(target, msg, reply_to)) => {
+                    let away = mailbox.find(&target);
+                    match find_peer(&target, &online).or(away) {
+                        Some(to) => {
+                            if away.is_some() {
+                                tx.try_send(UICommand::Notice(format!(
+                                    "{target} is offline, anyone holding mail will pass it on"
+                                )))
+                                .unwrap();
+                            }
+                            let msg_id: Id = rand::thread_rng().gen();
+                            let packet = Packet::DirectMessage(local_id, to, msg_id, reply_to, msg);
+                            let expected = recipients(&packet, &local_id, &online, &mailbox);
+                            tx.try_send(UICommand::ExpectReceipts(msg_id, expected))
+                                .unwrap();
+                            echo(
+                                &tx,
+                                &packet,
+                                &local_username,
+                                &online,
+                                &mailbox,
+                                Delivery::Sending,
+                            );
+                            channel.send_to(packet.clone(), destination(&packet, &macs))?;
+                            pending.insert(
+                                msg_id,
+                                Pending {
+                                    sent: Instant::now(),
+                                    packet,
+                                    timed_out: false,
+                                },
+                            );
+                        }
+                        None => {
+                            tx.try_send(UICommand::Notice(format!(
+                                "nobody called {target} is online"
+                            )))
+                            .unwrap();
+                        }
+                    }
                 }
                 Ok(NetCommand::UpdateUsername(new_username)) => {
                     local_username = new_username;
@@ -80,92 +958,621 @@ pub(super) fn start_net_thread(tx: Sender<UICommand>, rx: Receiver<NetCommand>)
                     break;
                 }
                 Ok(NetCommand::PauseHeartbeat(pause)) => pause_heartbeat = pause,
+                Ok(NetCommand::Ignore(target)) => {
+                    let notice = if target.is_empty() {
+                        match ignored.names().as_slice() {
+                            [] => "you're not ignoring anyone".to_string(),
+                            names => format!("ignoring {}", names.join(", ")),
+                        }
+                    } else {
+                        let found = match target.parse::<MacAddr>() {
+                            Ok(mac) => Ok((mac.to_string(), mac)),
+                            Err(_) => match find_peer(&target, &online) {
+                                Some(id) if id == local_id => {
+                                    Err("you can't ignore yourself".to_string())
+                                }
+                                Some(id) => {
+                                    let name = match online.get(&id) {
+                                        Some(peer) => display_name(&id, &peer.username, &online),
+                                        None => target.clone(),
+                                    };
+                                    match macs.get(&id) {
+                                        Some(mac) => Ok((name, *mac)),
+                                        None => Err(format!("haven't heard from {name} yet")),
+                                    }
+                                }
+                                None => Err(format!("nobody called {target} is online")),
+                            },
+                        };
+                        match found {
+                            Ok((_, mac)) if mac == channel.src_mac() => {
+                                "you can't ignore yourself".to_string()
+                            }
+                            Ok((name, mac)) => {
+                                ignored.add(name.clone(), mac);
+                                // Everyone sending from there goes, not just
+                                // whoever was asked for.
+                                let ids: Vec<Id> = macs
+                                    .iter()
+                                    .filter(|(_, other)| **other == mac)
+                                    .map(|(id, _)| *id)
+                                    .collect();
+                                for id in ids {
+                                    forget_peer(&tx, &mut online, &id);
+                                }
+                                and_saved(format!("ignoring {name}"), ignored.save())
+                            }
+                            Err(notice) => notice,
+                        }
+                    };
+                    tx.try_send(UICommand::Notice(notice)).unwrap();
+                }
+                Ok(NetCommand::Unignore(target)) => {
+                    let notice = match ignored.remove(&target).as_slice() {
+                        [] => format!("you weren't ignoring {target}"),
+                        names => and_saved(
+                            format!("no longer ignoring {}", names.join(", ")),
+                            ignored.save(),
+                        ),
+                    };
+                    tx.try_send(UICommand::Notice(notice)).unwrap();
+                }
                 Err(_) => {}
             }
 
-            match channel.try_recv()? {
-                Some(Packet::Message(id, msg)) => {
-                    let username = match online.get(&id) {
-                        Some((_, username)) => username.clone(),
-                        None => "unknown".to_string(),
-                    };
-                    if id != local_id && msg.contains(&local_username) {
-                        tx.try_send(UICommand::AlertUser).unwrap();
+            let received = match channel.try_recv()? {
+                Some((src_mac, _)) if ignored.matches(src_mac) => None,
+                // Our own packets are never rate limited, we'd lose our echoes.
+                // Either half is easy to fake, so it takes both.
+                Some((src_mac, packet))
+                    if src_mac == channel.src_mac() && packet.sender() == Some(local_id) =>
+                {
+                    Some((src_mac, packet))
+                }
+                Some((src_mac, packet)) => match flood_guard.check(src_mac, &packet) {
+                    Verdict::Allowed => Some((src_mac, packet)),
+                    Verdict::JustMuted => {
+                        let name = packet
+                            .sender()
+                            .and_then(|id| {
+                                let peer = online.get(&id)?;
+                                Some(display_name(&id, &peer.username, &online))
+                            })
+                            .unwrap_or_else(|| src_mac.to_string());
+                        tx.try_send(UICommand::Notice(format!("{name} muted for flooding")))
+                            .unwrap();
+                        None
+                    }
+                    Verdict::Muted => None,
+                },
+                None => None,
+            };
+
+            match received {
+                // Retries of things we've already seen.
+                Some((_, Packet::Message(_, msg_id, _, _, _)))
+                | Some((_, Packet::DirectMessage(_, _, msg_id, _, _)))
+                | Some((_, Packet::Image(_, msg_id, _, _, _, _)))
+                    if seen_messages.contains(&msg_id) => {}
+                Some((_, Packet::Message(id, msg_id, reply_to, room, msg))) => {
+                    seen_messages.push(msg_id);
+                    room_info.saw(msg_id, id);
+                    pending.remove(&msg_id);
+                    if use_mailbox {
+                        let username = name_of(&id, &online);
+                        let entry = held_entry(id, msg_id, reply_to, username, &room, &msg);
+                        mailbox.hold(&entry, None);
                     }
-                    tx.try_send(UICommand::NewMessage(id, username, msg, false))
+
+                    // Everyone hears every room, we just don't listen.
+                    if local_rooms.contains(&room) {
+                        let username = name_of(&id, &online);
+                        if id != local_id {
+                            receipts.entry(id).or_default().0.push(msg_id);
+                            if msg.contains(&local_username) {
+                                tx.try_send(UICommand::AlertUser).unwrap();
+                            }
+                        }
+                        tx.try_send(UICommand::NewMessage(
+                            id,
+                            msg_id,
+                            reply_to,
+                            username,
+                            room,
+                            msg,
+                            Delivery::Sent,
+                        ))
                         .unwrap();
+                    }
                 }
-                Some(Packet::PresenceReq) => {
+                Some((_, Packet::PresenceReq)) => {
                     if state == NetThreadState::NeedsInitialPresence {
-                        channel.send(Packet::Presence(local_id, true, local_username.clone()))?;
-                    } else {
-                        channel.send(Packet::Presence(local_id, false, local_username.clone()))?;
+                        channel.send(Packet::Presence(
+                            local_id,
+                            true,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                    } else if last_presence_reply
+                        .is_none_or(|last| last.elapsed() >= presence_reply_interval)
+                    {
+                        // Anyone who asked in between will hear our next
+                        // heartbeat soon enough.
+                        channel.send(Packet::Presence(
+                            local_id,
+                            false,
+                            local_username.clone(),
+                            local_rooms.clone(),
+                            local_availability,
+                            local_status.clone(),
+                            shares.names(),
+                        ))?;
+                        last_presence_reply = Some(Instant::now());
                     }
                 }
-                Some(Packet::Presence(pres_id, is_join, username)) => {
-                    match online.insert(pres_id, (Instant::now(), username.clone())) {
-                        Some((_, former)) => {
+                Some((
+                    src_mac,
+                    Packet::Presence(
+                        pres_id,
+                        is_join,
+                        username,
+                        rooms,
+                        availability,
+                        status,
+                        their_shares,
+                    ),
+                )) => {
+                    macs.insert(pres_id, src_mac);
+                    mailbox.back(&pres_id, src_mac);
+                    let peer = Peer {
+                        last_heartbeat: Instant::now(),
+                        username: username.clone(),
+                        rooms: rooms.clone(),
+                        availability,
+                        status: status.clone(),
+                        shares: their_shares,
+                    };
+                    let former = online.insert(pres_id, peer).map(|peer| peer.username);
+                    let display = display_name(&pres_id, &username, &online);
+
+                    // Someone new won't know what's been going on in our
+                    // rooms. Only they get told, so asking for presence
+                    // doesn't set everyone off broadcasting it.
+                    if former.is_none() && pres_id != local_id {
+                        let news = room_info.news(local_id, &rooms);
+                        if !news.is_empty() {
+                            // Say who it's from first, in case they haven't
+                            // heard.
+                            let presence = Packet::Presence(
+                                local_id,
+                                false,
+                                local_username.clone(),
+                                local_rooms.clone(),
+                                local_availability,
+                                local_status.clone(),
+                                shares.names(),
+                            );
+                            channel.send_to(presence, src_mac)?;
+                            for packet in news {
+                                channel.send_to(packet, src_mac)?;
+                            }
+                        }
+                    }
+
+                    match &former {
+                        Some(former) => {
                             tx.try_send(UICommand::PresenceUpdate(
                                 pres_id,
-                                username,
+                                display,
                                 false,
-                                UpdatePresenceKind::UsernameChange(former),
+                                UpdatePresenceKind::UsernameChange(display_name(
+                                    &pres_id, former, &online,
+                                )),
+                                rooms,
+                                availability,
+                                status,
                             ))
                             .unwrap();
                         }
                         None => {
                             tx.try_send(UICommand::PresenceUpdate(
                                 pres_id,
-                                username,
+                                display,
                                 false,
                                 if offline.remove(&local_id) || is_join {
                                     UpdatePresenceKind::JoinOrReconnect
                                 } else {
                                     UpdatePresenceKind::Boring
                                 },
+                                rooms,
+                                availability,
+                                status,
                             ))
                             .unwrap();
                         }
                     }
 
+                    if former.as_ref() != Some(&username) {
+                        let former = former.as_deref().unwrap_or_default();
+                        refresh_confusable(&tx, &online, &pres_id, &[&username, former]);
+
+                        if pres_id != local_id && is_confusable(&username, &local_username) {
+                            tx.try_send(UICommand::Notice(format!(
+                                "warning: {username}#{} has a name that looks like yours",
+                                short_id(&pres_id)
+                            )))
+                            .unwrap();
+                        }
+                    }
+
+                    if pres_id == local_id && state != NetThreadState::Ready {
+                        let mut net = Net::new(local_id, &tx, &macs, &online);
+                        backlogs.request(&mut net, local_rooms.clone());
+                        net.flush(channel)?;
+                    }
                     if pres_id == local_id {
                         state = NetThreadState::Ready;
+                        if let Some(notice) = privileges_notice.take() {
+                            tx.try_send(UICommand::Notice(notice)).unwrap();
+                        }
+                    }
+                }
+                Some((_, Packet::DirectMessage(from, to, msg_id, reply_to, msg))) => {
+                    seen_messages.push(msg_id);
+                    pending.remove(&msg_id);
+                    if use_mailbox {
+                        let username = name_of(&from, &online);
+                        let entry = held_entry(from, msg_id, reply_to, username, "", &msg);
+                        mailbox.hold(&entry, Some(to));
+                    }
+
+                    // Broadcast fallbacks reach everyone, so check it's ours.
+                    if to == local_id || from == local_id {
+                        let peer = if from == local_id { to } else { from };
+                        if from != local_id {
+                            receipts.entry(from).or_default().0.push(msg_id);
+                            tx.try_send(UICommand::AlertUser).unwrap();
+                        }
+                        tx.try_send(UICommand::NewDirectMessage(
+                            peer,
+                            peer_name(&peer, &online, &mailbox),
+                            from,
+                            msg_id,
+                            reply_to,
+                            msg,
+                            Delivery::Sent,
+                        ))
+                        .unwrap();
                     }
                 }
-                Some(Packet::Disconnect(id)) => {
-                    if let Some((_, username)) = online.remove(&id) {
-                        tx.try_send(UICommand::RemovePresence(id, username))
+                Some((_, Packet::Image(from, msg_id, to, room, name, image))) => {
+                    seen_messages.push(msg_id);
+                    if to.is_none() {
+                        room_info.saw(msg_id, from);
+                    }
+                    pending.remove(&msg_id);
+                    // Broadcast fallbacks reach everyone, so check it's ours.
+                    let wanted = match to {
+                        Some(to) => to == local_id || from == local_id,
+                        None => local_rooms.contains(&room),
+                    };
+                    let peer = to.map(|to| if from == local_id { to } else { from });
+                    if wanted {
+                        if from != local_id {
+                            receipts.entry(from).or_default().0.push(msg_id);
+                            if peer.is_some() {
+                                tx.try_send(UICommand::AlertUser).unwrap();
+                            }
+                        }
+                        tx.try_send(UICommand::Image(msg_id, name.clone(), image))
                             .unwrap();
+                        let caption = image_caption(&name);
+                        let cmd = match peer {
+                            Some(peer) => UICommand::NewDirectMessage(
+                                peer,
+                                peer_name(&peer, &online, &mailbox),
+                                from,
+                                msg_id,
+                                None,
+                                caption,
+                                Delivery::Sent,
+                            ),
+                            None => UICommand::NewMessage(
+                                from,
+                                msg_id,
+                                None,
+                                name_of(&from, &online),
+                                room,
+                                caption,
+                                Delivery::Sent,
+                            ),
+                        };
+                        tx.try_send(cmd).unwrap();
+                    }
+                }
+                Some((_, Packet::Receipt(from, to, delivered, read))) => {
+                    if to == local_id {
+                        tx.try_send(UICommand::Receipts(
+                            from,
+                            name_of(&from, &online),
+                            delivered,
+                            read,
+                        ))
+                        .unwrap();
+                    }
+                }
+                // Anyone can claim to be the author, but at least make sure
+                // it came from where the author's been talking from. If we
+                // don't know where that is, there's no telling, so it's out.
+                Some((src_mac, Packet::Edit(author, _, _)))
+                | Some((src_mac, Packet::Delete(author, _)))
+                    if !sent_by(&author, src_mac, &local_id, channel.src_mac(), &macs) => {}
+                Some((_, Packet::Edit(author, msg_id, msg))) => {
+                    tx.try_send(UICommand::MessageEdited(author, msg_id, msg))
+                        .unwrap();
+                }
+                Some((_, Packet::Delete(author, msg_id))) => {
+                    tx.try_send(UICommand::MessageDeleted(author, msg_id))
+                        .unwrap();
+                }
+                Some((_, Packet::Reaction(from, msg_id, reaction, is_added))) => {
+                    let name = match from == local_id {
+                        true => local_username.clone(),
+                        false => name_of(&from, &online),
+                    };
+                    tx.try_send(UICommand::Reacted(from, name, msg_id, reaction, is_added))
+                        .unwrap();
+                }
+                Some((_, Packet::Typing(from, to, room))) if from != local_id => {
+                    let buffer = match to {
+                        Some(to) if to == local_id => Some(format!("@{}", hex_id(&from))),
+                        Some(_) => None,
+                        None if local_rooms.contains(&room) => Some(room),
+                        None => None,
+                    };
+                    if let Some(buffer) = buffer {
+                        tx.try_send(UICommand::Typing(from, name_of(&from, &online), buffer))
+                            .unwrap();
+                    }
+                }
+                Some((_, Packet::Typing(_, _, _))) => {}
+                Some((_, Packet::HistoryReq(from, rooms)))
+                    if from != local_id && state == NetThreadState::Ready =>
+                {
+                    if should_send_backlog(&local_id, &from, &rooms, &online) {
+                        backlogs.owe(from, rooms);
+                    }
+                }
+                Some((_, Packet::HistoryReq(_, _))) => {}
+                Some((src_mac, Packet::RoomInfo(from, room, updated, topic, pins)))
+                    if local_rooms.contains(&room)
+                        && sent_by(&from, src_mac, &local_id, channel.src_mac(), &macs) =>
+                {
+                    room_info.received(&tx, from, room, updated, topic, pins);
+                }
+                Some((_, Packet::RoomInfo(_, _, _, _, _))) => {}
+                Some((_, Packet::HistoryResp(from, to, entries))) if from != local_id => {
+                    let net = Net::new(local_id, &tx, &macs, &online);
+                    backlogs.received(&net, from, to, entries, &local_rooms, &seen_messages);
+                }
+                Some((_, Packet::HistoryResp(_, _, _))) => {}
+                Some((_, Packet::Mail(from, to, entries))) if to == local_id => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    let entries =
+                        open_mail(&mut net, from, entries, &local_rooms, &mut seen_messages);
+                    net.flush(channel)?;
+                    for entry in &entries {
+                        receipts
+                            .entry(entry.author)
+                            .or_default()
+                            .0
+                            .push(entry.msg_id);
+                    }
+                }
+                Some((_, Packet::Mail(_, _, _))) => {}
+                // Otherwise anyone could make every mailbox throw away
+                // someone's mail.
+                Some((src_mac, Packet::MailAck(id, msg_ids)))
+                    if sent_by(&id, src_mac, &local_id, channel.src_mac(), &macs) =>
+                {
+                    mailbox.delivered(&id, &msg_ids)
+                }
+                Some((_, Packet::MailAck(_, _))) => {}
+                Some((_, Packet::FileOffer(from, to, file_id, room, name, size, hash)))
+                    if from != local_id =>
+                {
+                    let buffer = match to {
+                        Some(to) if to == local_id => Some(format!("@{}", hex_id(&from))),
+                        Some(_) => None,
+                        None if local_rooms.contains(&room) => Some(room),
+                        None => None,
+                    };
+                    if let Some(buffer) = buffer {
+                        let offer = Incoming {
+                            from,
+                            name,
+                            size,
+                            hash,
+                            buffer,
+                            since: Instant::now(),
+                        };
+                        let net = Net::new(local_id, &tx, &macs, &online);
+                        transfers.offered(&net, file_id, offer);
+                    }
+                }
+                Some((_, Packet::FileOffer(_, _, _, _, _, _, _))) => {}
+                Some((src_mac, Packet::FileAccept(from, to, file_id, offset)))
+                    if to == local_id =>
+                {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.accepted(&mut net, src_mac, from, file_id, offset);
+                    net.flush(channel)?;
+                }
+                Some((_, Packet::FileAccept(_, _, _, _))) => {}
+                Some((src_mac, Packet::FileChunk(from, to, file_id, offset, chunk)))
+                    if to == local_id =>
+                {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.chunk(&mut net, src_mac, from, file_id, offset, &chunk);
+                    net.flush(channel)?;
+                }
+                Some((_, Packet::FileChunk(_, _, _, _, _))) => {}
+                Some((_, Packet::FileCancel(from, to, file_id))) if to == local_id => {
+                    let net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.cancelled(&net, from, file_id);
+                }
+                Some((_, Packet::FileCancel(_, _, _))) => {}
+                Some((src_mac, Packet::ListReq(from, to, path))) if to == local_id => {
+                    let packet = match shares.list(&path) {
+                        Ok((entries, truncated)) => {
+                            Packet::Listing(local_id, from, path, truncated, entries)
+                        }
+                        Err(err) => Packet::ShareError(local_id, from, path, err),
+                    };
+                    channel.send_to(packet, src_mac)?;
+                }
+                Some((_, Packet::ListReq(_, _, _))) => {}
+                Some((_, Packet::Listing(from, to, path, truncated, entries)))
+                    if to == local_id =>
+                {
+                    tx.try_send(UICommand::Listing(
+                        name_of(&from, &online),
+                        path,
+                        truncated,
+                        entries,
+                    ))
+                    .unwrap();
+                }
+                Some((_, Packet::Listing(_, _, _, _, _))) => {}
+                Some((src_mac, Packet::GetReq(from, to, file_id, path))) if to == local_id => {
+                    let mut net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.asked_for(&mut net, src_mac, &shares, from, file_id, path);
+                    net.flush(channel)?;
+                }
+                Some((_, Packet::GetReq(_, _, _, _))) => {}
+                Some((_, Packet::ShareError(from, to, path, reason))) if to == local_id => {
+                    let net = Net::new(local_id, &tx, &macs, &online);
+                    transfers.refused(&net, from, path, reason);
+                }
+                Some((_, Packet::ShareError(_, _, _, _))) => {}
+                Some((_, Packet::Disconnect(id))) => {
+                    if let Some(peer) = online.get(&id) {
+                        let display = display_name(&id, &peer.username, &online);
+                        let username = peer.username.clone();
+                        if use_mailbox && id != local_id {
+                            mailbox.away(id, username.clone(), macs.get(&id).copied());
+                        }
+                        online.remove(&id);
+                        macs.remove(&id);
+                        tx.try_send(UICommand::RemovePresence(id, display)).unwrap();
+                        refresh_confusable(&tx, &online, &id, &[&username]);
                     }
                 }
                 None => {}
             }
 
+            for pending in pending.values_mut() {
+                if !pending.timed_out && pending.sent.elapsed() > ECHO_TIMEOUT {
+                    pending.timed_out = true;
+                    echo(
+                        &tx,
+                        &pending.packet,
+                        &local_username,
+                        &online,
+                        &mailbox,
+                        Delivery::NotEchoed,
+                    );
+                }
+            }
+
+            let mut net = Net::new(local_id, &tx, &macs, &online);
+            transfers.pump(&mut net);
+            net.flush(channel)?;
+
+            backlogs.collect_due(&Net::new(local_id, &tx, &macs, &online));
+
+            let mut net = Net::new(local_id, &tx, &macs, &online);
+            // They've probably forgotten about us while they were gone, so
+            // say who it's from.
+            mailbox.deliver(&mut net, || {
+                Packet::Presence(
+                    local_id,
+                    false,
+                    local_username.clone(),
+                    local_rooms.clone(),
+                    local_availability,
+                    local_status.clone(),
+                    shares.names(),
+                )
+            });
+            net.flush(channel)?;
+
+            if last_receipts.elapsed() > RECEIPT_INTERVAL {
+                for (author, (delivered, read)) in receipts.iter_mut() {
+                    let delivered: Vec<Id> = delivered
+                        .drain(..delivered.len().min(MAX_RECEIPT_IDS))
+                        .collect();
+                    let read: Vec<Id> = read.drain(..read.len().min(MAX_RECEIPT_IDS)).collect();
+                    if delivered.is_empty() && read.is_empty() {
+                        continue;
+                    }
+                    let packet = Packet::Receipt(local_id, *author, delivered, read);
+                    channel.send_to(packet.clone(), destination(&packet, &macs))?;
+                }
+                receipts.retain(|_, (delivered, read)| !delivered.is_empty() || !read.is_empty());
+                last_receipts = Instant::now();
+            }
+
             if last_heartbeat.elapsed() > HEARTBEAT_INTERVAL && state == NetThreadState::Ready {
                 if !pause_heartbeat {
-                    channel.send(Packet::Presence(local_id, false, local_username.clone()))?;
+                    channel.send(Packet::Presence(
+                        local_id,
+                        false,
+                        local_username.clone(),
+                        local_rooms.clone(),
+                        local_availability,
+                        local_status.clone(),
+                        shares.names(),
+                    ))?;
                 }
 
                 let mut to_remove = vec![];
-                for (id, (last_heartbeat, username)) in online.iter() {
-                    if last_heartbeat.elapsed() > OFFLINE_TIMEOUT {
+                for (id, peer) in online.iter() {
+                    let display = display_name(id, &peer.username, &online);
+                    if peer.last_heartbeat.elapsed() > OFFLINE_TIMEOUT {
                         offline.insert(*id);
-                        tx.try_send(UICommand::RemovePresence(*id, username.clone()))
+                        tx.try_send(UICommand::RemovePresence(*id, display))
                             .unwrap();
                         to_remove.push(*id);
-                    } else if last_heartbeat.elapsed() > INACTIVE_TIMEOUT {
+                    } else if peer.last_heartbeat.elapsed() > INACTIVE_TIMEOUT {
                         tx.try_send(UICommand::PresenceUpdate(
                             *id,
-                            username.clone(),
+                            display,
                             true,
                             UpdatePresenceKind::Boring,
+                            peer.rooms.clone(),
+                            peer.availability,
+                            peer.status.clone(),
                         ))
                         .unwrap();
                     }
                 }
                 for id in to_remove {
-                    online.remove(&id);
+                    let mac = macs.remove(&id);
+                    if let Some(peer) = online.remove(&id) {
+                        if use_mailbox {
+                            mailbox.away(id, peer.username.clone(), mac);
+                        }
+                        refresh_confusable(&tx, &online, &id, &[&peer.username]);
+                    }
                 }
+                flood_guard.prune();
+                mailbox.expire();
+
+                transfers.expire(&Net::new(local_id, &tx, &macs, &online));
 
                 last_heartbeat = Instant::now();
             }
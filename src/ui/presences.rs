@@ -0,0 +1,72 @@
+use cursive::views::SelectView;
+use cursive::Cursive;
+
+use crate::net::Id;
+
+use super::util::{remove_presence, update_presence};
+
+struct Entry {
+    label: String,
+    rooms: Vec<String>,
+}
+
+/// Rooms list the people in them, anything else lists everyone.
+fn is_visible(rooms: &[String], buffer: &str) -> bool {
+    !buffer.starts_with('#') || rooms.iter().any(|room| room == buffer)
+}
+
+/// Everyone online and the rooms they're in. The "online users" panel only
+/// shows the ones in whichever buffer is active.
+pub struct Presences {
+    peers: Vec<(Id, Entry)>,
+}
+
+impl Presences {
+    pub fn new() -> Self {
+        Self { peers: vec![] }
+    }
+
+    /// Returns the rooms they were in before, if we already knew about them.
+    pub fn update(
+        &mut self,
+        siv: &mut Cursive,
+        id: Id,
+        label: String,
+        rooms: Vec<String>,
+        buffer: &str,
+    ) -> Option<Vec<String>> {
+        if is_visible(&rooms, buffer) {
+            update_presence(siv, &id, label.clone());
+        } else {
+            remove_presence(siv, &id);
+        }
+
+        let entry = Entry { label, rooms };
+        match self.peers.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, old)) => Some(std::mem::replace(old, entry).rooms),
+            None => {
+                self.peers.push((id, entry));
+                None
+            }
+        }
+    }
+
+    /// Returns the rooms they were in, if we knew about them.
+    pub fn remove(&mut self, siv: &mut Cursive, id: &Id) -> Option<Vec<String>> {
+        remove_presence(siv, id);
+        let index = self.peers.iter().position(|(other, _)| other == id)?;
+        Some(self.peers.remove(index).1.rooms)
+    }
+
+    /// Fill the panel with the people in a buffer that was just switched to.
+    pub fn show(&self, siv: &mut Cursive, buffer: &str) {
+        siv.call_on_name("presences", |presences: &mut SelectView<Id>| {
+            presences.clear();
+            for (id, entry) in &self.peers {
+                if is_visible(&entry.rooms, buffer) {
+                    presences.add_item(entry.label.clone(), *id);
+                }
+            }
+        });
+    }
+}
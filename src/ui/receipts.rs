@@ -0,0 +1,171 @@
+use std::collections::{HashMap, VecDeque};
+
+use cursive::backends::crossterm::crossterm::style::Stylize;
+
+use crate::net::Id;
+
+/// How many of our own messages to keep track of.
+const MAX_TRACKED: usize = 500;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Waiting,
+    Delivered,
+    Read,
+}
+
+struct Tracked {
+    /// Where it's shown, once it's been echoed.
+    buffer: Option<String>,
+    msg: String,
+    recipients: Vec<(Id, String, Status)>,
+}
+
+impl Tracked {
+    fn summary(&self) -> String {
+        if self.buffer.is_none() || self.recipients.is_empty() {
+            return "".to_string();
+        }
+        let read = self
+            .recipients
+            .iter()
+            .filter(|(_, _, status)| *status == Status::Read)
+            .count();
+        format!(" seen by {read}/{}", self.recipients.len())
+            .dark_grey()
+            .to_string()
+    }
+
+    fn details(&self) -> String {
+        let names = |status: Status| {
+            let names: Vec<&str> = self
+                .recipients
+                .iter()
+                .filter(|(_, _, other)| *other == status)
+                .map(|(_, name, _)| name.as_str())
+                .collect();
+            match names.as_slice() {
+                [] => "nobody".to_string(),
+                names => names.join(", "),
+            }
+        };
+        format!(
+            "read: {}\nreceived: {}\nnot received: {}",
+            names(Status::Read),
+            names(Status::Delivered),
+            names(Status::Waiting)
+        )
+    }
+}
+
+/// Who's gotten and read our messages, and which of everyone else's we
+/// still have to say we've read.
+pub struct Receipts {
+    tracked: HashMap<Id, Tracked>,
+    order: VecDeque<Id>,
+    unread: HashMap<String, Vec<(Id, Id)>>,
+}
+
+impl Receipts {
+    pub fn new() -> Self {
+        Self {
+            tracked: HashMap::new(),
+            order: VecDeque::new(),
+            unread: HashMap::new(),
+        }
+    }
+
+    pub fn expect(&mut self, msg_id: Id, recipients: Vec<(Id, String)>) {
+        if self.order.len() >= MAX_TRACKED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.tracked.remove(&oldest);
+            }
+        }
+        self.order.push_back(msg_id);
+        self.tracked.insert(
+            msg_id,
+            Tracked {
+                buffer: None,
+                msg: String::new(),
+                recipients: recipients
+                    .into_iter()
+                    .map(|(id, name)| (id, name, Status::Waiting))
+                    .collect(),
+            },
+        );
+    }
+
+    pub fn is_ours(&self, msg_id: &Id) -> bool {
+        self.tracked.contains_key(msg_id)
+    }
+
+    /// Remember where one of our messages is shown and what it says.
+    pub fn shown(&mut self, msg_id: &Id, buffer: &str, msg: &str) {
+        if let Some(tracked) = self.tracked.get_mut(msg_id) {
+            tracked.buffer = Some(buffer.to_string());
+            tracked.msg = msg.to_string();
+        }
+    }
+
+    /// What goes after one of our messages once it's shown, like "seen by
+    /// 2/3". Empty for anything else.
+    pub fn summary(&self, msg_id: &Id) -> String {
+        self.tracked
+            .get(msg_id)
+            .map(|tracked| tracked.summary())
+            .unwrap_or_default()
+    }
+
+    /// Returns the shown messages whose summary changed.
+    pub fn update(&mut self, from: Id, name: &str, delivered: &[Id], read: &[Id]) -> Vec<Id> {
+        let mut changed = vec![];
+        let updates = delivered
+            .iter()
+            .map(|msg_id| (msg_id, Status::Delivered))
+            .chain(read.iter().map(|msg_id| (msg_id, Status::Read)));
+        for (msg_id, status) in updates {
+            let Some(tracked) = self.tracked.get_mut(msg_id) else {
+                continue;
+            };
+            match tracked.recipients.iter_mut().find(|(id, _, _)| *id == from) {
+                Some((_, _, old)) => *old = status.max(*old),
+                // They showed up after we sent it.
+                None => tracked.recipients.push((from, name.to_string(), status)),
+            }
+            if tracked.buffer.is_some() && !changed.contains(msg_id) {
+                changed.push(*msg_id);
+            }
+        }
+        changed
+    }
+
+    /// Note someone else's message so we can say we've read it once its
+    /// buffer is looked at.
+    pub fn received(&mut self, buffer: &str, author: Id, msg_id: Id) {
+        self.unread
+            .entry(buffer.to_string())
+            .or_default()
+            .push((author, msg_id));
+    }
+
+    pub fn take_unread(&mut self, buffer: &str) -> Vec<(Id, Id)> {
+        self.unread.remove(buffer).unwrap_or_default()
+    }
+
+    /// Our messages in a buffer, newest first, with who's seen each one.
+    pub fn details(&self, buffer: &str) -> Vec<(String, String)> {
+        self.order
+            .iter()
+            .rev()
+            .filter_map(|msg_id| self.tracked.get(msg_id))
+            .filter(|tracked| tracked.buffer.as_deref() == Some(buffer))
+            .map(|tracked| {
+                let mut label: String = tracked.msg.chars().take(40).collect();
+                if label.len() < tracked.msg.len() {
+                    label += "...";
+                }
+                (label + &tracked.summary(), tracked.details())
+            })
+            .collect()
+    }
+}
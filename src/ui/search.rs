@@ -0,0 +1,195 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime, TimeZone};
+use cursive::backends::crossterm::crossterm::style::Stylize;
+
+use crate::net::Id;
+
+use super::messages::{Messages, Record};
+
+/// Turn something like `14:30`, `2024-01-02`, or `2h` (two hours ago) into
+/// a time.
+fn parse_time(time: &str) -> Option<DateTime<Local>> {
+    if let Ok(time) = NaiveTime::parse_from_str(time, "%H:%M") {
+        return Local
+            .from_local_datetime(&Local::now().date_naive().and_time(time))
+            .earliest();
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Local
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+            .earliest();
+    }
+    let unit = time.chars().last()?;
+    // Small enough that none of these overflow.
+    let count: u32 = time[..time.len() - unit.len_utf8()].parse().ok()?;
+    let ago = match unit {
+        'm' => Duration::minutes(count.into()),
+        'h' => Duration::hours(count.into()),
+        'd' => Duration::days(count.into()),
+        _ => return None,
+    };
+    Local::now().checked_sub_signed(ago)
+}
+
+/// What to look for in the scrollback: words in messages, and who said them
+/// and when.
+pub struct Query {
+    /// Lowercased, so matching ignores case.
+    text: Vec<char>,
+    from: Option<String>,
+    after: Option<DateTime<Local>>,
+    before: Option<DateTime<Local>>,
+}
+
+impl Query {
+    /// Parse something like `disk full from:alice after:14:00`.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut words = vec![];
+        let (mut from, mut after, mut before) = (None, None, None);
+        for word in input.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", name)) if !name.is_empty() => from = Some(name.to_lowercase()),
+                Some(("after", time)) => {
+                    after = Some(parse_time(time).ok_or_else(|| format!("what time is {time}?"))?);
+                }
+                Some(("before", time)) => {
+                    before = Some(parse_time(time).ok_or_else(|| format!("what time is {time}?"))?);
+                }
+                _ => words.push(word),
+            }
+        }
+        Ok(Self {
+            text: words.join(" ").to_lowercase().chars().collect(),
+            from,
+            after,
+            before,
+        })
+    }
+
+    /// The words to look for, lowercased.
+    pub fn text(&self) -> &[char] {
+        &self.text
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        !record.deleted
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| record.username.to_lowercase().contains(from))
+            && self.after.is_none_or(|after| record.time >= after)
+            && self.before.is_none_or(|before| record.time < before)
+            && (self.text.is_empty() || !matches(&record.msg, &self.text).is_empty())
+    }
+}
+
+/// Where `text`, which is lowercase, turns up in `msg`, ignoring case, as
+/// byte ranges.
+fn matches(msg: &str, text: &[char]) -> Vec<(usize, usize)> {
+    let mut found = vec![];
+    let mut skip_to = 0;
+    for (start, _) in msg.char_indices() {
+        if start < skip_to {
+            continue;
+        }
+        let mut wanted = text.iter();
+        let mut end = start;
+        let mut rest = msg[start..].chars();
+        let matched = loop {
+            if wanted.as_slice().is_empty() {
+                break true;
+            }
+            let Some(c) = rest.next() else {
+                break false;
+            };
+            if !c.to_lowercase().all(|c| wanted.next() == Some(&c)) {
+                break false;
+            }
+            end += c.len_utf8();
+        };
+        if matched && end > start {
+            found.push((start, end));
+            skip_to = end;
+        }
+    }
+    found
+}
+
+/// A message with whatever's been searched for picked out, more so if it's
+/// the match we're on.
+pub fn highlight(msg: &str, text: &[char], current: bool) -> String {
+    let mut highlighted = String::new();
+    let mut last = 0;
+    for (start, end) in matches(msg, text) {
+        highlighted += &msg[last..start];
+        highlighted += &match current {
+            true => msg[start..end].black().on_yellow().to_string(),
+            false => msg[start..end].reverse().to_string(),
+        };
+        last = end;
+    }
+    highlighted + &msg[last..]
+}
+
+/// A search of one buffer's scrollback, and which of the messages it found
+/// we're looking at.
+pub struct Search {
+    pub buffer: String,
+    /// What was typed, so it can be searched for again.
+    pub input: String,
+    pub query: Query,
+    /// Oldest first.
+    results: Vec<Id>,
+    current: usize,
+}
+
+impl Search {
+    /// Search a buffer, starting at the newest match.
+    pub fn new(buffer: &str, input: &str, query: Query, messages: &Messages) -> Self {
+        let mut results: Vec<Id> = messages
+            .recent(buffer, usize::MAX)
+            .into_iter()
+            .filter(|(_, record)| query.matches(record))
+            .map(|(msg_id, _)| msg_id)
+            .collect();
+        results.reverse();
+        Self {
+            buffer: buffer.to_string(),
+            input: input.to_string(),
+            query,
+            current: results.len().saturating_sub(1),
+            results,
+        }
+    }
+
+    pub fn results(&self) -> &[Id] {
+        &self.results
+    }
+
+    pub fn current(&self) -> Option<Id> {
+        self.results.get(self.current).copied()
+    }
+
+    /// Which match we're on, from one, and how many there are.
+    pub fn position(&self) -> (usize, usize) {
+        (self.current + 1, self.results.len())
+    }
+
+    /// Move to a newer match, or an older one. Returns the one we were on
+    /// before and the one we're on now, unless there's nowhere to go.
+    pub fn step(&mut self, newer: bool) -> Option<(Id, Id)> {
+        let before = self.current()?;
+        self.current = match newer {
+            true if self.current + 1 < self.results.len() => self.current + 1,
+            false if self.current > 0 => self.current - 1,
+            _ => return None,
+        };
+        Some((before, self.current()?))
+    }
+
+    /// Move to a particular match. Returns the one we were on before.
+    pub fn select(&mut self, msg_id: &Id) -> Option<Id> {
+        let before = self.current()?;
+        self.current = self.results.iter().position(|other| other == msg_id)?;
+        Some(before)
+    }
+}
@@ -0,0 +1,428 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::ArpchatError;
+use crate::net::ShareEntry;
+
+use super::config::{SharedFolder, CONFIG};
+use super::util::format_size;
+
+/// Biggest file anyone can fetch from a share that doesn't say otherwise.
+const DEFAULT_MAX_SIZE_MB: u64 = 100;
+
+/// Roughly how much of a listing goes in one reply. Packets can't be much
+/// more than 60KiB.
+const MAX_LISTING_SIZE: usize = 16 * 1024;
+
+struct Share {
+    name: String,
+    root: PathBuf,
+    max_size_mb: Option<u64>,
+}
+
+impl Share {
+    fn max_size(&self) -> u64 {
+        self.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) * 1024 * 1024
+    }
+
+    /// Where something in the share really is, as long as it's really in
+    /// the share. Symlinks could point anywhere.
+    fn locate(&self, parts: &[&str]) -> Result<(PathBuf, PathBuf), String> {
+        let root = self
+            .root
+            .canonicalize()
+            .map_err(|_| format!("{} isn't there anymore", self.name))?;
+        let path = parts
+            .iter()
+            .fold(root.clone(), |path, part| path.join(part));
+        match path.canonicalize() {
+            Ok(path) if path.starts_with(&root) => Ok((root, path)),
+            _ => Err("there's nothing there".to_string()),
+        }
+    }
+
+    /// Open a file in the share. Something could get swapped for a symlink
+    /// between finding it and opening it, so once it's open, make sure
+    /// it's still what's really in the share. Only plain files get opened,
+    /// since opening a FIFO blocks and opening a device could do anything.
+    fn open(&self, parts: &[&str]) -> Result<File, String> {
+        let (_, path) = self.locate(parts)?;
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => return Err("that's a folder, try /ls".to_string()),
+            Ok(meta) if meta.is_file() => {}
+            _ => return Err("there's nothing there".to_string()),
+        }
+        let file = open_file(&path).map_err(|_| "there's nothing there".to_string())?;
+        let (_, path) = self.locate(parts)?;
+        match is_same_file(&file, &path) {
+            true => Ok(file),
+            false => Err("there's nothing there".to_string()),
+        }
+    }
+}
+
+/// Open without waiting, in case it got swapped for a FIFO after we checked.
+#[cfg(target_os = "linux")]
+fn open_file(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_file(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// There's no telling elsewhere, so hope for the best.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// Split a path someone asked for into its parts, refusing anything that
+/// could climb out of a share or get at hidden files.
+fn split_path(path: &str) -> Result<Vec<&str>, String> {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    let sneaky = parts.iter().any(|part| {
+        part.starts_with('.') || part.contains(['\\', ':']) || part.chars().any(char::is_control)
+    });
+    match sneaky {
+        true => Err("there's nothing there".to_string()),
+        false => Ok(parts),
+    }
+}
+
+/// Folders we're letting everyone list and fetch files from. Nothing in
+/// them can be changed, and hidden files aren't shared. Mirrored to the
+/// config so it survives restarts.
+pub struct Shares {
+    shares: Vec<Share>,
+}
+
+impl Shares {
+    pub fn load() -> Self {
+        let shares = CONFIG
+            .lock()
+            .unwrap()
+            .shares
+            .iter()
+            .map(|share| Share {
+                name: share.name.clone(),
+                root: PathBuf::from(&share.path),
+                max_size_mb: share.max_size_mb,
+            })
+            .collect();
+        Self { shares }
+    }
+
+    /// Write what we're sharing back to the config.
+    pub fn save(&self) -> Result<(), ArpchatError> {
+        let mut config = CONFIG.lock().unwrap();
+        config.shares = self
+            .shares
+            .iter()
+            .map(|share| SharedFolder {
+                name: share.name.clone(),
+                path: share.root.to_string_lossy().to_string(),
+                max_size_mb: share.max_size_mb,
+            })
+            .collect();
+        config.save()
+    }
+
+    /// Share a folder. Returns what everyone else will see it as.
+    pub fn add(&mut self, path: &Path) -> Result<String, String> {
+        let root = path
+            .canonicalize()
+            .map_err(|err| format!("couldn't open {}: {err}", path.display()))?;
+        if !root.is_dir() {
+            return Err(format!("{} isn't a folder", path.display()));
+        }
+        if let Some(share) = self.shares.iter().find(|share| share.root == root) {
+            return Err(format!("you're already sharing that as {}", share.name));
+        }
+
+        let base = root
+            .file_name()
+            .map(|name| name.to_string_lossy().replace(['\\', ':'], "_"))
+            .filter(|name| !name.starts_with('.'))
+            .unwrap_or_else(|| "share".to_string());
+        let mut name = base.clone();
+        let mut n = 2;
+        while self.shares.iter().any(|share| share.name == name) {
+            name = format!("{base}-{n}");
+            n += 1;
+        }
+
+        self.shares.push(Share {
+            name: name.clone(),
+            root,
+            max_size_mb: None,
+        });
+        Ok(name)
+    }
+
+    /// Returns whether we were sharing anything by that name.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.shares.len();
+        self.shares.retain(|share| share.name != name);
+        self.shares.len() != before
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.shares.iter().map(|share| share.name.clone()).collect()
+    }
+
+    /// Each share, where it is, and how big a file it'll give out.
+    pub fn describe(&self) -> Vec<String> {
+        self.shares
+            .iter()
+            .map(|share| {
+                format!(
+                    "{} ({}, up to {})",
+                    share.name,
+                    share.root.display(),
+                    format_size(share.max_size())
+                )
+            })
+            .collect()
+    }
+
+    /// Which share a path's in, and the rest of the path. If it doesn't
+    /// start with a share's name and there's only the one, it's in that.
+    fn find<'a, 'b>(&self, parts: &'a [&'b str]) -> Option<(&Share, &'a [&'b str])> {
+        let (first, rest) = parts.split_first()?;
+        if let Some(share) = self.shares.iter().find(|share| share.name == *first) {
+            return Some((share, rest));
+        }
+        match self.shares.as_slice() {
+            [share] => Some((share, parts)),
+            _ => None,
+        }
+    }
+
+    /// What's in a folder, folders first, and whether any of it was left
+    /// out. The top level is the shares themselves.
+    pub fn list(&self, path: &str) -> Result<(Vec<ShareEntry>, bool), String> {
+        let parts = split_path(path)?;
+        if parts.is_empty() {
+            let entries = self
+                .shares
+                .iter()
+                .map(|share| ShareEntry {
+                    name: share.name.clone(),
+                    is_dir: true,
+                    size: 0,
+                })
+                .collect();
+            return Ok((entries, false));
+        }
+
+        let (share, rest) = self
+            .find(&parts)
+            .ok_or_else(|| "there's nothing there".to_string())?;
+        let (root, dir) = share.locate(rest)?;
+        if !dir.is_dir() {
+            return Err("that's a file, try /get".to_string());
+        }
+
+        let mut entries = vec![];
+        let read_dir = fs::read_dir(&dir).map_err(|_| "couldn't look in there".to_string())?;
+        for entry in read_dir.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let Ok(path) = entry.path().canonicalize() else {
+                continue;
+            };
+            if !path.starts_with(&root) {
+                continue;
+            }
+            match fs::metadata(&path) {
+                Ok(meta) if meta.is_dir() => entries.push(ShareEntry {
+                    name,
+                    is_dir: true,
+                    size: 0,
+                }),
+                Ok(meta) if meta.is_file() && meta.len() <= share.max_size() => {
+                    entries.push(ShareEntry {
+                        name,
+                        is_dir: false,
+                        size: meta.len(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        let mut size = 0;
+        let kept = entries
+            .iter()
+            .take_while(|entry| {
+                size += entry.serialize().len();
+                size <= MAX_LISTING_SIZE
+            })
+            .count();
+        let truncated = kept < entries.len();
+        entries.truncate(kept);
+        Ok((entries, truncated))
+    }
+
+    /// A file someone asked for, already open, with its name and size.
+    pub fn resolve(&self, path: &str) -> Result<(File, String, u64), String> {
+        let parts = split_path(path)?;
+        let (share, rest) = self
+            .find(&parts)
+            .ok_or_else(|| "there's nothing there".to_string())?;
+        let Some(name) = rest.last() else {
+            return Err("that's a folder, try /ls".to_string());
+        };
+        let file = share.open(rest)?;
+        let meta = file
+            .metadata()
+            .map_err(|_| "there's nothing there".to_string())?;
+        if meta.is_dir() {
+            return Err("that's a folder, try /ls".to_string());
+        }
+        if !meta.is_file() {
+            return Err("there's nothing there".to_string());
+        }
+        if meta.len() > share.max_size() {
+            return Err(format!(
+                "it's over the {} limit",
+                format_size(share.max_size())
+            ));
+        }
+        Ok((file, name.to_string(), meta.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A share called "stuff" with a couple of files in it, and a secret
+    /// file next to it that shouldn't be reachable.
+    fn setup(name: &str) -> (PathBuf, Shares) {
+        let dir = std::env::temp_dir().join(format!("arpchat-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("stuff");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("sub").join("b.txt"), "b").unwrap();
+        fs::write(root.join(".hidden"), "hidden").unwrap();
+        fs::write(dir.join("outside").join("secret.txt"), "secret").unwrap();
+
+        let shares = Shares {
+            shares: vec![Share {
+                name: "stuff".to_string(),
+                root,
+                max_size_mb: None,
+            }],
+        };
+        (dir, shares)
+    }
+
+    #[test]
+    fn split_path_refuses_sneaky_parts() {
+        assert_eq!(split_path("a/b.txt").unwrap(), ["a", "b.txt"]);
+        assert_eq!(split_path("/a//b.txt/").unwrap(), ["a", "b.txt"]);
+        for path in [
+            "..",
+            "../etc/passwd",
+            "a/../../b",
+            ".hidden",
+            "a/.git/config",
+            "..\\..\\b",
+            "a\\b",
+            "C:",
+            "a/\u{7f}b",
+        ] {
+            assert!(split_path(path).is_err(), "{path:?} got through");
+        }
+    }
+
+    #[test]
+    fn files_inside_resolve() {
+        let (dir, shares) = setup("inside");
+        let (_, name, size) = shares.resolve("stuff/a.txt").unwrap();
+        assert_eq!((name.as_str(), size), ("a.txt", 1));
+        // With only the one share, its name can be left off.
+        assert!(shares.resolve("sub/b.txt").is_ok());
+        assert!(shares.resolve("stuff/sub").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_outside_resolves() {
+        let (dir, shares) = setup("outside");
+        for path in [
+            "../outside/secret.txt",
+            "stuff/../outside/secret.txt",
+            "/etc/passwd",
+            "stuff/.hidden",
+            ".hidden",
+        ] {
+            assert!(shares.resolve(path).is_err(), "{path:?} got through");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_dont_resolve() {
+        use std::os::unix::fs::symlink;
+
+        let (dir, shares) = setup("symlinks");
+        let root = dir.join("stuff");
+        symlink(dir.join("outside").join("secret.txt"), root.join("link")).unwrap();
+        symlink(dir.join("outside"), root.join("door")).unwrap();
+        symlink(root.join("a.txt"), root.join("fine")).unwrap();
+
+        assert!(shares.resolve("link").is_err());
+        assert!(shares.resolve("door/secret.txt").is_err());
+        assert!(shares.list("door").is_err());
+        assert!(shares.resolve("fine").is_ok());
+
+        let (entries, _) = shares.list("stuff").unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["sub", "a.txt", "fine"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fifos_dont_resolve() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (dir, shares) = setup("fifos");
+        let pipe = dir.join("stuff").join("pipe");
+        let pipe_c = CString::new(pipe.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(pipe_c.as_ptr(), 0o644) }, 0);
+
+        // Would block forever if it got opened.
+        assert!(shares.resolve("pipe").is_err());
+        let (entries, _) = shares.list("stuff").unwrap();
+        assert!(entries.iter().all(|entry| entry.name != "pipe"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
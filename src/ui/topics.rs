@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local, TimeZone};
+use crossbeam_channel::Sender;
+use cursive::views::{HideableView, NamedView, Panel, ResizedView, ScrollView, TextView};
+use cursive::Cursive;
+
+use crate::net::{BacklogEntry, Id, Packet, Topic};
+use crate::ringbuffer::Ringbuffer;
+
+use super::messages::snippet;
+use super::net_thread::Net;
+use super::util::{format_message, Delivery};
+use super::UICommand;
+
+/// Most messages that can be pinned in a room. Pinning another lets go of
+/// the oldest.
+const MAX_PINS: usize = 20;
+
+/// How far ahead of our clock someone else's can be before their changes
+/// to a room stop counting.
+const MAX_CLOCK_SKEW_MS: i64 = 5 * 60 * 1000;
+
+pub type PinsPanel = HideableView<ResizedView<Panel<ScrollView<NamedView<TextView>>>>>;
+
+fn local_time(time: i64) -> DateTime<Local> {
+    Local
+        .timestamp_opt(time, 0)
+        .single()
+        .unwrap_or_else(Local::now)
+}
+
+/// What a topic is and who set it, for a notice.
+pub fn describe(topic: &Topic) -> String {
+    let time = local_time(topic.time).format("%Y-%m-%d %H:%M");
+    match topic.text.is_empty() {
+        true => format!("{} cleared the topic at {time}", topic.username),
+        false => format!("topic: {}, set by {} at {time}", topic.text, topic.username),
+    }
+}
+
+/// The topic and pinned messages of each room we're in, as last announced.
+/// The pins panel shows the active room's when it's open.
+pub struct Topics {
+    rooms: HashMap<String, (Option<Topic>, Vec<BacklogEntry>)>,
+}
+
+impl Topics {
+    pub fn new() -> Self {
+        Self {
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Returns what the topic was before.
+    pub fn set(
+        &mut self,
+        room: String,
+        topic: Option<Topic>,
+        pins: Vec<BacklogEntry>,
+    ) -> Option<Topic> {
+        self.rooms.insert(room, (topic, pins))?.0
+    }
+
+    pub fn remove(&mut self, room: &str) {
+        self.rooms.remove(room);
+    }
+
+    pub fn topic(&self, room: &str) -> Option<&Topic> {
+        self.rooms.get(room)?.0.as_ref()
+    }
+
+    pub fn pins(&self, room: &str) -> &[BacklogEntry] {
+        self.rooms.get(room).map_or(&[], |(_, pins)| pins)
+    }
+
+    /// What goes next to our name in the chat panel's title.
+    pub fn title(&self, room: &str) -> Option<String> {
+        self.topic(room)
+            .filter(|topic| !topic.text.is_empty())
+            .map(|topic| snippet(&topic.text, 60))
+    }
+
+    /// Fill the pins panel with a room's pins.
+    pub fn show(&self, siv: &mut Cursive, buffer: &str) {
+        let content = match self.pins(buffer) {
+            _ if !buffer.starts_with('#') => "only rooms have pins".to_string(),
+            [] => "nothing's pinned here, /pin to pin something".to_string(),
+            pins => pins
+                .iter()
+                .map(|pin| {
+                    format_message(
+                        &local_time(pin.time),
+                        &pin.author,
+                        &pin.username,
+                        &snippet(&pin.msg, 200),
+                        Delivery::Sent,
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        };
+        siv.call_on_name("pins", |view: &mut TextView| view.set_content(content));
+    }
+
+    /// Open the pins panel, or close it.
+    pub fn toggle(&self, siv: &mut Cursive, buffer: &str) {
+        self.show(siv, buffer);
+        siv.call_on_name("pins_panel", |panel: &mut PinsPanel| {
+            panel.set_visible(!panel.is_visible());
+        });
+    }
+}
+
+/// A room's topic and pins, as far as we know.
+#[derive(Default)]
+struct RoomInfo {
+    /// When it last changed, in unix milliseconds.
+    updated: i64,
+    topic: Option<Topic>,
+    pins: Vec<BacklogEntry>,
+}
+
+impl RoomInfo {
+    fn is_empty(&self) -> bool {
+        self.topic.is_none() && self.pins.is_empty()
+    }
+
+    fn packet(&self, local_id: Id, room: &str) -> Packet {
+        Packet::RoomInfo(
+            local_id,
+            room.to_string(),
+            self.updated,
+            self.topic.clone(),
+            self.pins.clone(),
+        )
+    }
+
+    /// Let everyone know we've changed it. It has to count as newer than
+    /// what it was even if someone's clock is ahead of ours.
+    fn changed(&mut self, net: &mut Net, room: &str) {
+        let now = chrono::offset::Local::now().timestamp_millis();
+        self.updated = now.max(self.updated.saturating_add(1));
+        self.show(net.tx, room);
+        net.send(self.packet(net.local_id, room));
+    }
+
+    /// Take on someone's newer version, as far as it checks out. Only they
+    /// can have set the topic in it, and they can only pin messages we saw
+    /// from whoever they say sent them.
+    fn merge(
+        &mut self,
+        from: Id,
+        updated: i64,
+        topic: Option<Topic>,
+        pins: Vec<BacklogEntry>,
+        authors: &Ringbuffer<(Id, Id)>,
+    ) {
+        let before = pins.len();
+        let mut pins: Vec<BacklogEntry> = pins
+            .into_iter()
+            .filter(|pin| self.pins.contains(pin) || authors.contains(&(pin.msg_id, pin.author)))
+            .collect();
+        let mut complete = pins.len() == before;
+        pins.drain(..pins.len().saturating_sub(MAX_PINS));
+        self.pins = pins;
+
+        match topic {
+            Some(topic) if topic.author == from => self.topic = Some(topic),
+            Some(topic) => complete &= self.topic.as_ref() == Some(&topic),
+            None => {}
+        }
+        // If some of it didn't check out, the whole thing from whoever it's
+        // really from can still come in after.
+        if complete {
+            self.updated = updated;
+        }
+    }
+
+    fn show(&self, tx: &Sender<UICommand>, room: &str) {
+        tx.try_send(UICommand::RoomInfo(
+            room.to_string(),
+            self.topic.clone(),
+            self.pins.clone(),
+        ))
+        .unwrap();
+    }
+}
+
+/// The topic and pins of each room we're in, as the net thread keeps track
+/// of them. Whoever changed them last wins.
+pub struct RoomInfos {
+    rooms: HashMap<String, RoomInfo>,
+    /// Who sent recent room messages, by message id, to check pins against.
+    authors: Ringbuffer<(Id, Id)>,
+}
+
+impl RoomInfos {
+    pub fn new() -> Self {
+        Self {
+            rooms: HashMap::new(),
+            authors: Ringbuffer::with_capacity(1000),
+        }
+    }
+
+    /// A message went by in a room, so it can be pinned.
+    pub fn saw(&mut self, msg_id: Id, author: Id) {
+        self.authors.push((msg_id, author));
+    }
+
+    pub fn part(&mut self, room: &str) {
+        self.rooms.remove(room);
+    }
+
+    /// Set a room's topic, or clear it if `text` is empty.
+    pub fn set_topic(&mut self, net: &mut Net, room: &str, text: String, username: &str) {
+        let info = self.rooms.entry(room.to_string()).or_default();
+        info.topic = Some(Topic {
+            text,
+            author: net.local_id,
+            username: username.to_string(),
+            time: chrono::offset::Local::now().timestamp(),
+        });
+        info.changed(net, room);
+    }
+
+    pub fn pin(&mut self, net: &mut Net, room: &str, entry: BacklogEntry) {
+        let info = self.rooms.entry(room.to_string()).or_default();
+        info.pins.retain(|pin| pin.msg_id != entry.msg_id);
+        info.pins.push(entry);
+        if info.pins.len() > MAX_PINS {
+            info.pins.remove(0);
+        }
+        info.changed(net, room);
+    }
+
+    pub fn unpin(&mut self, net: &mut Net, room: &str, msg_id: Id) {
+        let info = self.rooms.entry(room.to_string()).or_default();
+        info.pins.retain(|pin| pin.msg_id != msg_id);
+        info.changed(net, room);
+    }
+
+    /// Someone's told us what a room's topic and pins are. It's only taken
+    /// if it's newer than what we've got.
+    pub fn received(
+        &mut self,
+        tx: &Sender<UICommand>,
+        from: Id,
+        room: String,
+        updated: i64,
+        topic: Option<Topic>,
+        pins: Vec<BacklogEntry>,
+    ) {
+        let info = self.rooms.entry(room.clone()).or_default();
+        // Anything from too far in the future would win over every real
+        // change after it.
+        let now = chrono::offset::Local::now().timestamp_millis();
+        let from_future = updated > now + MAX_CLOCK_SKEW_MS;
+        // Anything else is old news, or our own echo.
+        if updated > info.updated && !from_future {
+            info.merge(from, updated, topic, pins, &self.authors);
+            info.show(tx, &room);
+        }
+    }
+
+    /// What someone new should hear about the rooms they're in.
+    pub fn news(&self, local_id: Id, rooms: &[String]) -> Vec<Packet> {
+        self.rooms
+            .iter()
+            .filter(|(room, info)| !info.is_empty() && rooms.contains(room))
+            .map(|(room, info)| info.packet(local_id, room))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::unbounded;
+
+    fn topic(author: Id, text: &str) -> Topic {
+        Topic {
+            text: text.to_string(),
+            author,
+            username: "someone".to_string(),
+            time: 0,
+        }
+    }
+
+    fn pin(n: u8, author: Id) -> BacklogEntry {
+        BacklogEntry {
+            msg_id: [n; 8],
+            author,
+            time: 0,
+            reply_to: None,
+            username: "someone".to_string(),
+            room: "#general".to_string(),
+            msg: "hi".to_string(),
+        }
+    }
+
+    fn now() -> i64 {
+        chrono::offset::Local::now().timestamp_millis()
+    }
+
+    #[test]
+    fn topics_only_come_from_whoever_set_them() {
+        let (tx, _rx) = unbounded();
+        let mut infos = RoomInfos::new();
+        let room = "#general".to_string();
+        let (alice, mallory) = ([1; 8], [2; 8]);
+
+        let forged = Some(topic(alice, "forged"));
+        infos.received(&tx, mallory, room.clone(), now(), forged, vec![]);
+        assert!(infos.news([0; 8], std::slice::from_ref(&room)).is_empty());
+
+        let real = Some(topic(alice, "real"));
+        infos.received(&tx, alice, room.clone(), now(), real.clone(), vec![]);
+        assert_eq!(infos.rooms[&room].topic, real);
+    }
+
+    #[test]
+    fn only_pins_of_messages_we_saw_are_taken() {
+        let (tx, _rx) = unbounded();
+        let mut infos = RoomInfos::new();
+        let room = "#general".to_string();
+        let (alice, bob) = ([1; 8], [2; 8]);
+        infos.saw([1; 8], alice);
+
+        let pins = vec![pin(1, alice), pin(2, alice), pin(1, bob)];
+        infos.received(&tx, bob, room.clone(), now(), None, pins);
+        assert_eq!(infos.rooms[&room].pins, [pin(1, alice)]);
+
+        for n in 0..MAX_PINS as u8 + 5 {
+            infos.saw([n; 8], alice);
+        }
+        let pins = (0..MAX_PINS as u8 + 5).map(|n| pin(n, alice)).collect();
+        infos.received(&tx, bob, room.clone(), now() + 1000, None, pins);
+        assert_eq!(infos.rooms[&room].pins.len(), MAX_PINS);
+    }
+
+    #[test]
+    fn nothing_from_the_future_sticks() {
+        let (tx, _rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let local_id = [1; 8];
+        let mut net = Net::new(local_id, &tx, &macs, &online);
+        let mut infos = RoomInfos::new();
+        let room = "#general".to_string();
+        let mallory = [2; 8];
+
+        let forged = Some(topic(mallory, "forever"));
+        infos.received(&tx, mallory, room.clone(), i64::MAX, forged, vec![]);
+        infos.set_topic(&mut net, &room, "ours".to_string(), "us");
+        assert_eq!(infos.rooms[&room].topic.as_ref().unwrap().text, "ours");
+
+        // Even if something got through, changing it doesn't overflow.
+        infos.rooms.get_mut(&room).unwrap().updated = i64::MAX;
+        infos.set_topic(&mut net, &room, "still ours".to_string(), "us");
+        assert_eq!(infos.rooms[&room].updated, i64::MAX);
+        assert!(matches!(
+            net.sent().last(),
+            Some((Packet::RoomInfo(from, _, i64::MAX, Some(topic), _), _))
+                if *from == local_id && topic.text == "still ours"
+        ));
+    }
+}
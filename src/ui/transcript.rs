@@ -0,0 +1,324 @@
+use std::collections::{HashSet, VecDeque};
+
+use chrono::{DateTime, Local};
+use cursive::backends::crossterm::crossterm::style::Color;
+use serde::Serialize;
+
+use crate::net::Id;
+
+use super::history::EntryKind;
+use super::messages::{Messages, Record};
+use super::util::{color_from_id, hex_id, strip_ansi, ACTION_PREFIX};
+
+/// Most lines to remember for `/save`, across every buffer.
+const MAX_LINES: usize = 50_000;
+
+/// What a transcript gets written as.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Txt,
+    Md,
+    Json,
+    Html,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "txt" | "text" => Some(Self::Txt),
+            "md" | "markdown" => Some(Self::Md),
+            "json" => Some(Self::Json),
+            "html" | "htm" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Txt => "txt",
+            Self::Md => "md",
+            Self::Json => "json",
+            Self::Html => "html",
+        }
+    }
+}
+
+/// Something that happened this session, as it was shown.
+struct Line {
+    time: DateTime<Local>,
+    kind: EntryKind,
+    buffer: String,
+    author: Id,
+    username: String,
+    msg_id: Option<Id>,
+    reply_to: Option<Id>,
+    /// The message, or the notice for anything else.
+    text: String,
+}
+
+/// A line as it goes in a JSON transcript.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    kind: EntryKind,
+    id: String,
+    username: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msg_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<String>,
+    message: String,
+    edited: bool,
+    deleted: bool,
+}
+
+/// Messages and comings and goings from this session, so they can be
+/// written out for people who weren't there. Messages are looked up again
+/// when it's written, so edits and deletes show up.
+pub struct Transcript {
+    lines: VecDeque<Line>,
+    seen: HashSet<Id>,
+    /// How many lines fell off the front.
+    dropped: usize,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            seen: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        self.lines.push_back(line);
+        if self.lines.len() > MAX_LINES {
+            if let Some(Line {
+                msg_id: Some(msg_id),
+                ..
+            }) = self.lines.pop_front()
+            {
+                self.seen.remove(&msg_id);
+            }
+            self.dropped += 1;
+        }
+    }
+
+    pub fn message(&mut self, msg_id: Id, record: &Record) {
+        if !self.seen.insert(msg_id) {
+            return;
+        }
+        self.push(Line {
+            time: record.time,
+            kind: EntryKind::Message,
+            buffer: record.buffer.clone(),
+            author: record.author,
+            username: record.username.clone(),
+            msg_id: Some(msg_id),
+            reply_to: record.reply_to,
+            text: record.msg.clone(),
+        });
+    }
+
+    /// Someone joining, leaving, or changing their name.
+    pub fn event(&mut self, buffer: &str, kind: EntryKind, id: Id, username: &str, notice: &str) {
+        self.push(Line {
+            time: Local::now(),
+            kind,
+            buffer: buffer.to_string(),
+            author: id,
+            username: username.to_string(),
+            msg_id: None,
+            reply_to: None,
+            text: notice.to_string(),
+        });
+    }
+
+    /// Everything in a buffer, written out in `format` under a title.
+    pub fn export(&self, buffer: &str, title: &str, messages: &Messages, format: Format) -> String {
+        let lines: Vec<(&Line, String, bool, bool)> = self
+            .lines
+            .iter()
+            .filter(|line| line.buffer == buffer)
+            .map(|line| {
+                let record = line.msg_id.and_then(|msg_id| messages.get(&msg_id));
+                let (text, edited, deleted) = match record {
+                    Some(record) => (record.msg.clone(), record.edited, record.deleted),
+                    None => (line.text.clone(), false, false),
+                };
+                (line, strip_ansi(&text), edited, deleted)
+            })
+            .collect();
+        let title = strip_ansi(title);
+        let note = match self.dropped {
+            0 => None,
+            _ => Some("earlier lines didn't fit in memory and were left out"),
+        };
+
+        match format {
+            Format::Txt => {
+                let mut out = format!("{title}\n");
+                out.extend(note.map(|note| format!("({note})\n")));
+                for (line, text, edited, deleted) in lines {
+                    out += &format!(
+                        "[{}] {}\n",
+                        timestamp(&line.time),
+                        txt(line, &text, edited, deleted)
+                    );
+                }
+                out
+            }
+            Format::Md => {
+                let mut out = format!("# {}\n\n", escape_md(&title));
+                out.extend(note.map(|note| format!("_{note}_\n\n")));
+                for (line, text, edited, deleted) in lines {
+                    let time = timestamp(&line.time);
+                    let username = escape_md(&strip_ansi(&line.username));
+                    let body = match line.kind {
+                        _ if deleted => format!("**{username}**: _message deleted_"),
+                        EntryKind::Message => match text.strip_prefix(ACTION_PREFIX) {
+                            Some(action) => format!("\\* **{username}** _{}_", escape_md(action)),
+                            None => format!("**{username}**: {}", escape_md(&text)),
+                        },
+                        _ => format!("_{}_", escape_md(&text)),
+                    };
+                    let edited = if edited && !deleted {
+                        " _(edited)_"
+                    } else {
+                        ""
+                    };
+                    // Two spaces on the end keep multi-line messages apart.
+                    out += &format!("`{time}` {}{edited}  \n", body.replace('\n', "  \n"));
+                }
+                out
+            }
+            Format::Json => {
+                let lines: Vec<JsonLine> = lines
+                    .iter()
+                    .map(|(line, text, edited, deleted)| JsonLine {
+                        time: line.time.to_rfc3339(),
+                        kind: line.kind,
+                        id: hex_id(&line.author),
+                        username: &line.username,
+                        msg_id: line.msg_id.as_ref().map(hex_id),
+                        reply_to: line.reply_to.as_ref().map(hex_id),
+                        message: match deleted {
+                            true => String::new(),
+                            false => text.clone(),
+                        },
+                        edited: *edited,
+                        deleted: *deleted,
+                    })
+                    .collect();
+                serde_json::to_string_pretty(&lines).unwrap_or_default() + "\n"
+            }
+            Format::Html => {
+                let title = escape_html(&title);
+                let mut out = format!(
+                    "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <title>{title}</title>\n<style>\n\
+                     body {{ font-family: monospace; background: #1e1e1e; color: #ddd; }}\n\
+                     .time, .event, .note {{ color: #888; }}\n\
+                     </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+                );
+                out.extend(note.map(|note| format!("<p class=\"note\">{note}</p>\n")));
+                for (line, text, edited, deleted) in lines {
+                    let time = timestamp(&line.time);
+                    let username = format!(
+                        "<b style=\"color: {}\">{}</b>",
+                        css_color(color_from_id(&line.author)),
+                        escape_html(&strip_ansi(&line.username))
+                    );
+                    let body = match line.kind {
+                        _ if deleted => {
+                            format!("[{username}] <span class=\"event\">message deleted</span>")
+                        }
+                        EntryKind::Message => match text.strip_prefix(ACTION_PREFIX) {
+                            Some(action) => format!("* {username} <i>{}</i>", escape_html(action)),
+                            None => format!("[{username}] {}", escape_html(&text)),
+                        },
+                        _ => format!("<span class=\"event\">&gt; {}</span>", escape_html(&text)),
+                    };
+                    let edited = match edited && !deleted {
+                        true => " <span class=\"event\">(edited)</span>",
+                        false => "",
+                    };
+                    out += &format!(
+                        "<div><span class=\"time\">{time}</span> {}{edited}</div>\n",
+                        body.replace('\n', "<br>")
+                    );
+                }
+                out + "</body>\n</html>\n"
+            }
+        }
+    }
+}
+
+/// What to call a transcript of a buffer if nobody says otherwise, like
+/// `general-20240102-150405.txt`.
+pub fn default_name(label: &str, format: Format) -> String {
+    let label: String = label
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let label = match label.is_empty() {
+        true => "chat".to_string(),
+        false => label,
+    };
+    let time = Local::now().format("%Y%m%d-%H%M%S");
+    format!("{label}-{time}.{}", format.extension())
+}
+
+fn timestamp(time: &DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// A line in a plain text transcript, after the time.
+fn txt(line: &Line, text: &str, edited: bool, deleted: bool) -> String {
+    let username = strip_ansi(&line.username);
+    let body = match line.kind {
+        _ if deleted => return format!("[{username}] message deleted"),
+        EntryKind::Message => match text.strip_prefix(ACTION_PREFIX) {
+            Some(action) => format!("* {username} {action}"),
+            None => format!("[{username}] {text}"),
+        },
+        _ => format!("> {text}"),
+    };
+    match edited {
+        true => format!("{body} (edited)"),
+        false => body,
+    }
+}
+
+fn escape_md(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Roughly what a terminal colour looks like, on a dark background.
+fn css_color(color: Color) -> &'static str {
+    match color {
+        Color::Red => "#f55",
+        Color::DarkRed => "#b22",
+        Color::Green => "#5f5",
+        Color::Yellow => "#ff5",
+        Color::Blue => "#59f",
+        Color::Magenta => "#f5f",
+        Color::Cyan => "#5ff",
+        _ => "#eee",
+    }
+}
@@ -0,0 +1,1167 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use blake2::{Blake2s256, Digest};
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use rand::Rng;
+
+use crate::net::{FileHash, Id, Packet};
+use crate::ratelimit::TokenBucket;
+
+use super::net_thread::Net;
+use super::shares::Shares;
+use super::util::{format_size, hex_id};
+use super::{NetCommand, UICommand};
+
+/// How much of a file goes in each chunk. Small enough that a chunk is well
+/// under the biggest packet we can send.
+const CHUNK_SIZE: usize = 4096;
+
+/// How far ahead of the last acknowledgement we'll send.
+const WINDOW: u64 = 16 * CHUNK_SIZE as u64;
+
+/// How often to acknowledge what's come in.
+const ACK_EVERY: u64 = 4 * CHUNK_SIZE as u64;
+
+/// Go back to the last acknowledgement if we haven't had one in this long,
+/// or nudge the sender if we haven't had a chunk.
+const RESEND_AFTER: Duration = Duration::from_secs(2);
+
+/// Give up on a transfer if it hasn't gone anywhere in this long.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Forget about offers nobody's taken up after this long.
+const OFFER_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Offers of files from a share are for whoever just asked, and anyone can
+/// ask for as many as they like, so they don't stick around.
+const SERVED_OFFER_EXPIRY: Duration = Duration::from_secs(2 * 60);
+
+/// How often to update progress bars.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How fast file chunks go out, so transfers never crowd out chat.
+const CHUNKS_PER_SECOND: f64 = 64.0;
+const CHUNK_BURST: f64 = 4.0;
+
+/// How long to wait for a file we asked for to be offered.
+const GET_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most files from our shares being hashed at once, and most waiting to be
+/// taken up. Anyone can ask, as often as they like.
+const MAX_HASHING: usize = 2;
+const MAX_SERVED_OFFERS: usize = 16;
+
+/// Most file hashes to remember, so the same file isn't hashed over and
+/// over for everyone who asks.
+const MAX_CACHED_HASHES: usize = 256;
+
+/// Hash the first `size` bytes of a file, which had better all be there.
+fn hash_file(file: &File, size: u64) -> io::Result<FileHash> {
+    let mut hasher = Blake2s256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut file = file.take(size);
+    let mut hashed = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            len => {
+                hasher.update(&buf[..len]);
+                hashed += len as u64;
+            }
+        }
+    }
+    match hashed == size {
+        true => Ok(hasher.finalize().into()),
+        false => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "it got smaller while reading it",
+        )),
+    }
+}
+
+/// What a file's hash can be remembered by: which file it is, and enough to
+/// tell if it's changed since.
+type FileKey = (u64, u64, SystemTime, u64);
+
+#[cfg(unix)]
+fn file_key(file: &File) -> Option<FileKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = file.metadata().ok()?;
+    Some((meta.dev(), meta.ino(), meta.modified().ok()?, meta.len()))
+}
+
+/// There's no telling which file is which elsewhere, so nothing's
+/// remembered.
+#[cfg(not(unix))]
+fn file_key(_file: &File) -> Option<FileKey> {
+    None
+}
+
+/// Somewhere to save a file that won't overwrite anything, named as close
+/// to what the sender called it as we can manage safely.
+fn save_path(dir: &Path, name: &str) -> PathBuf {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = match Path::new(&name).file_name().and_then(|name| name.to_str()) {
+        Some(name) if !name.starts_with('.') => name.to_string(),
+        _ => "download".to_string(),
+    };
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{ext}")),
+        _ => (name.clone(), String::new()),
+    };
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{stem} ({n}){ext}"));
+        n += 1;
+    }
+    path
+}
+
+/// A file we've offered, which anyone it was offered to can accept.
+pub struct Offer {
+    /// Opened when it was offered, so nothing can be swapped in under its
+    /// path in the meantime.
+    pub file: File,
+    pub name: String,
+    pub size: u64,
+    /// Who it was offered to, if it was direct.
+    pub to: Option<Id>,
+    pub buffer: String,
+    pub since: Instant,
+    /// Whether it's from a share, because someone asked for it.
+    pub served: bool,
+}
+
+impl Offer {
+    pub fn expired(&self) -> bool {
+        let expiry = if self.served {
+            SERVED_OFFER_EXPIRY
+        } else {
+            OFFER_EXPIRY
+        };
+        self.since.elapsed() > expiry
+    }
+}
+
+/// A file someone's offered us.
+pub struct Incoming {
+    pub from: Id,
+    pub name: String,
+    pub size: u64,
+    pub hash: FileHash,
+    pub buffer: String,
+    pub since: Instant,
+}
+
+impl Incoming {
+    pub fn expired(&self) -> bool {
+        self.since.elapsed() > OFFER_EXPIRY
+    }
+}
+
+/// One of our files on its way to someone.
+pub struct Upload {
+    file: File,
+    pub file_id: Id,
+    pub to: Id,
+    pub name: String,
+    pub size: u64,
+    pub buffer: String,
+    sent: u64,
+    acked: u64,
+    last_progress: Instant,
+    last_shown: Option<Instant>,
+}
+
+impl Upload {
+    pub fn start(file_id: Id, offer: &Offer, to: Id, offset: u64) -> io::Result<Self> {
+        let offset = offset.min(offer.size);
+        Ok(Self {
+            file: offer.file.try_clone()?,
+            file_id,
+            to,
+            name: offer.name.clone(),
+            size: offer.size,
+            buffer: offer.buffer.clone(),
+            sent: offset,
+            acked: offset,
+            last_progress: Instant::now(),
+            last_shown: None,
+        })
+    }
+
+    /// They've got everything up to `offset`. If that's behind what we've
+    /// sent, something went missing, so start again from there.
+    pub fn ack(&mut self, offset: u64) {
+        let offset = offset.min(self.size);
+        if offset > self.acked {
+            self.last_progress = Instant::now();
+        }
+        self.acked = offset;
+        if offset < self.sent {
+            self.sent = offset;
+        }
+    }
+
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    pub fn done(&self) -> bool {
+        self.acked >= self.size
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.last_progress.elapsed() > STALL_TIMEOUT
+    }
+
+    /// Whether there's anything to send right now.
+    pub fn ready(&mut self) -> bool {
+        // Whatever we sent since the last acknowledgement probably got lost.
+        if self.sent > self.acked && self.last_progress.elapsed() > RESEND_AFTER {
+            self.sent = self.acked;
+            self.last_progress = Instant::now();
+        }
+        self.sent < self.size && self.sent < self.acked + WINDOW
+    }
+
+    /// The next chunk and where it goes in the file.
+    pub fn next_chunk(&mut self) -> io::Result<(u64, Vec<u8>)> {
+        let offset = self.sent;
+        let len = (self.size - offset).min(CHUNK_SIZE as u64) as usize;
+        let mut chunk = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut chunk)?;
+        self.sent += len as u64;
+        Ok((offset, chunk))
+    }
+
+    /// Whether it's time to update the progress bar.
+    pub fn show_progress(&mut self) -> bool {
+        if self
+            .last_shown
+            .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return false;
+        }
+        self.last_shown = Some(Instant::now());
+        true
+    }
+}
+
+/// Someone else's file on its way to us, saved to a partial file in the
+/// downloads folder until it's all there. The partial file is named after
+/// the hash, so if the same file is offered again it picks up where it left
+/// off.
+pub struct Download {
+    file: File,
+    part: PathBuf,
+    hasher: Blake2s256,
+    pub file_id: Id,
+    pub from: Id,
+    pub name: String,
+    pub size: u64,
+    pub hash: FileHash,
+    pub buffer: String,
+    received: u64,
+    last_ack: Instant,
+    last_progress: Instant,
+    last_shown: Option<Instant>,
+}
+
+impl Download {
+    pub fn start(dir: &Path, file_id: Id, offer: Incoming) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let hash: String = offer
+            .hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let part = dir.join(format!(".{hash}.part"));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part)?;
+        if file.metadata()?.len() > offer.size {
+            file.set_len(0)?;
+        }
+
+        // Whatever's already there has to be hashed along with the rest.
+        let mut hasher = Blake2s256::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                len => {
+                    hasher.update(&buf[..len]);
+                    received += len as u64;
+                }
+            }
+        }
+
+        Ok(Self {
+            file,
+            part,
+            hasher,
+            file_id,
+            from: offer.from,
+            name: offer.name,
+            size: offer.size,
+            hash: offer.hash,
+            buffer: offer.buffer,
+            received,
+            last_ack: Instant::now(),
+            last_progress: Instant::now(),
+            last_shown: None,
+        })
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn done(&self) -> bool {
+        self.received >= self.size
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.last_progress.elapsed() > STALL_TIMEOUT
+    }
+
+    /// Save a chunk if it's the one we're waiting for. Returns whether to
+    /// acknowledge what we've got now.
+    pub fn write(&mut self, offset: u64, chunk: &[u8]) -> io::Result<bool> {
+        if offset != self.received || chunk.is_empty() {
+            // Out of order, so something went missing. Let them know, but
+            // not for every single chunk that comes after.
+            return Ok(self.last_ack.elapsed() > RESEND_AFTER / 4);
+        }
+        let chunk = &chunk[..chunk.len().min((self.size - self.received) as usize)];
+        self.file.write_all(chunk)?;
+        self.hasher.update(chunk);
+        let before = self.received;
+        self.received += chunk.len() as u64;
+        self.last_progress = Instant::now();
+        Ok(self.done() || self.received / ACK_EVERY != before / ACK_EVERY)
+    }
+
+    /// Whether it's been long enough without hearing anything to remind the
+    /// sender where we're up to.
+    pub fn needs_nudge(&self) -> bool {
+        self.last_ack.elapsed() > RESEND_AFTER && self.last_progress.elapsed() > RESEND_AFTER
+    }
+
+    pub fn acked(&mut self) {
+        self.last_ack = Instant::now();
+    }
+
+    pub fn show_progress(&mut self) -> bool {
+        if self
+            .last_shown
+            .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return false;
+        }
+        self.last_shown = Some(Instant::now());
+        true
+    }
+
+    /// Check it's the file we were offered and move it where it belongs.
+    /// Returns where it ended up.
+    pub fn finish(self, dir: &Path) -> Result<PathBuf, String> {
+        drop(self.file);
+        let hash: FileHash = self.hasher.finalize().into();
+        if hash != self.hash {
+            let _ = fs::remove_file(&self.part);
+            return Err(format!("{} got corrupted on the way, try again", self.name));
+        }
+        let path = save_path(dir, &self.name);
+        fs::rename(&self.part, &path)
+            .map_err(|err| format!("couldn't save {}: {err}", self.name))?;
+        Ok(path)
+    }
+}
+
+/// Offer a file for `/send`, which is a path and maybe who to send it to.
+/// It's hashed in the background and offered once that's done.
+pub fn send_file(
+    args: &str,
+    buffer: &str,
+    net_tx: &Sender<NetCommand>,
+    ui_tx: &Sender<UICommand>,
+) -> Result<(), String> {
+    // Paths can have spaces in them, so it's only someone to send it to if
+    // the whole thing isn't a file.
+    let (path, target) = match args.rsplit_once(' ') {
+        Some((path, target)) if !Path::new(args).exists() => {
+            (PathBuf::from(path), Some(target.to_string()))
+        }
+        _ => (PathBuf::from(args), None),
+    };
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match fs::metadata(&path) {
+        Ok(meta) if meta.is_file() => {}
+        Ok(_) => return Err(format!("{} isn't a file", path.display())),
+        Err(err) => return Err(format!("couldn't open {}: {err}", path.display())),
+    }
+
+    let buffer = buffer.to_string();
+    let net_tx = net_tx.clone();
+    let ui_tx = ui_tx.clone();
+    // Hashing a big file takes a while. The size comes from the same handle
+    // that's hashed, so they can't disagree if the file changes.
+    thread::spawn(move || {
+        let hashed = File::open(&path).and_then(|file| {
+            let size = file.metadata()?.len();
+            Ok((hash_file(&file, size)?, file, size))
+        });
+        match hashed {
+            Ok((hash, file, size)) => net_tx
+                .try_send(NetCommand::OfferFile(
+                    file, name, size, hash, target, buffer,
+                ))
+                .unwrap(),
+            Err(err) => ui_tx
+                .try_send(UICommand::Notice(format!("couldn't read {name}: {err}")))
+                .unwrap(),
+        }
+    });
+    Ok(())
+}
+
+fn show_upload(net: &Net, upload: &Upload) {
+    net.tx
+        .try_send(UICommand::TransferProgress(
+            upload.file_id,
+            upload.to,
+            net.name_of(&upload.to),
+            upload.buffer.clone(),
+            upload.name.clone(),
+            true,
+            upload.acked(),
+            upload.size,
+        ))
+        .unwrap();
+}
+
+fn show_download(net: &Net, download: &Download) {
+    net.tx
+        .try_send(UICommand::TransferProgress(
+            download.file_id,
+            download.from,
+            net.name_of(&download.from),
+            download.buffer.clone(),
+            download.name.clone(),
+            false,
+            download.received(),
+            download.size,
+        ))
+        .unwrap();
+}
+
+/// Check a download that's all there and save it.
+fn finish_download(net: &Net, download: Download, dir: &Path) {
+    show_download(net, &download);
+    let (file_id, from, buffer) = (download.file_id, download.from, download.buffer.clone());
+    let done = match download.finish(dir) {
+        Ok(path) => format!("saved to {}", path.display()),
+        Err(err) => err,
+    };
+    net.tx
+        .try_send(UICommand::TransferDone(file_id, from, buffer, done))
+        .unwrap();
+}
+
+/// Every file on its way in or out, and everything on offer either way.
+pub struct Transfers {
+    downloads_dir: Option<PathBuf>,
+    net_tx: Sender<NetCommand>,
+    offers: HashMap<Id, Offer>,
+    /// Files from our shares being hashed.
+    hashing: usize,
+    hash_cache: HashMap<FileKey, FileHash>,
+    incoming: HashMap<Id, Incoming>,
+    /// Taking turns, so one big file doesn't hold up everything else.
+    uploads: Vec<Upload>,
+    downloads: HashMap<Id, Download>,
+    chunk_bucket: TokenBucket,
+    /// Files we've asked for, with who from, the path, and when we asked.
+    requested: HashMap<Id, (Id, String, Instant)>,
+}
+
+impl Transfers {
+    pub fn new(downloads_dir: Option<PathBuf>, net_tx: Sender<NetCommand>) -> Self {
+        Self {
+            downloads_dir,
+            net_tx,
+            offers: HashMap::new(),
+            hashing: 0,
+            hash_cache: HashMap::new(),
+            incoming: HashMap::new(),
+            uploads: vec![],
+            downloads: HashMap::new(),
+            chunk_bucket: TokenBucket::new(CHUNKS_PER_SECOND, CHUNK_BURST),
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Offer one of our files to a room, or to whoever it's for.
+    pub fn offer(&mut self, net: &mut Net, offer: Offer, hash: FileHash) {
+        let file_id: Id = rand::thread_rng().gen();
+        let room = match offer.to {
+            Some(_) => String::new(),
+            None => offer.buffer.clone(),
+        };
+        let packet = Packet::FileOffer(
+            net.local_id,
+            offer.to,
+            file_id,
+            room,
+            offer.name.clone(),
+            offer.size,
+            hash,
+        );
+        let dest = offer
+            .to
+            .map_or_else(MacAddr::broadcast, |to| net.mac_of(&to));
+        net.send_to(packet, dest);
+
+        let who = match offer.to {
+            Some(to) => net.name_of(&to),
+            None => format!("everyone in {}", offer.buffer),
+        };
+        net.notice(format!(
+            "offered {} ({}) to {who}",
+            offer.name,
+            format_size(offer.size)
+        ));
+        self.offers.insert(file_id, offer);
+    }
+
+    /// Start downloading something we were offered.
+    pub fn accept(&mut self, net: &mut Net, file_id: Id) {
+        let Some(offer) = self.incoming.remove(&file_id) else {
+            net.notice("that file isn't on offer anymore".to_string());
+            return;
+        };
+        let from = offer.from;
+        // Partial downloads are kept by hash so they can pick up from
+        // anyone, so two at once would trample each other.
+        let busy = self
+            .downloads
+            .values()
+            .find(|download| download.hash == offer.hash);
+        let download = match (busy, &self.downloads_dir) {
+            (Some(busy), _) => Err(format!(
+                "you're already getting the same file from {}",
+                net.name_of(&busy.from)
+            )),
+            (None, Some(dir)) => {
+                Download::start(dir, file_id, offer).map_err(|err| err.to_string())
+            }
+            (None, None) => Err("there's nowhere to save it".to_string()),
+        };
+        match download {
+            Ok(mut download) => {
+                let offset = download.received();
+                net.send_to_peer(
+                    Packet::FileAccept(net.local_id, from, file_id, offset),
+                    &from,
+                );
+                download.acked();
+                match (download.done(), &self.downloads_dir) {
+                    // We already had all of it from last time.
+                    (true, Some(dir)) => finish_download(net, download, dir),
+                    _ => {
+                        show_download(net, &download);
+                        self.downloads.insert(file_id, download);
+                    }
+                }
+            }
+            Err(err) => {
+                net.notice(format!("couldn't download it: {err}"));
+                net.send_to_peer(Packet::FileCancel(net.local_id, from, file_id), &from);
+            }
+        }
+    }
+
+    pub fn decline(&mut self, net: &mut Net, file_id: Id) {
+        if let Some(offer) = self.incoming.remove(&file_id) {
+            let packet = Packet::FileCancel(net.local_id, offer.from, file_id);
+            net.send_to_peer(packet, &offer.from);
+        }
+    }
+
+    /// Ask someone for a file from their shares. It's taken as soon as
+    /// they offer it.
+    pub fn get(&mut self, net: &mut Net, to: Id, target: &str, path: String) {
+        let file_id: Id = rand::thread_rng().gen();
+        net.send_to_peer(Packet::GetReq(net.local_id, to, file_id, path.clone()), &to);
+        net.notice(format!("asked {target} for {path}"));
+        self.requested.insert(file_id, (to, path, Instant::now()));
+    }
+
+    /// Someone asked for a file from one of our shares. Once it's hashed
+    /// it's offered just to them, under the id they picked.
+    pub fn asked_for(
+        &mut self,
+        net: &mut Net,
+        src_mac: MacAddr,
+        shares: &Shares,
+        from: Id,
+        file_id: Id,
+        path: String,
+    ) {
+        let served = self.offers.values().filter(|offer| offer.served).count();
+        let resolved = if self.hashing >= MAX_HASHING || served >= MAX_SERVED_OFFERS {
+            Err("it's busy, try again in a bit".to_string())
+        } else {
+            shares.resolve(&path)
+        };
+        let (file, name, size) = match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                net.send_to(Packet::ShareError(net.local_id, from, path, err), src_mac);
+                return;
+            }
+        };
+
+        let offer = Offer {
+            file,
+            name,
+            size,
+            to: Some(from),
+            buffer: format!("@{}", hex_id(&from)),
+            since: Instant::now(),
+            served: true,
+        };
+        let cached = file_key(&offer.file).and_then(|key| self.hash_cache.get(&key).copied());
+        match cached {
+            Some(hash) => self.serve(net, file_id, path, offer, Ok(hash)),
+            None => {
+                self.hashing += 1;
+                let net_tx = self.net_tx.clone();
+                // Hashing a big file takes a while.
+                thread::spawn(move || {
+                    let hash = hash_file(&offer.file, offer.size)
+                        .map_err(|_| "couldn't read it".to_string());
+                    let _ = net_tx.try_send(NetCommand::ServeFile(file_id, path, offer, hash));
+                });
+            }
+        }
+    }
+
+    /// A file someone asked for from our shares is done hashing.
+    pub fn hashed(
+        &mut self,
+        net: &mut Net,
+        file_id: Id,
+        asked: String,
+        offer: Offer,
+        hash: Result<FileHash, String>,
+    ) {
+        self.hashing = self.hashing.saturating_sub(1);
+        if let (Ok(hash), Some(key)) = (&hash, file_key(&offer.file)) {
+            if self.hash_cache.len() >= MAX_CACHED_HASHES {
+                self.hash_cache.clear();
+            }
+            self.hash_cache.insert(key, *hash);
+        }
+        self.serve(net, file_id, asked, offer, hash);
+    }
+
+    fn serve(
+        &mut self,
+        net: &mut Net,
+        file_id: Id,
+        asked: String,
+        mut offer: Offer,
+        hash: Result<FileHash, String>,
+    ) {
+        // Whoever asked picked the id, so don't let it clobber anything.
+        let Some(to) = offer.to.filter(|_| !self.offers.contains_key(&file_id)) else {
+            return;
+        };
+        match hash {
+            Ok(hash) => {
+                let packet = Packet::FileOffer(
+                    net.local_id,
+                    Some(to),
+                    file_id,
+                    String::new(),
+                    offer.name.clone(),
+                    offer.size,
+                    hash,
+                );
+                net.send_to_peer(packet, &to);
+                offer.since = Instant::now();
+                self.offers.insert(file_id, offer);
+            }
+            Err(err) => {
+                net.send_to_peer(Packet::ShareError(net.local_id, to, asked, err), &to);
+            }
+        }
+    }
+
+    /// Someone couldn't give us what we asked for from their shares.
+    pub fn refused(&mut self, net: &Net, from: Id, path: String, reason: String) {
+        self.requested
+            .retain(|_, (peer, asked, _)| *peer != from || *asked != path);
+        net.notice(format!(
+            "{} can't share {path}: {reason}",
+            net.name_of(&from)
+        ));
+    }
+
+    /// Someone's offered us a file, which we take straight away if we asked
+    /// them for it.
+    pub fn offered(&mut self, net: &Net, file_id: Id, offer: Incoming) {
+        if self
+            .requested
+            .remove(&file_id)
+            .is_some_and(|(peer, _, _)| peer == offer.from)
+        {
+            // We asked for it, so there's no need to ask us.
+            net.tx.try_send(UICommand::AcceptFile(file_id)).unwrap();
+        } else {
+            net.tx
+                .try_send(UICommand::FileOffered(
+                    net.name_of(&offer.from),
+                    file_id,
+                    offer.buffer.clone(),
+                    offer.name.clone(),
+                    offer.size,
+                ))
+                .unwrap();
+        }
+        self.incoming.insert(file_id, offer);
+    }
+
+    /// Someone's starting on one of our files, or letting us know how much
+    /// of it they've got.
+    pub fn accepted(
+        &mut self,
+        net: &mut Net,
+        src_mac: MacAddr,
+        from: Id,
+        file_id: Id,
+        offset: u64,
+    ) {
+        let upload = self
+            .uploads
+            .iter_mut()
+            .find(|upload| upload.file_id == file_id && upload.to == from);
+        match upload {
+            Some(upload) => upload.ack(offset),
+            None => {
+                // Someone it was offered to, starting or picking back up
+                // where they left off.
+                let offer = self
+                    .offers
+                    .get(&file_id)
+                    .filter(|offer| offer.to.is_none_or(|to| to == from));
+                if let Some(offer) = offer {
+                    match Upload::start(file_id, offer, from, offset) {
+                        Ok(upload) => {
+                            show_upload(net, &upload);
+                            self.uploads.push(upload);
+                        }
+                        Err(err) => {
+                            net.notice(format!("couldn't send {}: {err}", offer.name));
+                            net.send_to(Packet::FileCancel(net.local_id, from, file_id), src_mac);
+                        }
+                    }
+                }
+            }
+        }
+
+        let done = self
+            .uploads
+            .iter()
+            .position(|upload| upload.file_id == file_id && upload.to == from && upload.done());
+        if let Some(index) = done {
+            let upload = self.uploads.remove(index);
+            show_upload(net, &upload);
+            net.tx
+                .try_send(UICommand::TransferDone(
+                    file_id,
+                    from,
+                    upload.buffer,
+                    format!("sent {} to {}", upload.name, net.name_of(&from)),
+                ))
+                .unwrap();
+        }
+    }
+
+    /// A piece of something we're downloading.
+    pub fn chunk(
+        &mut self,
+        net: &mut Net,
+        src_mac: MacAddr,
+        from: Id,
+        file_id: Id,
+        offset: u64,
+        chunk: &[u8],
+    ) {
+        let Some(download) = self
+            .downloads
+            .get_mut(&file_id)
+            .filter(|download| download.from == from)
+        else {
+            return;
+        };
+        match download.write(offset, chunk) {
+            Ok(ack) => {
+                if ack {
+                    let received = download.received();
+                    let packet = Packet::FileAccept(net.local_id, from, file_id, received);
+                    net.send_to(packet, src_mac);
+                    download.acked();
+                }
+                if download.show_progress() {
+                    show_download(net, download);
+                }
+            }
+            Err(err) => {
+                net.tx
+                    .try_send(UICommand::TransferDone(
+                        file_id,
+                        from,
+                        download.buffer.clone(),
+                        format!("couldn't save {}: {err}", download.name),
+                    ))
+                    .unwrap();
+                self.downloads.remove(&file_id);
+                net.send_to(Packet::FileCancel(net.local_id, from, file_id), src_mac);
+            }
+        }
+
+        let done = self
+            .downloads
+            .get(&file_id)
+            .is_some_and(|download| download.done());
+        if let (true, Some(dir)) = (done, &self.downloads_dir) {
+            if let Some(download) = self.downloads.remove(&file_id) {
+                finish_download(net, download, dir);
+            }
+        }
+    }
+
+    /// Someone's called off a file going either way.
+    pub fn cancelled(&mut self, net: &Net, from: Id, file_id: Id) {
+        let who = net.name_of(&from);
+        if let Some(offer) = self.incoming.remove(&file_id) {
+            net.notice(format!("{who} isn't offering {} anymore", offer.name));
+        }
+        if let Some(download) = self.downloads.remove(&file_id) {
+            net.tx
+                .try_send(UICommand::TransferDone(
+                    file_id,
+                    from,
+                    download.buffer,
+                    format!("{who} stopped sending {}", download.name),
+                ))
+                .unwrap();
+        }
+        let upload = self
+            .uploads
+            .iter()
+            .position(|upload| upload.file_id == file_id && upload.to == from);
+        match (upload, self.offers.get(&file_id)) {
+            (Some(index), _) => {
+                let upload = self.uploads.remove(index);
+                net.tx
+                    .try_send(UICommand::TransferDone(
+                        file_id,
+                        from,
+                        upload.buffer,
+                        format!("{who} stopped receiving {}", upload.name),
+                    ))
+                    .unwrap();
+            }
+            // Only worth mentioning if it was just for them.
+            (None, Some(offer)) if offer.to == Some(from) => {
+                net.notice(format!("{who} declined {}", offer.name));
+            }
+            (None, _) => {}
+        }
+    }
+
+    /// Send the next chunk of whichever upload's turn it is, if there's
+    /// room for it, and remind anyone who's gone quiet where our downloads
+    /// are up to.
+    pub fn pump(&mut self, net: &mut Net) {
+        if let Some(index) = self.uploads.iter_mut().position(|upload| upload.ready()) {
+            if self.chunk_bucket.try_take() {
+                let mut upload = self.uploads.remove(index);
+                match upload.next_chunk() {
+                    Ok((offset, chunk)) => {
+                        let packet = Packet::FileChunk(
+                            net.local_id,
+                            upload.to,
+                            upload.file_id,
+                            offset,
+                            chunk,
+                        );
+                        net.send_to_peer(packet, &upload.to);
+                        if upload.show_progress() {
+                            show_upload(net, &upload);
+                        }
+                        // To the back of the line.
+                        self.uploads.push(upload);
+                    }
+                    Err(err) => {
+                        let packet = Packet::FileCancel(net.local_id, upload.to, upload.file_id);
+                        net.send_to_peer(packet, &upload.to);
+                        net.tx
+                            .try_send(UICommand::TransferDone(
+                                upload.file_id,
+                                upload.to,
+                                upload.buffer,
+                                format!("couldn't read {}: {err}", upload.name),
+                            ))
+                            .unwrap();
+                    }
+                }
+            }
+        }
+        for download in self.downloads.values_mut() {
+            if download.needs_nudge() {
+                let packet = Packet::FileAccept(
+                    net.local_id,
+                    download.from,
+                    download.file_id,
+                    download.received(),
+                );
+                net.send_to_peer(packet, &download.from);
+                download.acked();
+            }
+        }
+    }
+
+    /// Give up on anything that's stopped going anywhere, and forget old
+    /// offers and requests.
+    pub fn expire(&mut self, net: &Net) {
+        let stalled: Vec<Upload>;
+        (stalled, self.uploads) = self.uploads.drain(..).partition(|upload| upload.stalled());
+        for upload in stalled {
+            net.tx
+                .try_send(UICommand::TransferDone(
+                    upload.file_id,
+                    upload.to,
+                    upload.buffer,
+                    format!("gave up sending {}", upload.name),
+                ))
+                .unwrap();
+        }
+        let stalled: Vec<Id> = self
+            .downloads
+            .iter()
+            .filter(|(_, download)| download.stalled())
+            .map(|(file_id, _)| *file_id)
+            .collect();
+        for download in stalled
+            .iter()
+            .filter_map(|file_id| self.downloads.remove(file_id))
+        {
+            net.tx
+                .try_send(UICommand::TransferDone(
+                    download.file_id,
+                    download.from,
+                    download.buffer,
+                    format!(
+                        "gave up on {}, it'll pick up where it left off if it's offered again",
+                        download.name
+                    ),
+                ))
+                .unwrap();
+        }
+        self.offers.retain(|_, offer| !offer.expired());
+        self.incoming.retain(|_, offer| !offer.expired());
+        self.requested
+            .retain(|_, (_, _, since)| since.elapsed() < GET_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::{unbounded, Receiver};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arpchat-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn transfers(dir: Option<PathBuf>) -> (Transfers, Receiver<NetCommand>) {
+        let (net_tx, net_rx) = unbounded();
+        (Transfers::new(dir, net_tx), net_rx)
+    }
+
+    fn notices(rx: &Receiver<UICommand>) -> Vec<String> {
+        rx.try_iter()
+            .filter_map(|cmd| match cmd {
+                UICommand::Notice(notice) | UICommand::TransferDone(_, _, _, notice) => {
+                    Some(notice)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accepting_something_gone_says_so() {
+        let (tx, rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let mut net = Net::new([1; 8], &tx, &macs, &online);
+        let (mut transfers, _) = transfers(None);
+
+        transfers.accept(&mut net, [9; 8]);
+        assert!(net.sent().is_empty());
+        assert_eq!(notices(&rx), ["that file isn't on offer anymore"]);
+    }
+
+    #[test]
+    fn offered_files_go_out_in_chunks() {
+        let dir = temp_dir("upload");
+        let path = dir.join("a.txt");
+        fs::write(&path, "hello").unwrap();
+        let file = File::open(&path).unwrap();
+
+        let (tx, _rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let (local_id, peer) = ([1; 8], [2; 8]);
+        let mut net = Net::new(local_id, &tx, &macs, &online);
+        let (mut transfers, _) = transfers(None);
+        let offer = Offer {
+            file,
+            name: "a.txt".to_string(),
+            size: 5,
+            to: Some(peer),
+            buffer: String::new(),
+            since: Instant::now(),
+            served: false,
+        };
+        transfers.offer(&mut net, offer, [0; 32]);
+        let Some((Packet::FileOffer(_, Some(to), file_id, _, _, 5, _), _)) = net.sent().first()
+        else {
+            panic!("nothing was offered");
+        };
+        assert_eq!(*to, peer);
+        let file_id = *file_id;
+
+        // Nobody else gets it.
+        transfers.accepted(&mut net, MacAddr::broadcast(), [3; 8], file_id, 0);
+        transfers.pump(&mut net);
+        assert_eq!(net.sent().len(), 1);
+
+        transfers.accepted(&mut net, MacAddr::broadcast(), peer, file_id, 0);
+        transfers.pump(&mut net);
+        assert!(matches!(
+            net.sent().last(),
+            Some((Packet::FileChunk(_, to, id, 0, chunk), _))
+                if *to == peer && *id == file_id && chunk == b"hello"
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn served_offers_dont_clobber_others() {
+        let dir = temp_dir("clobber");
+        let path = dir.join("a.txt");
+        fs::write(&path, "hello").unwrap();
+
+        let (tx, _rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let mut net = Net::new([1; 8], &tx, &macs, &online);
+        let (mut transfers, _) = transfers(None);
+        let offer = |served| Offer {
+            file: File::open(&path).unwrap(),
+            name: "a.txt".to_string(),
+            size: 5,
+            to: Some([2; 8]),
+            buffer: String::new(),
+            since: Instant::now(),
+            served,
+        };
+        transfers.offer(&mut net, offer(false), [0; 32]);
+        let Some((Packet::FileOffer(_, _, file_id, _, _, _, _), _)) = net.sent().first() else {
+            panic!("nothing was offered");
+        };
+        let file_id = *file_id;
+
+        transfers.hashed(
+            &mut net,
+            file_id,
+            "a.txt".to_string(),
+            offer(true),
+            Ok([1; 32]),
+        );
+        assert_eq!(net.sent().len(), 1);
+        assert!(!transfers.offers[&file_id].served);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_are_checked_and_saved() {
+        let dir = temp_dir("download");
+        let (tx, rx) = unbounded();
+        let (macs, online) = (HashMap::new(), HashMap::new());
+        let (local_id, peer, file_id) = ([1; 8], [2; 8], [3; 8]);
+        let mut net = Net::new(local_id, &tx, &macs, &online);
+        let (mut transfers, _) = transfers(Some(dir.clone()));
+
+        let offer = |hash| Incoming {
+            from: peer,
+            name: "a.txt".to_string(),
+            size: 5,
+            hash,
+            buffer: String::new(),
+            since: Instant::now(),
+        };
+        let hash: FileHash = Blake2s256::digest(b"hello").into();
+        transfers.offered(&net, file_id, offer(hash));
+        transfers.accept(&mut net, file_id);
+        assert!(matches!(
+            net.sent(),
+            [(Packet::FileAccept(_, to, id, 0), _)] if *to == peer && *id == file_id
+        ));
+
+        // Only from whoever offered it.
+        transfers.chunk(&mut net, MacAddr::broadcast(), [4; 8], file_id, 0, b"hello");
+        assert!(!dir.join("a.txt").exists());
+        transfers.chunk(&mut net, MacAddr::broadcast(), peer, file_id, 0, b"hello");
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+        assert!(notices(&rx)
+            .iter()
+            .any(|notice| notice.starts_with("saved to")));
+
+        // Something else under the same hash doesn't get saved.
+        let other = [5; 8];
+        transfers.offered(&net, other, offer([0; 32]));
+        transfers.accept(&mut net, other);
+        transfers.chunk(&mut net, MacAddr::broadcast(), peer, other, 0, b"hello");
+        assert!(!dir.join("a (1).txt").exists());
+        assert!(notices(&rx)
+            .iter()
+            .any(|notice| notice.contains("got corrupted")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+use cursive::backends::crossterm::crossterm::style::Stylize;
+use cursive::views::TextView;
+use cursive::Cursive;
+
+use crate::net::Id;
+
+/// How long someone shows as typing after we last heard they were. Has to be
+/// comfortably longer than the interval typing packets are sent at.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+struct Typist {
+    id: Id,
+    name: String,
+    buffer: String,
+    since: Instant,
+}
+
+/// Who's typing where, for the line above the input box.
+pub struct Typing {
+    typists: Vec<Typist>,
+}
+
+impl Typing {
+    pub fn new() -> Self {
+        Self { typists: vec![] }
+    }
+
+    pub fn start(&mut self, id: Id, name: String, buffer: String) {
+        self.typists.retain(|typist| typist.id != id);
+        self.typists.push(Typist {
+            id,
+            name,
+            buffer,
+            since: Instant::now(),
+        });
+    }
+
+    /// They sent something, so they're done typing it.
+    pub fn stop(&mut self, id: &Id) -> bool {
+        let before = self.typists.len();
+        self.typists.retain(|typist| typist.id != *id);
+        self.typists.len() != before
+    }
+
+    /// Forget anyone we haven't heard from in a while. Returns whether
+    /// anyone was forgotten.
+    pub fn expire(&mut self) -> bool {
+        let before = self.typists.len();
+        self.typists
+            .retain(|typist| typist.since.elapsed() < TYPING_TIMEOUT);
+        self.typists.len() != before
+    }
+
+    pub fn show(&self, siv: &mut Cursive, buffer: &str) {
+        let names: Vec<&str> = self
+            .typists
+            .iter()
+            .filter(|typist| typist.buffer == buffer)
+            .map(|typist| typist.name.as_str())
+            .collect();
+        let line = match names.as_slice() {
+            [] => String::new(),
+            [name] => format!("{name} is typing…"),
+            [first, second] => format!("{first} and {second} are typing…"),
+            _ => "several people are typing…".to_string(),
+        };
+        siv.call_on_name("typing", |view: &mut TextView| {
+            view.set_content(line.dark_grey().to_string());
+        });
+    }
+}
@@ -1,11 +1,27 @@
-use cursive::backends::crossterm::crossterm::style::Color;
-use cursive::traits::Nameable;
+use chrono::{DateTime, Local, Timelike};
+use cursive::backends::crossterm::crossterm::style::{Color, Stylize};
 use cursive::utils::markup::StyledString;
-use cursive::views::{LinearLayout, NamedView, Panel, ResizedView, ScrollView, TextView};
+use cursive::views::{LinearLayout, NamedView, Panel, SelectView};
 use cursive::Cursive;
 
 use crate::error::ArpchatError;
-use crate::net::{EtherType, Id};
+use std::time::Duration;
+
+use std::fs::File;
+
+use crate::net::{Availability, BacklogEntry, EtherType, FileHash, Id, ShareEntry, Topic, ID_SIZE};
+
+use super::chat_log::ChatLog;
+use super::commands::Command;
+use super::transfer::Offer;
+
+/// What `/me` messages start with when they're sent.
+pub const ACTION_PREFIX: &str = "/me ";
+
+/// What an image says in the chat, above the image itself.
+pub fn image_caption(name: &str) -> String {
+    format!("[image: {name}]")
+}
 
 pub enum UpdatePresenceKind {
     Boring,
@@ -13,71 +29,355 @@ pub enum UpdatePresenceKind {
     UsernameChange(String),
 }
 
+/// How far one of our own messages has gotten.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Sending,
+    /// We never heard it come back, so it probably didn't go out.
+    NotEchoed,
+    /// Someone else's message, or one of ours that came back.
+    Sent,
+}
+
 pub enum UICommand {
     AlertUser,
+    UnlockHistory(String),
+    /// The chat's been set up, so whatever was loaded from history can go
+    /// in it.
+    ShowHistory,
     UpdateUsername(String),
+    /// Whatever was typed into the input box.
     SendMessage(String),
+    /// Send a message to the active buffer.
+    Say(String),
+    Run(Command),
     SetInterface(String),
     SetEtherType(EtherType),
-    NewMessage(Id, String, String, bool),
-    PresenceUpdate(Id, String, bool, UpdatePresenceKind),
+    /// Sender, message id, the message it replies to, their name, room, and
+    /// message.
+    NewMessage(Id, Id, Option<Id>, String, String, String, Delivery),
+    /// The other person in the conversation, their name, who sent the
+    /// message, the message id, the message it replies to, and the message.
+    NewDirectMessage(Id, String, Id, Id, Option<Id>, String, Delivery),
+    /// One of our messages and who it's meant for.
+    ExpectReceipts(Id, Vec<(Id, String)>),
+    /// Author, message id, and the new message.
+    MessageEdited(Id, Id, String),
+    /// Author and message id.
+    MessageDeleted(Id, Id),
+    /// Who reacted, their name, message id, the reaction, and whether it
+    /// was added or taken back.
+    Reacted(Id, String, Id, String, bool),
+    /// Who sent them, their name, and the messages they got and read.
+    Receipts(Id, String, Vec<Id>, Vec<Id>),
+    SwitchBuffer(String),
+    /// Reply to a message with whatever gets sent next.
+    ReplyTo(Id),
+    /// Put one of our messages in the input box to be edited.
+    StartEdit(Id),
+    /// Start editing the last thing we said in the active buffer.
+    EditLast,
+    Delete(Id),
+    /// Add a reaction to a message, or take it back if we already did.
+    React(Id, String),
+    ShowReactions(Id),
+    /// Something's been typed in the input box.
+    Typed,
+    /// Any key or click at all, so we know nobody's away.
+    Active,
+    /// Who, their name, and the buffer they're typing in.
+    Typing(Id, String, String),
+    ShowThread(Id),
+    /// Who, their name, whether they're inactive, what changed, the rooms
+    /// they're in, and their availability and status line.
+    PresenceUpdate(
+        Id,
+        String,
+        bool,
+        UpdatePresenceKind,
+        Vec<String>,
+        Availability,
+        String,
+    ),
     RemovePresence(Id, String),
+    HidePresence(Id),
+    InterfaceChanged(String),
+    PrivilegesDropped,
+    Ignore(String),
+    /// Look someone up by name or hex id.
+    Whois(String),
+    /// Who, their name, availability, status line, rooms, what they're
+    /// sharing, MAC address, and how long it's been since we heard from them.
+    ShowUser(
+        Id,
+        String,
+        Availability,
+        String,
+        Vec<String>,
+        Vec<String>,
+        String,
+        Duration,
+    ),
+    /// Someone new wants to catch up on these rooms, and it's on us.
+    CollectBacklog(Id, Vec<String>),
+    /// Who sent it, and messages from before we got here.
+    Backfill(String, Vec<BacklogEntry>),
+    /// Who held them, and messages that came in while we were offline.
+    Mail(String, Vec<BacklogEntry>),
+    /// Who's offering it, file id, the buffer it's offered in, the file's
+    /// name, and its size.
+    FileOffered(String, Id, String, String, u64),
+    AcceptFile(Id),
+    DeclineFile(Id),
+    /// File id, who it's going to or coming from, their name, buffer, the
+    /// file's name, whether we're sending it, how much has made it, and its
+    /// size.
+    TransferProgress(Id, Id, String, String, String, bool, u64, u64),
+    /// File id, who it was going to or coming from, buffer, and how it
+    /// ended.
+    TransferDone(Id, Id, String, String),
+    /// Whose shares, the path that was listed, whether some of it was left
+    /// out, and what's there.
+    Listing(String, String, bool, Vec<ShareEntry>),
+    /// Message id, file name, and an image, which comes just before the
+    /// message it goes with.
+    Image(Id, String, Vec<u8>),
+    ViewImage(Id),
+    ShowFind,
+    /// Search the active buffer's scrollback, or stop searching if it's
+    /// empty.
+    Find(String),
+    /// Go to a newer match, or an older one.
+    FindStep(bool),
+    ListMatches,
+    JumpTo(Id),
+    /// Room, its topic if it's ever had one, and its pinned messages.
+    RoomInfo(String, Option<Topic>, Vec<BacklogEntry>),
+    /// Pin a message, or take it down.
+    Pin(Id),
+    Unpin(Id),
+    Notice(String),
     Error(ArpchatError),
 }
 
 pub enum NetCommand {
     UpdateUsername(String),
-    SendMessage(String),
+    /// Room, message, and the message it replies to.
+    SendMessage(String, String, Option<Id>),
+    /// Recipient, by name or hex id, message, and the message it replies to.
+    SendDirect(String, String, Option<Id>),
     SetInterface(String),
     SetEtherType(EtherType),
+    /// Resend everything that wasn't echoed.
+    Retry,
+    /// Authors and ids of messages that have been looked at.
+    MarkRead(Vec<(Id, Id)>),
+    /// Message id, the new message, and who it was sent to if it was direct.
+    EditMessage(Id, String, Option<Id>),
+    /// Message id, and who it was sent to if it was direct.
+    DeleteMessage(Id, Option<Id>),
+    /// Message id, the reaction, whether it's being added, and who it was
+    /// sent to if it was direct.
+    React(Id, String, bool, Option<Id>),
+    /// Buffer, file name, and an image ready to go.
+    SendImage(String, String, Vec<u8>),
+    /// Tell whoever's in a buffer that we're typing in it.
+    Typing(String),
+    /// Our availability and status line.
+    SetStatus(Availability, String),
+    Whois(String),
+    /// List who's in a buffer.
+    Who(String),
+    /// Who asked for a backlog, and what to send them.
+    SendBacklog(Id, Vec<BacklogEntry>),
+    /// Path, name, size, and hash of a file, who to offer it to if they
+    /// were named, and the buffer it was offered from.
+    OfferFile(File, String, u64, FileHash, Option<String>, String),
+    AcceptFile(Id),
+    DeclineFile(Id),
+    /// Share a folder, or list what's shared if it's empty.
+    Share(String),
+    Unshare(String),
+    /// Whose shares, by name or hex id, and the path to list.
+    List(String, String),
+    /// Whose shares, by name or hex id, and the path of the file to fetch.
+    Get(String, String),
+    /// The file id to offer a file from our shares with, the path it was
+    /// asked for by, the offer for whoever asked, and its hash if it could
+    /// be read.
+    ServeFile(Id, String, Offer, Result<FileHash, String>),
+    JoinRoom(String),
+    PartRoom(String),
+    /// Room, and its new topic, which is empty to clear it.
+    SetTopic(String, String),
+    /// Room, and the message to pin there.
+    Pin(String, BacklogEntry),
+    /// Room, and the message to take down.
+    Unpin(String, Id),
     PauseHeartbeat(bool),
+    Ignore(String),
+    Unignore(String),
     Terminate,
 }
 
-// AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
-type ChatPanel = Panel<ScrollView<ResizedView<ResizedView<NamedView<LinearLayout>>>>>;
+type ChatPanel = Panel<NamedView<LinearLayout>>;
 
-pub fn update_title(siv: &mut Cursive, username: &str, interface: &str) {
-    let title = if interface.len() <= 8 {
+/// Put who we are in the window's title, and the active room's topic too in
+/// the chat panel's.
+pub fn update_title(
+    siv: &mut Cursive,
+    username: &str,
+    interface: &str,
+    unprivileged: bool,
+    topic: Option<&str>,
+) {
+    let mut title = if interface.len() <= 8 {
         format!("arpchat: {username} ({interface})")
     } else {
         format!("arpchat: {username}")
     };
+    if unprivileged {
+        title += " [unprivileged]";
+    }
     siv.set_window_title(&title);
+    if let Some(topic) = topic {
+        title = format!("{title} — {topic}");
+    }
     siv.call_on_name("chat_panel", |chat_panel: &mut ChatPanel| {
         chat_panel.set_title(title);
     });
 }
 
-/// If a `TextView` with the provided name exists, set its content. Otherwise,
-/// append a new `TextView` to the `LinearLayout` with the provided parent name.
+/// If a line with the provided name exists in the `ChatLog` with the provided
+/// parent name, set its content. Otherwise, append it as a new line.
 pub fn update_or_append_txt<S>(siv: &mut Cursive, parent_id: &str, id: &str, content: S)
 where
-    S: Into<StyledString> + Clone,
+    S: Into<StyledString>,
 {
-    let mut updated = false;
-    siv.call_on_name(id, |child: &mut TextView| {
-        child.set_content(content.clone());
-        updated = true;
+    siv.call_on_name(parent_id, |parent: &mut ChatLog| {
+        parent.set(id, content);
     });
+}
 
-    if !updated {
-        siv.call_on_name(parent_id, |parent: &mut LinearLayout| {
-            parent.add_child(TextView::new(content).with_name(id));
-        });
-    }
+/// Set the content of a line in the `ChatLog` with the provided parent name,
+/// if it's still there.
+pub fn update_txt<S>(siv: &mut Cursive, parent_id: &str, id: &str, content: S)
+where
+    S: Into<StyledString>,
+{
+    siv.call_on_name(parent_id, |parent: &mut ChatLog| {
+        parent.update(id, content);
+    });
 }
 
-/// Append a new `TextView` to the `LinearLayout` with the provided parent name.
+/// Append a new line to the `ChatLog` with the provided parent name.
 pub fn append_txt<S>(siv: &mut Cursive, parent_id: &str, content: S)
 where
     S: Into<StyledString>,
 {
-    siv.call_on_name(parent_id, |parent: &mut LinearLayout| {
-        parent.add_child(TextView::new(content));
+    siv.call_on_name(parent_id, |parent: &mut ChatLog| {
+        parent.append(content);
     });
 }
 
+/// Set the label of someone's entry in the presence list, adding it if
+/// they're not there yet.
+pub fn update_presence(siv: &mut Cursive, id: &Id, label: String) {
+    siv.call_on_name("presences", |presences: &mut SelectView<Id>| {
+        let index = presences.iter().position(|(_, other)| other == id);
+        match index {
+            Some(index) => {
+                if let Some((old_label, _)) = presences.get_item_mut(index) {
+                    *old_label = label.into();
+                }
+            }
+            None => presences.add_item(label, *id),
+        }
+    });
+}
+
+pub fn remove_presence(siv: &mut Cursive, id: &Id) {
+    siv.call_on_name("presences", |presences: &mut SelectView<Id>| {
+        let index = presences.iter().position(|(_, other)| other == id);
+        if let Some(index) = index {
+            presences.remove_item(index);
+        }
+    });
+}
+
+/// Name of the line a message is shown in, so it can be found again.
+pub fn message_name(msg_id: &Id) -> String {
+    format!("msg_{}", hex_id(msg_id))
+}
+
+/// Name of the line an image's thumbnail is shown in.
+pub fn image_name(msg_id: &Id) -> String {
+    format!("img_{}", hex_id(msg_id))
+}
+
+/// The name of a transfer's progress bar in the chat.
+pub fn transfer_name(file_id: &Id, peer: &Id) -> String {
+    format!("transfer_{}_{}", hex_id(file_id), hex_id(peer))
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Something like `[#####-----] 50%`.
+pub fn progress_bar(done: u64, total: u64) -> String {
+    const WIDTH: usize = 20;
+    let fraction = match total {
+        0 => 1.0,
+        total => done.min(total) as f64 / total as f64,
+    };
+    let filled = (fraction * WIDTH as f64) as usize;
+    format!(
+        "[{}{}] {}%",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        (fraction * 100.0) as u8
+    )
+}
+
+/// The line shown in the chat for a message.
+pub fn format_message(
+    time: &DateTime<Local>,
+    id: &Id,
+    username: &str,
+    msg: &str,
+    delivery: Delivery,
+) -> String {
+    let time = format!(
+        "{hours:02}:{mins:02}:{secs:02}",
+        hours = time.hour(),
+        mins = time.minute(),
+        secs = time.second()
+    )
+    .dark_grey();
+    let username = username.with(color_from_id(id));
+    let mut print = match msg.strip_prefix(ACTION_PREFIX) {
+        Some(action) => format!("{time} * {username} {}", action.italic()),
+        None => format!("{time} [{username}] {msg}"),
+    };
+    match delivery {
+        Delivery::Sending => print += &" sending...".dark_grey().to_string(),
+        Delivery::NotEchoed => print += &" not echoed — retry? (/retry)".red().to_string(),
+        Delivery::Sent => {}
+    }
+    print
+}
+
 pub fn color_from_id(id: &Id) -> Color {
     const COLOR_COUNT: usize = 8;
     const COLORS: [Color; COLOR_COUNT] = [
@@ -100,6 +400,60 @@ pub fn color_from_id(id: &Id) -> Color {
     COLORS[index]
 }
 
+/// A few hex digits of an id, enough to tell people with the same name apart.
+pub fn short_id(id: &Id) -> String {
+    hex_id(id)[..4].to_string()
+}
+
+pub fn hex_id(id: &Id) -> String {
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn parse_hex_id(hex: &str) -> Option<Id> {
+    if hex.len() != ID_SIZE * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut id: Id = [0; ID_SIZE];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(id)
+}
+
+/// Text without the escape codes `Stylize` adds, or anything else a
+/// terminal would take as one, for writing somewhere that isn't a terminal.
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // Colours and the like, up to a letter.
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // Titles and links, up to a bell or `ESC \`.
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\n' | '\t' => stripped.push(c),
+            c if c.is_control() => {}
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
 pub fn ring_bell() {
     use std::io::{stdout, Write};
     print!("\x07");